@@ -0,0 +1,360 @@
+// HTTP API.
+// Every endpoint turns into a ReplCommand for the daemon, just like the
+// REPL commands do. The daemon answers with a JSON body, or an ApiError,
+// which goes back to the client with its status code and a body like
+// {"error": "..."}.
+//
+// Anyone may read from a node, and other nodes announce and shake hands
+// with it freely, but endpoints changing its state on their own (posting
+// blocks and transactions, mining, registering nodes, resolving) may
+// require a bearer token or basic auth, see Auth.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+
+use serde_json;
+use tiny_http::Method;
+use url::{form_urlencoded, Url};
+
+use config::NodeConfig;
+use gossip::Inventory;
+use handshake::Handshake;
+use super::{Block, ReplCommand};
+
+// What went wrong with an HTTP command.
+#[derive(Debug, PartialEq)]
+pub struct ApiError {
+    pub status: u16,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'a str,
+}
+
+impl ApiError {
+    pub fn new<S: Into<String>>(status: u16, message: S) -> ApiError {
+        ApiError { status: status, message: message.into() }
+    }
+
+    pub fn bad_request<S: Into<String>>(message: S) -> ApiError {
+        ApiError::new(400, message)
+    }
+
+    pub fn not_found<S: Into<String>>(message: S) -> ApiError {
+        ApiError::new(404, message)
+    }
+
+    pub fn body(&self) -> String {
+        serde_json::to_string(&ErrorBody { error: &self.message }).unwrap()
+    }
+}
+
+// ----
+
+// Request bodies
+
+#[derive(Deserialize)]
+struct NewTransaction {
+    sender: String,
+    recipient: String,
+    amount: i64,
+}
+
+#[derive(Deserialize)]
+struct MineRequest {
+    miner: String,
+}
+
+#[derive(Deserialize)]
+struct RegisterNodes {
+    nodes: Vec<String>,
+}
+
+// Response bodies, built by the daemon
+
+#[derive(Serialize)]
+pub struct TransactionCreated {
+    pub message: String,
+    // Index of the block which will hold the transaction
+    pub index: u64,
+}
+
+#[derive(Serialize)]
+pub struct BlockAccepted {
+    // What became of the block: Connected, SideChain, Orphan or Duplicate
+    pub status: String,
+}
+
+#[derive(Serialize)]
+pub struct Fetching {
+    // How many of the announced blocks and transactions are new to us
+    pub blocks: usize,
+    pub transactions: usize,
+}
+
+#[derive(Serialize)]
+pub struct Nodes {
+    pub nodes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct Resolved {
+    pub replaced: bool,
+    // How many of our blocks were disconnected, if replaced
+    pub reorg_depth: usize,
+    pub length: usize,
+    // Nodes which failed us, and how
+    pub failures: BTreeMap<String, String>,
+}
+
+#[derive(Serialize)]
+pub struct Status {
+    pub network: String,
+    pub engine: String,
+    pub length: usize,
+    // Hash of our last block, and cumulative work up to it
+    pub tip: String,
+    pub work: u64,
+    pub mempool: usize,
+    pub nodes: usize,
+    // Addresses on our address book
+    pub known_peers: usize,
+    pub orphans: usize,
+}
+
+// ----
+
+// Parses the JSON body of a request.
+fn parse<'a, T: ::serde::Deserialize<'a>>(body: &'a str) -> Result<T, ApiError> {
+    serde_json::from_str(body)
+        .map_err(|err| ApiError::bad_request(format!("Invalid request body: {}", err)))
+}
+
+// Who may use the endpoints which change our state.
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    pub token: Option<String>,
+    // User and password, for basic auth
+    pub basic: Option<(String, String)>,
+}
+
+impl Auth {
+    pub fn from_config(config: &NodeConfig) -> Result<Auth, String> {
+        let basic = match (config.api_user.clone(), config.api_password.clone()) {
+            (Some(user), Some(password)) => Some((user, password)),
+            (None, None) => None,
+            _ => return Err("Please configure both api_user and api_password, or neither.".to_owned()),
+        };
+        if config.api_token.as_ref().map_or(false, |token| token.is_empty()) {
+            return Err("The API token may not be empty.".to_owned());
+        }
+        Ok(Auth { token: config.api_token.clone(), basic: basic })
+    }
+
+    // Whether every endpoint is open.
+    pub fn is_open(&self) -> bool {
+        self.token.is_none() && self.basic.is_none()
+    }
+
+    // What to answer a request which must authenticate with, on the
+    // WWW-Authenticate header.
+    pub fn challenge(&self) -> &'static str {
+        match self.basic {
+            Some(_) => "Basic realm=\"blockchain-rs\"",
+            None => "Bearer",
+        }
+    }
+
+    // Checks that a request may go through.
+    // authorization: Its Authorization header, if any
+    pub fn check(&self, method: &Method, url: &str, authorization: Option<&str>) -> Result<(), ApiError> {
+        if self.is_open() || !changes_state(method.as_str(), &path_segments(url)) {
+            return Ok(());
+        }
+        let authorization = authorization.unwrap_or("").trim();
+        let mut parts = authorization.splitn(2, ' ');
+        let scheme = parts.next().unwrap_or("").to_lowercase();
+        let credentials = parts.next().unwrap_or("").trim();
+        let granted = match (scheme.as_ref(), &self.token, &self.basic) {
+            ("bearer", &Some(ref token), _) => same(credentials, token),
+            ("basic", _, &Some((ref user, ref password))) =>
+                same(credentials, &base64(format!("{}:{}", user, password).as_bytes())),
+            _ => false,
+        };
+        match granted {
+            true => Ok(()),
+            false => Err(ApiError::new(401, "This endpoint requires authentication.")),
+        }
+    }
+}
+
+// Whether an endpoint changes our state on its own, rather than reading
+// from it, or being part of how nodes talk to each other.
+fn changes_state(method: &str, segments: &[&str]) -> bool {
+    match (method, segments) {
+        ("POST", &["block"]) | ("POST", &["transactions", "new"]) | ("POST", &["mine"])
+            | ("POST", &["nodes", "register"]) | ("POST", &["nodes", "resolve"]) => true,
+        _ => false,
+    }
+}
+
+// Compares credentials, taking the same time wherever they differ.
+fn same(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// Encodes bytes in standard base64, with padding, as basic auth does.
+pub fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | (byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(group >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+// Whether a request comes with a body we need to read: only POST
+// endpoints take one.
+pub fn takes_body(method: &Method, url: &str) -> bool {
+    method.as_str() == "POST" && takes_method(&path_segments(url)) == Some("POST")
+}
+
+// Reads the body of a request, refusing with 413 anything over limit
+// bytes.
+pub fn read_body<R: Read>(reader: R, limit: u64) -> Result<String, ApiError> {
+    let mut bytes = vec![];
+    reader.take(limit + 1).read_to_end(&mut bytes)
+        .map_err(|e| ApiError::bad_request(format!("Cannot read request body: {}", e)))?;
+    if bytes.len() as u64 > limit {
+        return Err(ApiError::new(413, format!("Request body is over the limit of {} bytes.", limit)));
+    }
+    String::from_utf8(bytes).map_err(|_| ApiError::bad_request("Request body is not valid UTF-8."))
+}
+
+// Splits the path of a URL into its segments, leaving out the query.
+fn path_segments(url: &str) -> Vec<&str> {
+    let path = url.splitn(2, '?').next().unwrap_or("");
+    path.trim_matches('/').split('/').collect()
+}
+
+// Gets the method an endpoint takes, if it exists.
+fn takes_method(segments: &[&str]) -> Option<&'static str> {
+    match segments {
+        &["chain"] | &["headers"] | &["blocks"] | &["proof", _] | &["block", _] | &["transaction", _]
+            | &["nodes"] | &["peers"] | &["status"] => Some("GET"),
+        &["block"] | &["transactions", "new"] | &["mine"] | &["nodes", "register"]
+            | &["nodes", "resolve"] | &["inv"] | &["handshake"] => Some("POST"),
+        _ => None,
+    }
+}
+
+// Turns a request into a command for the daemon.
+// Return: The command, along with the status code to answer with if it
+// succeeds, or Err if the request makes no sense.
+pub fn route(method: &Method, url: &str, body: &str) -> Result<(ReplCommand, u16), ApiError> {
+    let query: HashMap<String, String> = form_urlencoded::parse(url.splitn(2, '?').nth(1).unwrap_or("").as_bytes())
+        .into_owned()
+        .collect();
+    let segments = path_segments(url);
+
+    let command = match (method.as_str(), &segments[..]) {
+        // curl -X GET "http://127.0.0.1:3000/chain"
+        ("GET", &["chain"]) => ReplCommand::HttpGetChain,
+        // curl -X GET "http://127.0.0.1:3000/headers"
+        // curl -X GET "http://127.0.0.1:3000/headers?locator=HASH,HASH"
+        ("GET", &["headers"]) => ReplCommand::HttpGetHeaders {
+            locator: query.get("locator").map(|locator| locator.split(',').map(str::to_owned).collect()),
+        },
+        // curl -X GET "http://127.0.0.1:3000/blocks?from=HASH&to=HASH"
+        ("GET", &["blocks"]) => match (query.get("from"), query.get("to")) {
+            (Some(from), Some(to)) => ReplCommand::HttpGetBlocks { from: from.clone(), to: to.clone() },
+            _ => return Err(ApiError::bad_request("Please specify the hashes to get blocks from and to.")),
+        },
+        // curl -X GET "http://127.0.0.1:3000/proof/TXID"
+        ("GET", &["proof", txid]) => ReplCommand::HttpGetProof { txid: txid.to_owned() },
+        // curl -X GET "http://127.0.0.1:3000/block/INDEX_OR_HASH"
+        ("GET", &["block", id]) => ReplCommand::HttpGetBlock { id: id.to_owned() },
+        // curl -X GET "http://127.0.0.1:3000/transaction/TXID"
+        ("GET", &["transaction", txid]) => ReplCommand::HttpGetTransaction { txid: txid.to_owned() },
+        // curl -X GET "http://127.0.0.1:3000/nodes"
+        ("GET", &["nodes"]) => ReplCommand::HttpGetNodes,
+        // curl -X GET "http://127.0.0.1:3000/peers"
+        ("GET", &["peers"]) => ReplCommand::HttpGetPeers,
+        // curl -X GET "http://127.0.0.1:3000/status"
+        ("GET", &["status"]) => ReplCommand::HttpGetStatus,
+        // curl -X POST -d @block.json "http://127.0.0.1:3000/block"
+        ("POST", &["block"]) => {
+            let block: Block = parse(body)?;
+            ReplCommand::HttpPostBlock { block: block }
+        },
+        // curl -X POST -d '{"sender": "A", "recipient": "B", "amount": 5}' \
+        //      "http://127.0.0.1:3000/transactions/new"
+        ("POST", &["transactions", "new"]) => {
+            let transaction: NewTransaction = parse(body)?;
+            if transaction.amount <= 0 {
+                return Err(ApiError::bad_request("Amount must be positive."));
+            }
+            return Ok((ReplCommand::HttpNewTransaction {
+                from: transaction.sender,
+                to: transaction.recipient,
+                amount: transaction.amount,
+            }, 201));
+        },
+        // curl -X POST -d '{"miner": "ADDRESS"}' "http://127.0.0.1:3000/mine"
+        ("POST", &["mine"]) => {
+            let request: MineRequest = parse(body)?;
+            if request.miner.is_empty() {
+                return Err(ApiError::bad_request("Please specify who to reward."));
+            }
+            ReplCommand::HttpMine { miner: request.miner }
+        },
+        // curl -X POST -d '{"nodes": ["http://127.0.0.1:3001"]}' \
+        //      "http://127.0.0.1:3000/nodes/register"
+        ("POST", &["nodes", "register"]) => {
+            let request: RegisterNodes = parse(body)?;
+            if request.nodes.is_empty() {
+                return Err(ApiError::bad_request("Please specify at least one node."));
+            }
+            for url in &request.nodes {
+                if Url::parse(url).is_err() {
+                    return Err(ApiError::bad_request(format!("Invalid node address: {}", url)));
+                }
+            }
+            return Ok((ReplCommand::HttpRegNodes { urls: request.nodes }, 201));
+        },
+        // curl -X POST "http://127.0.0.1:3000/nodes/resolve"
+        ("POST", &["nodes", "resolve"]) => ReplCommand::HttpResolve,
+        // curl -X POST -d '{"from": "http://127.0.0.1:3001", "blocks": ["HASH"]}' \
+        //      "http://127.0.0.1:3000/inv"
+        ("POST", &["inv"]) => {
+            let inventory: Inventory = parse(body)?;
+            if Url::parse(&inventory.from).is_err() {
+                return Err(ApiError::bad_request(format!("Invalid node address: {}", inventory.from)));
+            }
+            ReplCommand::HttpPostInv { inventory: inventory }
+        },
+        // curl -X POST -d @handshake.json "http://127.0.0.1:3000/handshake"
+        ("POST", &["handshake"]) => {
+            let handshake: Handshake = parse(body)?;
+            ReplCommand::HttpHandshake { handshake: handshake }
+        },
+        (_, segments) => {
+            let endpoint = format!("/{}", segments.join("/"));
+            return Err(match takes_method(segments) {
+                Some(allowed) => ApiError::new(405, format!("{} takes {}, not {}",
+                                                            endpoint, allowed, method)),
+                None => ApiError::not_found(format!("Unknown endpoint {}", endpoint)),
+            });
+        },
+    };
+    Ok((command, 200))
+}
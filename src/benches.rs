@@ -0,0 +1,117 @@
+// Benchmarks.
+// These need a nightly compiler:
+//     cargo +nightly bench --features bench
+
+use std::sync::Arc;
+
+use test::Bencher;
+
+use super::{Block, Blockchain, Transaction};
+use consensus::{pow, ProofOfWork, PowHash};
+use parallel;
+
+// A single proof hash, which is what validating a block costs.
+#[bench]
+fn pow_sha256_valid_proof(b: &mut Bencher) {
+    let engine = ProofOfWork::new(PowHash::Sha256, 4);
+    let work_hash = pow::work_hash(&Blockchain::new().chain[0].header());
+    let mut proof = 0;
+    b.iter(|| {
+        proof += 1;
+        engine.valid_proof(&work_hash, proof)
+    });
+}
+
+#[bench]
+fn pow_scrypt_valid_proof(b: &mut Bencher) {
+    let engine = ProofOfWork::new(PowHash::Scrypt, 4);
+    let work_hash = pow::work_hash(&Blockchain::new().chain[0].header());
+    let mut proof = 0;
+    b.iter(|| {
+        proof += 1;
+        engine.valid_proof(&work_hash, proof)
+    });
+}
+
+// Mining a block with two leading zeroes, which is what producing a
+// block costs on average at that difficulty.
+#[bench]
+fn pow_sha256_mine(b: &mut Bencher) {
+    let engine = ProofOfWork::new(PowHash::Sha256, 2);
+    let mut header = Blockchain::new().chain[0].header();
+    b.iter(|| {
+        header.index += 1;
+        engine.proof_of_work(&pow::work_hash(&header))
+    });
+}
+
+#[bench]
+fn pow_scrypt_mine(b: &mut Bencher) {
+    let engine = ProofOfWork::new(PowHash::Scrypt, 2);
+    let mut header = Blockchain::new().chain[0].header();
+    b.iter(|| {
+        header.index += 1;
+        engine.proof_of_work(&pow::work_hash(&header))
+    });
+}
+
+// A chain of blocks valid for a proof of work of difficulty 0, so it
+// can be built quickly. Each block rewards a miner, who pays the coin to
+// someone else on the next block.
+fn synthetic_chain(blocks: u64) -> Blockchain {
+    let mut blockchain = Blockchain::new();
+    blockchain.engine = Arc::new(ProofOfWork::new(PowHash::Sha256, 0));
+    for index in 2..blocks + 2 {
+        let mut transactions = vec![Transaction {
+            sender: "0".to_owned(),
+            recipient: format!("miner{}", index),
+            amount: 1,
+            nonce: index,
+        }];
+        if index > 2 {
+            transactions.push(Transaction {
+                sender: format!("miner{}", index - 1),
+                recipient: "merchant".to_owned(),
+                amount: 1,
+                nonce: index,
+            });
+        }
+        let block = {
+            let parent = blockchain.chain.last().unwrap();
+            Block {
+                index: index,
+                timestamp: parent.timestamp + 1,
+                merkle_root: Blockchain::merkle_root(&transactions),
+                transactions: transactions,
+                proof: 0,
+                previous_hash: Blockchain::hash(parent),
+                signer: String::new(),
+                signature: String::new(),
+                vote: None,
+                evidence: vec![],
+            }
+        };
+        blockchain.chain.push(block);
+    }
+    blockchain
+}
+
+// Validating a 100k block chain, as when syncing from scratch: with one
+// thread per CPU... Each run takes a second or two on a single core, so
+// these take several minutes.
+#[bench]
+fn valid_chain_100k(b: &mut Bencher) {
+    let blockchain = synthetic_chain(100_000);
+    b.iter(|| {
+        blockchain.valid_chain_with(&blockchain.chain, parallel::threads()).unwrap()
+    });
+}
+
+// ...and with a single thread.
+#[bench]
+fn valid_chain_100k_one_thread(b: &mut Bencher) {
+    let blockchain = synthetic_chain(100_000);
+    b.iter(|| {
+        blockchain.valid_chain_with(&blockchain.chain, 1).unwrap()
+    });
+}
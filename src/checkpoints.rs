@@ -0,0 +1,44 @@
+// Checkpoints.
+// A checkpoint pins the hash of the block at some index. Chains which
+// have a different block there are refused, however much work they have,
+// so history before the last checkpoint can't be rewritten. And since
+// the blocks up to a checkpoint are known to be good, their consensus
+// data (proofs of work, signatures) doesn't need checking again.
+
+use std::collections::BTreeMap;
+
+use config::NodeConfig;
+use keys;
+use network::Network;
+
+// A checkpoint, as written on the configuration file.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Checkpoint {
+    pub index: u64,
+    pub hash: String,
+}
+
+// Gathers the checkpoints of a network and those on the node
+// configuration.
+// Return: Block hash for each checkpointed index, or Err if some
+// checkpoint is malformed or contradicts another.
+pub fn from_config(config: &NodeConfig, network: &Network) -> Result<BTreeMap<u64, String>, String> {
+    let mut checkpoints = BTreeMap::new();
+    for checkpoint in network.checkpoints.iter().chain(config.checkpoints.iter()).cloned() {
+        let valid_hash = match keys::from_hex(&checkpoint.hash) {
+            Some(bytes) => bytes.len() == 32,
+            None => false,
+        };
+        if checkpoint.index < 1 || !valid_hash {
+            return Err(format!("Invalid checkpoint for block {}: {}.",
+                               checkpoint.index, checkpoint.hash));
+        }
+        let hash = checkpoint.hash.to_lowercase();
+        if let Some(other) = checkpoints.insert(checkpoint.index, hash.clone()) {
+            if other != hash {
+                return Err(format!("Conflicting checkpoints for block {}.", checkpoint.index));
+            }
+        }
+    }
+    Ok(checkpoints)
+}
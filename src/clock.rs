@@ -0,0 +1,49 @@
+// Clocks.
+// Block timestamps are Unix time, in seconds. The blockchain reads the
+// time through the Clock trait so tests can make it say whatever they
+// want.
+
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub trait Clock: Send + Sync {
+    // Current Unix time, in seconds.
+    fn now(&self) -> u64;
+}
+
+// The local wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(elapsed) => elapsed.as_secs(),
+            Err(_) => 0, // Clock set before 1970. Not much we can do.
+        }
+    }
+}
+
+// A clock that only moves when told to.
+#[cfg(test)]
+pub struct FixedClock {
+    time: Mutex<u64>,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(time: u64) -> FixedClock {
+        FixedClock { time: Mutex::new(time) }
+    }
+
+    pub fn set(&self, time: u64) {
+        *self.time.lock().unwrap() = time;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> u64 {
+        *self.time.lock().unwrap()
+    }
+}
@@ -0,0 +1,165 @@
+// Node configuration.
+// The configuration is a JSON file, just like everything else we save to
+// disk. Missing fields take their default values, so an empty object (or
+// no file at all) gives a node that behaves like it always did.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+
+use serde_json;
+
+use checkpoints::Checkpoint;
+use consensus;
+use network;
+use peers;
+
+// Timestamp of the genesis block, unless the network says otherwise:
+// 2018-01-01 00:00:00 UTC.
+pub const GENESIS_TIMESTAMP: u64 = 1514764800;
+
+// Size limits, unless the network says otherwise.
+pub const MAX_BLOCK_SIZE: usize = 1_000_000;
+pub const MAX_TRANSACTION_SIZE: usize = 100_000;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct NodeConfig {
+    // Network to join, see network::from_config.
+    pub network: String,
+    // Name of the consensus engine, see consensus::from_config.
+    pub consensus: String,
+    // File holding this node's block signing key.
+    pub signer_key: String,
+    // Parameters every node on the network must agree on, for networks
+    // which are not built in.
+    pub genesis: Option<GenesisConfig>,
+    // Checkpoints, on top of the built-in ones.
+    pub checkpoints: Vec<Checkpoint>,
+    // Addresses of nodes to find peers from, on top of the built-in ones.
+    pub seeds: Vec<String>,
+    // Misbehaviour score which gets a node banned, and for how long, in
+    // seconds.
+    pub ban_threshold: u64,
+    pub ban_duration: u64,
+    // Addresses of nodes to keep p2p connections to, like 127.0.0.1:4001.
+    pub p2p_peers: Vec<String>,
+    // Address the HTTP server binds to. 0.0.0.0 takes connections from
+    // other machines too.
+    pub http_bind: String,
+    // URL other nodes reach our HTTP server at, like
+    // http://203.0.113.5:3000, which we announce blocks and transactions
+    // from. Where we bind, unless we bind to every address.
+    pub public_url: Option<String>,
+    // Address the p2p listener binds to, if not the HTTP one.
+    pub p2p_bind: Option<String>,
+    // Bearer token, or user and password for basic auth, which endpoints
+    // changing our state require. With none of them, they are open.
+    pub api_token: Option<String>,
+    pub api_user: Option<String>,
+    pub api_password: Option<String>,
+}
+
+// Genesis configuration.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GenesisConfig {
+    // Timestamp and proof of the genesis block.
+    pub timestamp: u64,
+    pub proof: u64,
+    // Coins given to each address on the genesis block.
+    pub allocations: BTreeMap<String, i64>,
+    // Hashing function for proof of work: "sha256" or "scrypt".
+    pub pow_hash: String,
+    // How many leading zeroes (in hex) a proof of work hash needs.
+    pub pow_difficulty: usize,
+    // Public keys of the signers authorized from genesis on,
+    // for proof of authority.
+    pub signers: Vec<String>,
+    // Stake of each validator at genesis, by public key,
+    // for proof of stake.
+    pub stakes: BTreeMap<String, i64>,
+    // Largest block and transaction allowed, in bytes of their canonical
+    // encoding.
+    pub max_block_size: usize,
+    pub max_transaction_size: usize,
+    // Consensus engine of the network. It is set from the node
+    // configuration, see network::from_config, but the genesis block
+    // commits to it along with everything else here.
+    #[serde(skip)]
+    pub consensus: String,
+}
+
+impl Default for GenesisConfig {
+    fn default() -> GenesisConfig {
+        GenesisConfig {
+            timestamp:            GENESIS_TIMESTAMP,
+            proof:                100,
+            allocations:          BTreeMap::new(),
+            pow_hash:             "sha256".to_owned(),
+            pow_difficulty:       consensus::pow::DEFAULT_DIFFICULTY,
+            signers:              vec![],
+            stakes:               BTreeMap::new(),
+            max_block_size:       MAX_BLOCK_SIZE,
+            max_transaction_size: MAX_TRANSACTION_SIZE,
+            consensus:            consensus::DEFAULT_ENGINE.to_owned(),
+        }
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig {
+            network:       network::DEFAULT_NETWORK.to_owned(),
+            consensus:     consensus::DEFAULT_ENGINE.to_owned(),
+            signer_key:    "signer.json".to_owned(),
+            genesis:       None,
+            checkpoints:   vec![],
+            seeds:         vec![],
+            ban_threshold: peers::DEFAULT_BAN_THRESHOLD,
+            ban_duration:  peers::DEFAULT_BAN_DURATION,
+            p2p_peers:     vec![],
+            http_bind:     "127.0.0.1".to_owned(),
+            public_url:    None,
+            p2p_bind:      None,
+            api_token:     None,
+            api_user:      None,
+            api_password:  None,
+        }
+    }
+}
+
+impl NodeConfig {
+    // Load configuration from file
+    pub fn from_file(filename: String) -> NodeConfig {
+        let f = File::open(filename);
+        match f {
+            Err(_) => {
+                println!("Cannot read config file. Using default configuration.");
+                NodeConfig::default()
+            },
+            Ok(mut f) => {
+                let mut text = String::new();
+                match f.read_to_string(&mut text) {
+                    Ok(_) => NodeConfig::from_str(&text),
+                    Err(_) => {
+                        println!("Cannot read config file text. Using default configuration.");
+                        NodeConfig::default()
+                    }
+                }
+            }
+        }
+    }
+
+    // Load configuration from string
+    pub fn from_str(string: &String) -> NodeConfig {
+        let deserialized = serde_json::from_str(string);
+        match deserialized {
+            Ok(config) => config,
+            Err(_)     => {
+                println!("Cannot parse config file. Using default configuration.");
+                NodeConfig::default()
+            }
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Consensus engines.
+// Mining, validation and fork choice used to be hard-wired into the
+// Blockchain itself. They now live behind the ConsensusEngine trait, so
+// we can experiment with other engines without forking the node. The
+// engine is picked by name from the node configuration.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use super::{Block, BlockHeader, Blockchain};
+use config::NodeConfig;
+use keys::SigningKey;
+use network::Network;
+
+pub mod pow;
+mod poa;
+mod pos;
+
+pub use self::pow::{ProofOfWork, PowHash};
+pub use self::poa::ProofOfAuthority;
+pub use self::pos::ProofOfStake;
+
+// Name of the engine used when the configuration says nothing.
+pub const DEFAULT_ENGINE: &'static str = "pow";
+
+// Everything a consensus engine has to decide about blocks.
+// Whenever a chain is given along with a block, it holds every block
+// that comes before it, from genesis up to its parent.
+pub trait ConsensusEngine: Send + Sync {
+    // Name of this engine, as used on the configuration file.
+    fn name(&self) -> &'static str;
+
+    // Block production. Fills in whatever the engine needs on a new
+    // block (a proof, a signature...) so that it may be appended to chain.
+    // Return: Err with a reason if this node cannot produce the block.
+    fn seal(&self, chain: &[Block], block: &mut Block) -> Result<(), String>;
+
+    // Header validation. Checks the consensus data of block.
+    fn verify_header(&self, chain: &[Block], block: &Block) -> bool;
+
+    // Checks whatever of the consensus data of a header doesn't depend on
+    // the chain before it (a proof, a signature...), so that header chains
+    // can be weeded out before downloading any block.
+    fn verify_seal(&self, _header: &BlockHeader) -> bool {
+        true
+    }
+
+    // Fork choice. How much a block adds to the cumulative work of its
+    // branch; we follow the branch with the most work. It only depends on
+    // the header, so header chains can be weighed before downloading any
+    // block. By default every block weighs the same, so the longest
+    // branch wins.
+    fn block_work(&self, _header: &BlockHeader) -> u64 {
+        1
+    }
+
+    // Public key this node signs blocks with, for engines which sign them.
+    fn local_signer(&self) -> Option<String> {
+        None
+    }
+
+    // Signers authorized to produce the block after chain, for engines
+    // which have them.
+    fn signers(&self, _chain: &[Block]) -> Option<Vec<String>> {
+        None
+    }
+
+    // Starts voting on whether candidate should be authorized
+    // (or deauthorized) as a signer, on every block we produce.
+    fn propose(&self, _candidate: String, _authorize: bool) -> Result<(), String> {
+        Err(format!("The {} engine has no signers to vote on.", self.name()))
+    }
+
+    // Stops voting on candidate.
+    fn discard(&self, _candidate: &str) -> Result<(), String> {
+        Err(format!("The {} engine has no signers to vote on.", self.name()))
+    }
+
+    // Stake locked by each validator after chain, for engines with stake.
+    fn stakes(&self, _chain: &[Block]) -> Option<Vec<(String, i64)>> {
+        None
+    }
+
+    // Address which coins are sent to in order to lock them as stake.
+    fn stake_address(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+// How many replayed states a ReplayCache keeps. When full, the oldest
+// one goes away.
+pub const REPLAY_CACHE_SIZE: usize = 1000;
+
+// State some engines replay over the whole chain (signer sets, stake
+// tables...), kept by hash of the last block replayed, so that every
+// block is only replayed once rather than on every call.
+pub struct ReplayCache<S> {
+    states: Mutex<HashMap<String, S>>,
+    // Hashes in the order they were cached
+    order: Mutex<VecDeque<String>>,
+}
+
+impl<S: Clone> ReplayCache<S> {
+    pub fn new() -> ReplayCache<S> {
+        ReplayCache {
+            states: Mutex::new(HashMap::new()),
+            order: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    // State after chain.
+    // initial: State at genesis
+    // apply: Replays one block on the state
+    pub fn get<F: Fn(&mut S, &Block)>(&self, chain: &[Block], initial: &S, apply: F) -> S {
+        // Walk back from the tip until we find a block we replayed before.
+        // Only cache what comes from a properly linked chain, of blocks
+        // whose bodies match their Merkle roots without repeating any
+        // transaction, or a forged one could leave
+        // a wrong state behind under a real block hash.
+        let mut hashes = vec![];
+        let mut linked = true;
+        let mut state = None;
+        for i in (1..chain.len()).rev() {
+            let hash = Blockchain::hash(&chain[i]);
+            if i + 1 < chain.len() && chain[i + 1].previous_hash != hash {
+                linked = false;
+            }
+            if let Some(cached) = self.states.lock().unwrap().get(&hash) {
+                state = Some(cached.clone());
+                break;
+            }
+            hashes.push(hash);
+        }
+        if state.is_none() && chain.len() > 1 && chain[1].previous_hash != Blockchain::hash(&chain[0]) {
+            linked = false;
+        }
+        let mut state = state.unwrap_or_else(|| initial.clone());
+
+        // Then replay the rest
+        let replayed = chain.len() - hashes.len();
+        for block in &chain[replayed..] {
+            apply(&mut state, block);
+            let hash = hashes.pop().unwrap();
+            linked = linked && block.merkle_root == Blockchain::merkle_root(&block.transactions)
+                && Blockchain::distinct_transactions(&block.transactions);
+            if linked {
+                self.insert(hash, state.clone());
+            }
+        }
+        state
+    }
+
+    fn insert(&self, hash: String, state: S) {
+        let mut states = self.states.lock().unwrap();
+        let mut order = self.order.lock().unwrap();
+        if states.insert(hash.clone(), state).is_none() {
+            order.push_back(hash);
+        }
+        while order.len() > REPLAY_CACHE_SIZE {
+            if let Some(oldest) = order.pop_front() {
+                states.remove(&oldest);
+            }
+        }
+    }
+}
+
+// Engine used by a Blockchain which was just created or deserialized.
+pub fn default_engine() -> Arc<dyn ConsensusEngine> {
+    Arc::new(ProofOfWork::new(PowHash::Sha256, pow::DEFAULT_DIFFICULTY))
+}
+
+// Creates the consensus engine of a network.
+// config: Node configuration, for whatever is not up to the network
+// Return: The engine, or Err if it is unknown or badly configured.
+pub fn from_config(config: &NodeConfig, network: &Network) -> Result<Arc<dyn ConsensusEngine>, String> {
+    let genesis = &network.genesis;
+    match network.consensus.as_ref() {
+        "pow" => {
+            let hash = match PowHash::from_name(genesis.pow_hash.as_ref()) {
+                Some(hash) => hash,
+                None => return Err(format!("Unknown proof of work hash \"{}\".",
+                                           genesis.pow_hash)),
+            };
+            Ok(Arc::new(ProofOfWork::new(hash, genesis.pow_difficulty)))
+        },
+        "poa" => {
+            let key = SigningKey::from_file(config.signer_key.clone());
+            let engine = ProofOfAuthority::new(genesis.signers.clone(), key)?;
+            Ok(Arc::new(engine))
+        },
+        "pos" => {
+            let key = SigningKey::from_file(config.signer_key.clone());
+            let engine = ProofOfStake::new(genesis.stakes.clone(), key)?;
+            Ok(Arc::new(engine))
+        },
+        name => Err(format!("Unknown consensus engine \"{}\".", name)),
+    }
+}
@@ -0,0 +1,184 @@
+// Proof of authority, for private networks.
+// A set of authorized signers, listed on the genesis configuration, take
+// turns producing blocks: the block at index i belongs to the signer at
+// position i % n of the sorted signer list. Each block is signed by its
+// producer. Signers may vote, one vote per block they produce, to add or
+// remove other signers; a proposal passes once more than half of the
+// current signers voted for it.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+use super::{ConsensusEngine, ReplayCache};
+use super::super::{Block, BlockHeader, Blockchain, SignerVote};
+use keys;
+use keys::SigningKey;
+
+pub struct ProofOfAuthority {
+    genesis_signers: BTreeSet<String>,
+    key: SigningKey,
+    // Our own proposals, true for authorizing and false for deauthorizing.
+    proposals: Mutex<HashMap<String, bool>>,
+    snapshots: ReplayCache<Snapshot>,
+}
+
+// Signer set after some block, along with the votes cast so far.
+#[derive(Clone)]
+struct Snapshot {
+    signers: BTreeSet<String>,
+    // (candidate, authorize) -> signers who voted for it
+    tally: HashMap<(String, bool), HashSet<String>>,
+}
+
+impl Snapshot {
+    // Applies the vote carried by a block.
+    fn apply(&mut self, voter: &String, vote: &SignerVote) {
+        if !self.signers.contains(voter) {
+            return;
+        }
+        // Votes asking for what is already in place mean nothing
+        if self.signers.contains(&vote.candidate) == vote.authorize {
+            return;
+        }
+        let key = (vote.candidate.clone(), vote.authorize);
+        let votes = {
+            let voters = self.tally.entry(key).or_insert(HashSet::new());
+            voters.insert(voter.clone());
+            voters.len()
+        };
+
+        if votes > self.signers.len() / 2 {
+            if vote.authorize {
+                self.signers.insert(vote.candidate.clone());
+            } else {
+                self.signers.remove(&vote.candidate);
+                // Whatever the removed signer voted for does not count anymore
+                for voters in self.tally.values_mut() {
+                    voters.remove(&vote.candidate);
+                }
+            }
+            let candidate = vote.candidate.clone();
+            self.tally.retain(|&(ref c, _), _| *c != candidate);
+        }
+    }
+
+    // Signer whose turn it is to produce the block at index.
+    fn in_turn(&self, index: u64) -> Option<&String> {
+        if self.signers.is_empty() {
+            return None;
+        }
+        let position = (index % self.signers.len() as u64) as usize;
+        self.signers.iter().nth(position)
+    }
+}
+
+impl ProofOfAuthority {
+    // genesis_signers: Public keys of the signers authorized at genesis
+    // key: This node's signing key
+    pub fn new(genesis_signers: Vec<String>, key: SigningKey) -> Result<ProofOfAuthority, String> {
+        if genesis_signers.is_empty() {
+            return Err("Proof of authority needs at least one genesis signer.".to_owned());
+        }
+        for signer in &genesis_signers {
+            if !keys::valid_public_key(signer) {
+                return Err(format!("Invalid genesis signer key {}.", signer));
+            }
+        }
+        Ok(ProofOfAuthority {
+            genesis_signers: genesis_signers.into_iter().collect(),
+            key: key,
+            proposals: Mutex::new(HashMap::new()),
+            snapshots: ReplayCache::new(),
+        })
+    }
+
+    // Replays the votes on chain, starting from the genesis signers.
+    fn snapshot(&self, chain: &[Block]) -> Snapshot {
+        let genesis = Snapshot {
+            signers: self.genesis_signers.clone(),
+            tally: HashMap::new(),
+        };
+        self.snapshots.get(chain, &genesis, |snapshot, block| {
+            if let Some(ref vote) = block.vote {
+                snapshot.apply(&block.signer, vote);
+            }
+        })
+    }
+}
+
+impl ConsensusEngine for ProofOfAuthority {
+    fn name(&self) -> &'static str {
+        "poa"
+    }
+
+    fn seal(&self, chain: &[Block], block: &mut Block) -> Result<(), String> {
+        let snapshot = self.snapshot(chain);
+        let us = self.key.public_key();
+        if !snapshot.signers.contains(&us) {
+            return Err(format!("{} is not an authorized signer.", us));
+        }
+        match snapshot.in_turn(block.index) {
+            Some(signer) if *signer == us => {},
+            Some(signer) => return Err(format!("Block {} is for signer {} to produce.",
+                                               block.index, signer)),
+            None => return Err("There are no authorized signers left.".to_owned()),
+        }
+
+        // Cast a vote for one of our pending proposals, if any of them
+        // still makes sense and we haven't voted on it yet.
+        let proposals = self.proposals.lock().unwrap();
+        let mut pending: Vec<(&String, &bool)> = proposals.iter()
+            .filter(|&(candidate, authorize)| {
+                snapshot.signers.contains(candidate) != *authorize
+                    && !snapshot.tally.get(&(candidate.clone(), *authorize))
+                    .map_or(false, |voters| voters.contains(&us))
+            })
+            .collect();
+        pending.sort();
+        block.vote = pending.first().map(|&(candidate, authorize)| SignerVote {
+            candidate: candidate.clone(),
+            authorize: *authorize,
+        });
+
+        block.signer = us;
+        block.signature = self.key.sign(Blockchain::seal_hash(&block.header()).as_bytes());
+        Ok(())
+    }
+
+    fn verify_header(&self, chain: &[Block], block: &Block) -> bool {
+        let snapshot = self.snapshot(chain);
+        // Signer must be authorized, and it must be its turn
+        match snapshot.in_turn(block.index) {
+            Some(signer) if *signer == block.signer => {},
+            _ => return false,
+        }
+        self.verify_seal(&block.header())
+    }
+
+    fn verify_seal(&self, header: &BlockHeader) -> bool {
+        keys::verify(&header.signer, Blockchain::seal_hash(header).as_bytes(), &header.signature)
+    }
+
+    fn local_signer(&self) -> Option<String> {
+        Some(self.key.public_key())
+    }
+
+    fn signers(&self, chain: &[Block]) -> Option<Vec<String>> {
+        Some(self.snapshot(chain).signers.into_iter().collect())
+    }
+
+    fn propose(&self, candidate: String, authorize: bool) -> Result<(), String> {
+        if !keys::valid_public_key(&candidate) {
+            return Err(format!("{} is not a valid public key.", candidate));
+        }
+        self.proposals.lock().unwrap().insert(candidate, authorize);
+        Ok(())
+    }
+
+    fn discard(&self, candidate: &str) -> Result<(), String> {
+        match self.proposals.lock().unwrap().remove(candidate) {
+            Some(_) => Ok(()),
+            None => Err(format!("There is no proposal for {}.", candidate)),
+        }
+    }
+}
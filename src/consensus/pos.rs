@@ -0,0 +1,192 @@
+// Proof of stake.
+// Instead of burning CPU on proof_of_work, the right to produce a block is
+// given to an address which locked some of its coins as stake. Coins are
+// locked by sending them to STAKE_ADDRESS, and the sender must be the
+// validator's public key, so that its blocks can be checked.
+// Each slot (the index of the block being produced) picks its proposer
+// pseudo-randomly, weighted by stake, using the previous block hash as
+// seed. A validator caught signing two different blocks for the same slot
+// loses all of its stake once the evidence is included in a block.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use super::{ConsensusEngine, ReplayCache};
+use super::super::{Block, BlockHeader, Blockchain, DoubleSignEvidence};
+use keys;
+use keys::SigningKey;
+
+// Recipient of stake locking transactions.
+pub const STAKE_ADDRESS: &'static str = "stake";
+
+pub struct ProofOfStake {
+    genesis_stakes: BTreeMap<String, i64>,
+    key: SigningKey,
+    tables: ReplayCache<Stakes>,
+}
+
+// Stake table after some block.
+#[derive(Clone)]
+struct Stakes {
+    stakes: BTreeMap<String, i64>,
+    slashed: HashSet<String>,
+}
+
+impl ProofOfStake {
+    // genesis_stakes: Stake of each validator at genesis, by public key
+    // key: This node's signing key
+    pub fn new(genesis_stakes: BTreeMap<String, i64>, key: SigningKey) -> Result<ProofOfStake, String> {
+        if genesis_stakes.values().all(|&stake| stake <= 0) {
+            return Err("Proof of stake needs at least one genesis stake.".to_owned());
+        }
+        for validator in genesis_stakes.keys() {
+            if !keys::valid_public_key(validator) {
+                return Err(format!("Invalid genesis validator key {}.", validator));
+            }
+        }
+        Ok(ProofOfStake {
+            genesis_stakes: genesis_stakes,
+            key: key,
+            tables: ReplayCache::new(),
+        })
+    }
+
+    // Replays stake locks and slashings on chain.
+    fn stake_table(&self, chain: &[Block]) -> Stakes {
+        let genesis = Stakes {
+            stakes: self.genesis_stakes.clone(),
+            slashed: HashSet::new(),
+        };
+        self.tables.get(chain, &genesis, |table, block| {
+            for transaction in &block.transactions {
+                if transaction.recipient == STAKE_ADDRESS
+                    && !table.slashed.contains(&transaction.sender) {
+                    *table.stakes.entry(transaction.sender.clone()).or_insert(0) += transaction.amount;
+                }
+            }
+            for evidence in &block.evidence {
+                table.stakes.remove(&evidence.first.signer);
+                table.slashed.insert(evidence.first.signer.clone());
+            }
+        })
+    }
+
+    // Picks the proposer for the block coming after chain.
+    fn proposer(&self, chain: &[Block]) -> Option<String> {
+        let table = self.stake_table(chain);
+        let total: u64 = table.stakes.values()
+            .filter(|&&stake| stake > 0)
+            .map(|&stake| stake as u64)
+            .sum();
+        if total == 0 {
+            return None;
+        }
+
+        // Seed is the previous block hash, along with the slot
+        let slot = chain.len() as u64 + 1;
+        let mut hasher = Sha256::new();
+        hasher.input_str(&Blockchain::hash(chain.last().unwrap()));
+        hasher.input_str(&slot.to_string());
+        let seed = u64::from_str_radix(&hasher.result_str()[..16], 16).unwrap();
+
+        // Walk the cumulative stake until we reach the drawn ticket
+        let mut ticket = seed % total;
+        for (validator, &stake) in &table.stakes {
+            if stake <= 0 {
+                continue;
+            }
+            if ticket < stake as u64 {
+                return Some(validator.clone());
+            }
+            ticket -= stake as u64;
+        }
+        None
+    }
+
+    // Checks slashing evidence: two different blocks for the same slot,
+    // both signed by the same validator.
+    fn valid_evidence(evidence: &DoubleSignEvidence) -> bool {
+        let first = &evidence.first;
+        let second = &evidence.second;
+        first.index == second.index
+            && !first.signer.is_empty()
+            && first.signer == second.signer
+            && Blockchain::seal_hash(first) != Blockchain::seal_hash(second)
+            && keys::verify(&first.signer, Blockchain::seal_hash(first).as_bytes(), &first.signature)
+            && keys::verify(&second.signer, Blockchain::seal_hash(second).as_bytes(), &second.signature)
+    }
+}
+
+impl ConsensusEngine for ProofOfStake {
+    fn name(&self) -> &'static str {
+        "pos"
+    }
+
+    fn seal(&self, chain: &[Block], block: &mut Block) -> Result<(), String> {
+        let us = self.key.public_key();
+        match self.proposer(chain) {
+            Some(ref proposer) if *proposer == us => {},
+            Some(proposer) => return Err(format!("Slot {} belongs to validator {}.",
+                                                 block.index, proposer)),
+            None => return Err("There is no stake left to elect a proposer.".to_owned()),
+        }
+
+        // Don't include evidence against someone who was already slashed
+        let table = self.stake_table(chain);
+        let mut offenders = HashSet::new();
+        block.evidence.retain(|evidence| {
+            ProofOfStake::valid_evidence(evidence)
+                && !table.slashed.contains(&evidence.first.signer)
+                && offenders.insert(evidence.first.signer.clone())
+        });
+
+        block.signer = us;
+        block.signature = self.key.sign(Blockchain::seal_hash(&block.header()).as_bytes());
+        Ok(())
+    }
+
+    fn verify_header(&self, chain: &[Block], block: &Block) -> bool {
+        // Proposer must have been elected for this slot
+        match self.proposer(chain) {
+            Some(ref proposer) if *proposer == block.signer => {},
+            _ => return false,
+        }
+        if !self.verify_seal(&block.header()) {
+            return false;
+        }
+
+        // Slashing evidence must hold, and punish each validator only once
+        let table = self.stake_table(chain);
+        let mut offenders = HashSet::new();
+        block.evidence.iter().all(|evidence| {
+            ProofOfStake::valid_evidence(evidence)
+                && !table.slashed.contains(&evidence.first.signer)
+                && offenders.insert(evidence.first.signer.clone())
+        })
+    }
+
+    fn verify_seal(&self, header: &BlockHeader) -> bool {
+        keys::verify(&header.signer, Blockchain::seal_hash(header).as_bytes(), &header.signature)
+    }
+
+    fn local_signer(&self) -> Option<String> {
+        Some(self.key.public_key())
+    }
+
+    fn signers(&self, chain: &[Block]) -> Option<Vec<String>> {
+        Some(self.stake_table(chain).stakes.into_iter()
+             .filter(|&(_, stake)| stake > 0)
+             .map(|(validator, _)| validator)
+             .collect())
+    }
+
+    fn stakes(&self, chain: &[Block]) -> Option<Vec<(String, i64)>> {
+        Some(self.stake_table(chain).stakes.into_iter().collect())
+    }
+
+    fn stake_address(&self) -> Option<&'static str> {
+        Some(STAKE_ADDRESS)
+    }
+}
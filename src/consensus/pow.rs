@@ -0,0 +1,133 @@
+// Hashcash-style proof of work, the original consensus of blockchain-rs.
+// The hashing function is chosen per network at genesis: SHA-256, which
+// is what we always used, or scrypt, which needs a good chunk of memory
+// for each hash and so doesn't favour whoever has the most cores as much.
+
+use crypto::digest::Digest;
+use crypto::scrypt::{scrypt, ScryptParams};
+use crypto::sha2::Sha256;
+
+use super::ConsensusEngine;
+use super::super::{Block, BlockHeader, Blockchain};
+use keys;
+
+// Leading zeroes a proof hash needs, unless the network says otherwise.
+pub const DEFAULT_DIFFICULTY: usize = 4;
+
+// Most leading zeroes a proof hash may be asked for: every hex digit of
+// a 256 bit hash.
+pub const MAX_DIFFICULTY: usize = 64;
+
+// Scrypt parameters: N = 2^10, r = 8, p = 1, so each hash goes through
+// 1 MiB of memory.
+const SCRYPT_LOG_N: u8 = 10;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+// Hashing functions the proof of work may use.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PowHash {
+    Sha256,
+    Scrypt,
+}
+
+impl PowHash {
+    // Gets a hashing function from its name on the genesis configuration.
+    pub fn from_name(name: &str) -> Option<PowHash> {
+        match name {
+            "sha256" => Some(PowHash::Sha256),
+            "scrypt" => Some(PowHash::Scrypt),
+            _        => None,
+        }
+    }
+
+    // Hashes data.
+    // Return: hash string
+    pub fn hash(&self, data: &[u8]) -> String {
+        match *self {
+            PowHash::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.input(data);
+                hasher.result_str()
+            },
+            PowHash::Scrypt => {
+                let params = ScryptParams::new(SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P);
+                let mut output = [0u8; 32];
+                scrypt(data, data, &params, &mut output);
+                keys::to_hex(&output)
+            },
+        }
+    }
+}
+
+pub struct ProofOfWork {
+    hash: PowHash,
+    difficulty: usize,
+}
+
+impl ProofOfWork {
+    // hash: Hashing function for the proofs
+    // difficulty: How many leading zeroes a proof hash must have
+    pub fn new(hash: PowHash, difficulty: usize) -> ProofOfWork {
+        ProofOfWork {
+            hash: hash,
+            difficulty: difficulty,
+        }
+    }
+
+    // Simple Proof of Work algorithm.
+    // Find a number p such that hash(sp) contains leading zeroes, where s
+    // is the work hash of the block header, so that the proof only holds
+    // for the header it was found for.
+    // work_hash: Work hash of the header being sealed, see work_hash
+    pub fn proof_of_work(&self, work_hash: &str) -> u64 {
+        let mut proof: u64 = 0;
+        while !self.valid_proof(work_hash, proof) {
+            proof += 1;
+        }
+        proof
+    }
+
+    // Validates the proof of work.
+    // work_hash: Work hash of the header the proof is for
+    // proof: Proof of work
+    // Return: Whether proof is correct
+    pub fn valid_proof(&self, work_hash: &str, proof: u64) -> bool {
+        let guess = work_hash.to_owned() + proof.to_string().as_ref();
+        let result = self.hash.hash(&guess.into_bytes());
+        // Now check if the first characters are zeroes
+        result.chars().take(self.difficulty).all(|c| c == '0')
+    }
+}
+
+// Hash a proof of work is found for: the seal hash of the header (its
+// index, timestamp, Merkle root and previous hash...) without the proof.
+pub fn work_hash(header: &BlockHeader) -> String {
+    let mut unproven = header.clone();
+    unproven.proof = 0;
+    Blockchain::seal_hash(&unproven)
+}
+
+impl ConsensusEngine for ProofOfWork {
+    fn name(&self) -> &'static str {
+        "pow"
+    }
+
+    fn seal(&self, _chain: &[Block], block: &mut Block) -> Result<(), String> {
+        block.proof = self.proof_of_work(&work_hash(&block.header()));
+        Ok(())
+    }
+
+    fn verify_header(&self, _chain: &[Block], block: &Block) -> bool {
+        self.verify_seal(&block.header())
+    }
+
+    fn verify_seal(&self, header: &BlockHeader) -> bool {
+        self.valid_proof(&work_hash(header), header.proof)
+    }
+
+    // Each leading zero takes 16 times more hashes to find, on average.
+    fn block_work(&self, _header: &BlockHeader) -> u64 {
+        1 << (4 * self.difficulty.min(15))
+    }
+}
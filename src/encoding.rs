@@ -0,0 +1,331 @@
+// Canonical binary encoding.
+// Whatever gets hashed or signed (block headers, transactions) is first
+// encoded with these rules, so hashes don't depend on how some JSON
+// library orders fields, spaces things out or writes numbers. JSON is
+// still what we save to disk and serve over HTTP; it just never gets
+// hashed. Nodes talking over p2p send each other these encodings, too.
+//
+// Version 2 rules:
+// - Every encoding starts with the version byte;
+// - u64 and i64 are 8 bytes, big-endian (i64 in two's complement);
+// - bool is a single byte, 0 or 1;
+// - Strings are their length, as u64, followed by their UTF-8 bytes;
+// - Vectors are their length, as u64, followed by each element;
+// - Options are a 0 byte for None, or a 1 byte followed by the value;
+// - Maps are their length, as u64, followed by each key and its value,
+//   in key order;
+// - Structs are their fields, in the order they are declared below.
+// Changing any of these rules means bumping ENCODING_VERSION.
+//
+// Version 2 added the nonce to transactions.
+//
+// Decoding follows the same rules backwards, refusing anything that
+// doesn't follow them exactly.
+
+use std::collections::BTreeMap;
+
+use super::{Block, BlockHeader, DoubleSignEvidence, SignerVote, Transaction};
+use config::GenesisConfig;
+
+pub const ENCODING_VERSION: u8 = 2;
+
+pub trait Encode {
+    // Appends the encoding of this value to out.
+    fn encode(&self, out: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    // Reads a value off the start of input, moving input past it.
+    fn decode(input: &mut &[u8]) -> Result<Self, String>;
+}
+
+// Encodes a value, starting with the version byte.
+pub fn to_bytes<T: Encode>(value: &T) -> Vec<u8> {
+    let mut out = vec![ENCODING_VERSION];
+    value.encode(&mut out);
+    out
+}
+
+// Length of the encoding of a value, without the version byte: what it
+// takes inside a block.
+pub fn encoded_len<T: Encode>(value: &T) -> usize {
+    let mut out = vec![];
+    value.encode(&mut out);
+    out.len()
+}
+
+// Decodes a value, starting with the version byte, which must take the
+// whole of bytes.
+pub fn from_bytes<T: Decode>(bytes: &[u8]) -> Result<T, String> {
+    let mut input = bytes;
+    match take(&mut input, 1)?[0] {
+        ENCODING_VERSION => {},
+        version => return Err(format!("unknown encoding version {}", version)),
+    }
+    let value = T::decode(&mut input)?;
+    if !input.is_empty() {
+        return Err(format!("{} bytes left over", input.len()));
+    }
+    Ok(value)
+}
+
+// Takes the next count bytes off input.
+fn take<'a>(input: &mut &'a [u8], count: usize) -> Result<&'a [u8], String> {
+    if input.len() < count {
+        return Err("unexpected end of input".to_owned());
+    }
+    let (taken, rest) = input.split_at(count);
+    *input = rest;
+    Ok(taken)
+}
+
+// Reads a length, making sure that many items (of one byte at least) can
+// be there, so no length makes us allocate more than we were sent.
+fn decode_len(input: &mut &[u8]) -> Result<usize, String> {
+    let len = u64::decode(input)?;
+    if len > input.len() as u64 {
+        return Err(format!("length {} runs past the end of input", len));
+    }
+    Ok(len as usize)
+}
+
+impl Encode for u64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        for shift in (0..8).rev() {
+            out.push((*self >> (shift * 8)) as u8);
+        }
+    }
+}
+
+impl Encode for i64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (*self as u64).encode(out);
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(if *self { 1 } else { 0 });
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl<T: Encode> Encode for Vec<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for item in self {
+            item.encode(out);
+        }
+    }
+}
+
+impl<T: Encode> Encode for Option<T> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            None => out.push(0),
+            Some(ref value) => {
+                out.push(1);
+                value.encode(out);
+            },
+        }
+    }
+}
+
+impl<K: Encode, V: Encode> Encode for BTreeMap<K, V> {
+    fn encode(&self, out: &mut Vec<u8>) {
+        (self.len() as u64).encode(out);
+        for (key, value) in self {
+            key.encode(out);
+            value.encode(out);
+        }
+    }
+}
+
+impl Decode for u64 {
+    fn decode(input: &mut &[u8]) -> Result<u64, String> {
+        Ok(take(input, 8)?.iter().fold(0, |value, &byte| (value << 8) | byte as u64))
+    }
+}
+
+impl Decode for i64 {
+    fn decode(input: &mut &[u8]) -> Result<i64, String> {
+        u64::decode(input).map(|value| value as i64)
+    }
+}
+
+impl Decode for bool {
+    fn decode(input: &mut &[u8]) -> Result<bool, String> {
+        match take(input, 1)?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            byte => Err(format!("invalid bool {}", byte)),
+        }
+    }
+}
+
+impl Decode for String {
+    fn decode(input: &mut &[u8]) -> Result<String, String> {
+        let len = decode_len(input)?;
+        String::from_utf8(take(input, len)?.to_vec()).map_err(|_| "invalid UTF-8".to_owned())
+    }
+}
+
+impl<T: Decode> Decode for Vec<T> {
+    fn decode(input: &mut &[u8]) -> Result<Vec<T>, String> {
+        let len = decode_len(input)?;
+        let mut items = vec![];
+        for _ in 0..len {
+            items.push(T::decode(input)?);
+        }
+        Ok(items)
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode(input: &mut &[u8]) -> Result<Option<T>, String> {
+        match take(input, 1)?[0] {
+            0 => Ok(None),
+            1 => T::decode(input).map(Some),
+            byte => Err(format!("invalid option {}", byte)),
+        }
+    }
+}
+
+impl Encode for Transaction {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.sender.encode(out);
+        self.recipient.encode(out);
+        self.amount.encode(out);
+        self.nonce.encode(out);
+    }
+}
+
+impl Encode for SignerVote {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.candidate.encode(out);
+        self.authorize.encode(out);
+    }
+}
+
+impl Encode for DoubleSignEvidence {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.first.encode(out);
+        self.second.encode(out);
+    }
+}
+
+impl Encode for BlockHeader {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.index.encode(out);
+        self.timestamp.encode(out);
+        self.merkle_root.encode(out);
+        self.proof.encode(out);
+        self.previous_hash.encode(out);
+        self.signer.encode(out);
+        self.signature.encode(out);
+        self.vote.encode(out);
+        self.evidence.encode(out);
+    }
+}
+
+// Whole blocks are never hashed, but their encoding is what block size
+// limits are measured on.
+impl Encode for Block {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.index.encode(out);
+        self.timestamp.encode(out);
+        self.transactions.encode(out);
+        self.merkle_root.encode(out);
+        self.proof.encode(out);
+        self.previous_hash.encode(out);
+        self.signer.encode(out);
+        self.signature.encode(out);
+        self.vote.encode(out);
+        self.evidence.encode(out);
+    }
+}
+
+// The genesis block commits to the genesis configuration through the
+// hash of this encoding. Sizes are encoded as u64.
+impl Encode for GenesisConfig {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.consensus.encode(out);
+        self.timestamp.encode(out);
+        self.proof.encode(out);
+        self.allocations.encode(out);
+        self.pow_hash.encode(out);
+        (self.pow_difficulty as u64).encode(out);
+        self.signers.encode(out);
+        self.stakes.encode(out);
+        (self.max_block_size as u64).encode(out);
+        (self.max_transaction_size as u64).encode(out);
+    }
+}
+
+impl Decode for Transaction {
+    fn decode(input: &mut &[u8]) -> Result<Transaction, String> {
+        Ok(Transaction {
+            sender:    String::decode(input)?,
+            recipient: String::decode(input)?,
+            amount:    i64::decode(input)?,
+            nonce:     u64::decode(input)?,
+        })
+    }
+}
+
+impl Decode for SignerVote {
+    fn decode(input: &mut &[u8]) -> Result<SignerVote, String> {
+        Ok(SignerVote {
+            candidate: String::decode(input)?,
+            authorize: bool::decode(input)?,
+        })
+    }
+}
+
+impl Decode for DoubleSignEvidence {
+    fn decode(input: &mut &[u8]) -> Result<DoubleSignEvidence, String> {
+        Ok(DoubleSignEvidence {
+            first:  BlockHeader::decode(input)?,
+            second: BlockHeader::decode(input)?,
+        })
+    }
+}
+
+impl Decode for BlockHeader {
+    fn decode(input: &mut &[u8]) -> Result<BlockHeader, String> {
+        Ok(BlockHeader {
+            index:         u64::decode(input)?,
+            timestamp:     u64::decode(input)?,
+            merkle_root:   String::decode(input)?,
+            proof:         u64::decode(input)?,
+            previous_hash: String::decode(input)?,
+            signer:        String::decode(input)?,
+            signature:     String::decode(input)?,
+            vote:          Option::decode(input)?,
+            evidence:      Vec::decode(input)?,
+        })
+    }
+}
+
+impl Decode for Block {
+    fn decode(input: &mut &[u8]) -> Result<Block, String> {
+        Ok(Block {
+            index:         u64::decode(input)?,
+            timestamp:     u64::decode(input)?,
+            transactions:  Vec::decode(input)?,
+            merkle_root:   String::decode(input)?,
+            proof:         u64::decode(input)?,
+            previous_hash: String::decode(input)?,
+            signer:        String::decode(input)?,
+            signature:     String::decode(input)?,
+            vote:          Option::decode(input)?,
+            evidence:      Vec::decode(input)?,
+        })
+    }
+}
@@ -0,0 +1,319 @@
+// Gossip.
+// New blocks and transactions spread from node to node: whoever mines or
+// accepts a block, or admits a transaction, announces it to every
+// registered node. Nodes fetch whatever they haven't seen yet from the
+// one which announced it, and if it checks out, announce it onwards.
+// Every node remembers what it has seen, so announcements die out instead
+// of going around in circles. Something only counts as seen once we have
+// it, or know it is no good: until then another node announcing it gets
+// us to fetch it from there.
+//
+// Addresses of other nodes spread the same way, on request: every now
+// and then we shake hands with our nodes, to see how they are doing and
+// whether they are still on our network, and ask them for the peers they
+// know.
+//
+// The daemon never waits on the network for gossip: announcing and
+// fetching happen on a thread of their own, which hands whatever it
+// fetched back to the daemon as a ReplCommand.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Instant;
+
+use reqwest;
+use serde::de::DeserializeOwned;
+use serde_json;
+
+use super::{Block, ReplCommand, SizeLimits, Transaction};
+use sync;
+use handshake::{self, Handshake};
+use remote::{self, PeerError};
+
+// How many block hashes and transaction IDs we remember having seen.
+// When full, the oldest ones are forgotten.
+pub const MAX_SEEN: usize = 10_000;
+
+// An announcement of blocks (by hash) and transactions (by ID).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct Inventory {
+    // Address of the node announcing them, where to fetch them from
+    pub from: String,
+    pub blocks: Vec<String>,
+    pub transactions: Vec<String>,
+}
+
+impl Inventory {
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty() && self.transactions.is_empty()
+    }
+}
+
+// Work for the gossip thread.
+enum Job {
+    Announce { inventory: Inventory, peers: Vec<String> },
+    Fetch { inventory: Inventory },
+    // Blocks after the last one a node has in common with our locator
+    FetchAncestors { from: String, locator: Vec<String> },
+    AskPeers { peers: Vec<String>, handshake: Handshake },
+}
+
+// Peers a node shares, on GET /peers.
+#[derive(Serialize, Deserialize)]
+pub struct Peers {
+    pub peers: Vec<String>,
+}
+
+pub struct Gossip {
+    // Our own address, as other nodes reach it, if we know it. Without
+    // it we announce nothing: nobody could fetch it from us.
+    local: Option<String>,
+    seen: HashSet<String>,
+    // Seen hashes and IDs, oldest first
+    order: VecDeque<String>,
+    // Hashes and IDs being fetched
+    fetching: HashSet<String>,
+    // Nodes we are fetching the ancestors of an orphan block from
+    syncing: HashSet<String>,
+    jobs: Sender<Job>,
+}
+
+impl Gossip {
+    // Starts the gossip thread.
+    // local: Our own address, as other nodes reach it, if we know it
+    // limits: Size limits of our network, to bound what we fetch
+    // daemon: Where fetched blocks and transactions go
+    pub fn start(local: Option<String>, limits: SizeLimits, daemon: Sender<ReplCommand>) -> Gossip {
+        let (jobs, rx) = mpsc::channel();
+        thread::spawn(move || Gossip::run(rx, limits, daemon));
+        Gossip {
+            local: local,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+            fetching: HashSet::new(),
+            syncing: HashSet::new(),
+            jobs: jobs,
+        }
+    }
+
+    // Remembers having seen a block or transaction.
+    // Return: Whether it is new to us
+    fn see(&mut self, id: &str) -> bool {
+        if self.seen.contains(id) {
+            return false;
+        }
+        if self.order.len() >= MAX_SEEN {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.to_owned());
+        self.order.push_back(id.to_owned());
+        true
+    }
+
+    // Announces blocks and transactions to every peer.
+    // except: The node we heard of them from, which has them already
+    pub fn announce(&mut self, peers: &[String], blocks: Vec<String>,
+                    transactions: Vec<String>, except: Option<&str>) {
+        for id in blocks.iter().chain(transactions.iter()) {
+            self.see(id);
+        }
+        let local = match self.local {
+            Some(ref local) => local.clone(),
+            None => return,
+        };
+        let peers: Vec<String> = peers.iter()
+            .filter(|peer| Some(peer.as_ref()) != except && **peer != local && *peer != "local")
+            .cloned()
+            .collect();
+        if peers.is_empty() {
+            return;
+        }
+        let inventory = Inventory {
+            from: local,
+            blocks: blocks,
+            transactions: transactions,
+        };
+        let _ = self.jobs.send(Job::Announce { inventory: inventory, peers: peers });
+    }
+
+    // Handles an announcement, fetching whatever we haven't seen, and
+    // aren't fetching, yet.
+    // Return: What is being fetched
+    pub fn receive(&mut self, inventory: Inventory) -> Inventory {
+        let wanted = Inventory {
+            from: inventory.from.clone(),
+            blocks: inventory.blocks.into_iter().filter(|hash| self.want(hash)).collect(),
+            transactions: inventory.transactions.into_iter().filter(|txid| self.want(txid)).collect(),
+        };
+        if !wanted.is_empty() {
+            let _ = self.jobs.send(Job::Fetch { inventory: wanted.clone() });
+        }
+        wanted
+    }
+
+    // Marks a block or transaction as being fetched, unless we have seen
+    // it, or are fetching it, already.
+    // Return: Whether to fetch it
+    fn want(&mut self, id: &str) -> bool {
+        !self.seen.contains(id) && self.fetching.insert(id.to_owned())
+    }
+
+    // Records how fetching a block or transaction went.
+    // seen: Whether we are done with it, having accepted or rejected it;
+    // if not, whoever announces it next gets asked for it
+    pub fn fetched(&mut self, id: &str, seen: bool) {
+        self.fetching.remove(id);
+        if seen {
+            self.see(id);
+        }
+    }
+
+    // Fetches the blocks leading up to an orphan block from the node
+    // which sent it, unless we are at it already.
+    // locator: Of our chain
+    pub fn fetch_ancestors(&mut self, from: &str, locator: Vec<String>) {
+        if self.syncing.insert(from.to_owned()) {
+            let _ = self.jobs.send(Job::FetchAncestors { from: from.to_owned(), locator: locator });
+        }
+    }
+
+    // Records that fetching ancestors from a node is over.
+    pub fn fetched_ancestors(&mut self, from: &str) {
+        self.syncing.remove(from);
+    }
+
+    // Shakes hands with nodes, and asks them for the peers they know.
+    // handshake: Ours
+    pub fn ask_peers(&self, peers: &[String], handshake: Handshake) {
+        let peers: Vec<String> = peers.iter()
+            .filter(|peer| self.local.as_ref() != Some(*peer) && *peer != "local")
+            .cloned()
+            .collect();
+        if !peers.is_empty() {
+            let _ = self.jobs.send(Job::AskPeers { peers: peers, handshake: handshake });
+        }
+    }
+
+    // The gossip thread.
+    fn run(jobs: Receiver<Job>, limits: SizeLimits, daemon: Sender<ReplCommand>) {
+        let client = remote::client();
+        let max_block = limits.block as u64 * remote::JSON_OVERHEAD;
+        let max_transaction = limits.transaction as u64 * remote::JSON_OVERHEAD;
+        for job in jobs {
+            match job {
+                Job::Announce { inventory, peers } => {
+                    let body = serde_json::to_string(&inventory).unwrap();
+                    for peer in peers {
+                        let _ = match remote::post(&client, &format!("{}/inv", peer), &body) {
+                            Ok(_) => daemon.send(ReplCommand::NodeReached { node: peer, height: None, latency: None }),
+                            Err(reason) => {
+                                println!("Gossip: could not announce to {}: {}", peer, reason);
+                                daemon.send(ReplCommand::NodeFailed { node: peer, error: reason })
+                            },
+                        };
+                    }
+                },
+                Job::Fetch { inventory } => {
+                    let from = inventory.from;
+                    for hash in inventory.blocks {
+                        let block: Block = match Gossip::fetch(&client, format!("{}/block/{}", from, hash), max_block) {
+                            Ok(block) => block,
+                            Err(error) => {
+                                let _ = daemon.send(ReplCommand::NodeFailed { node: from.clone(), error: error });
+                                let _ = daemon.send(ReplCommand::GossipFailed { id: hash });
+                                continue;
+                            },
+                        };
+                        let _ = daemon.send(ReplCommand::GossipBlock { id: hash, block: block, from: from.clone() });
+                    }
+                    for txid in inventory.transactions {
+                        let transaction: Transaction =
+                            match Gossip::fetch(&client, format!("{}/transaction/{}", from, txid), max_transaction) {
+                                Ok(transaction) => transaction,
+                                Err(error) => {
+                                    let _ = daemon.send(ReplCommand::NodeFailed { node: from.clone(), error: error });
+                                    let _ = daemon.send(ReplCommand::GossipFailed { id: txid });
+                                    continue;
+                                },
+                            };
+                        let _ = daemon.send(ReplCommand::GossipTransaction {
+                            id: txid,
+                            transaction: transaction,
+                            from: from.clone(),
+                        });
+                    }
+                },
+                Job::FetchAncestors { from, locator } => {
+                    let blocks = Gossip::fetch_chain(&client, &from, &locator, limits.block);
+                    let blocks = match blocks {
+                        Ok(blocks) => blocks,
+                        Err(error) => {
+                            println!("Gossip: could not fetch blocks from {}: {}", from, error);
+                            let _ = daemon.send(ReplCommand::NodeFailed { node: from.clone(), error: error });
+                            vec![]
+                        },
+                    };
+                    let _ = daemon.send(ReplCommand::GossipAncestors { blocks: blocks, from: from });
+                },
+                Job::AskPeers { peers, handshake } => {
+                    for peer in peers {
+                        // See how it is doing first
+                        let start = Instant::now();
+                        let theirs = match handshake::exchange(&client, &peer, &handshake) {
+                            Ok(theirs) => theirs,
+                            Err(error) => {
+                                println!("Gossip: could not shake hands with {}: {}", peer, error);
+                                let _ = daemon.send(ReplCommand::NodeFailed { node: peer, error: error });
+                                continue;
+                            },
+                        };
+                        let elapsed = start.elapsed();
+                        let compatible = theirs.check(&handshake).is_ok();
+                        let _ = daemon.send(ReplCommand::NodeShookHands {
+                            node: peer.clone(),
+                            handshake: theirs,
+                            latency: elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000,
+                        });
+                        // Nodes on other networks know peers on other networks
+                        if !compatible {
+                            continue;
+                        }
+                        if let Ok(known) = Gossip::fetch::<Peers>(&client, format!("{}/peers", peer),
+                                                                     remote::MAX_RESPONSE_SIZE) {
+                            let _ = daemon.send(ReplCommand::GossipPeers { peers: known.peers, from: peer });
+                        }
+                    }
+                },
+            }
+        }
+    }
+
+    // Downloads the blocks a node has after the last one it has in
+    // common with our locator, headers first.
+    // max_block_size: Largest block the network takes, encoded
+    fn fetch_chain(client: &reqwest::Client, node: &str, locator: &[String],
+                   max_block_size: usize) -> Result<Vec<Block>, PeerError> {
+        // Further behind than that, resolving catches up
+        let headers = sync::fetch_headers(client, node, locator, sync::MAX_HEADERS)?;
+        let hashes: Vec<String> = headers.iter().map(super::Blockchain::hash_header).collect();
+        let known = hashes.iter().cloned().collect();
+        let ranges = sync::ranges(&hashes, &[(node.to_owned(), known)]);
+        let mut failures = BTreeMap::new();
+        sync::fetch_blocks(client, &ranges, max_block_size, &mut failures)
+            .map(|blocks| blocks.into_iter().map(|(_, block)| block).collect())
+            .map_err(|reason| failures.remove(node).unwrap_or(PeerError::Misbehaved(reason)))
+    }
+
+    // Fetches a JSON document from a peer.
+    // limit: Most bytes we read
+    fn fetch<T: DeserializeOwned>(client: &reqwest::Client, uri: String, limit: u64) -> Result<T, PeerError> {
+        remote::get(client, &uri, limit).map_err(|reason| {
+            println!("Gossip: could not fetch {}: {}", uri, reason);
+            reason
+        })
+    }
+}
@@ -0,0 +1,85 @@
+// Handshake.
+// Before syncing, nodes tell each other who they are: the network they
+// are on (its name and the hash of its genesis block), the protocol
+// version they speak, the software they run, what they serve, and how long
+// their chain is. Over HTTP, a node posts its handshake to POST /handshake
+// and gets the other's back; over p2p, it is the version message.
+//
+// We only sync with nodes on our network speaking our protocol version.
+// Software versions may differ; they are only shown.
+
+use reqwest;
+use serde_json;
+
+use encoding::{Decode, Encode};
+use remote::{self, PeerError};
+
+// Version of the protocol nodes speak, over HTTP and p2p.
+pub const PROTOCOL_VERSION: u64 = 2;
+
+// Services a node may offer.
+// Serves whole blocks, not only headers
+pub const SERVICE_BLOCKS: &'static str = "blocks";
+// Takes p2p connections
+pub const SERVICE_P2P: &'static str = "p2p";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Handshake {
+    pub network: String,
+    // Hash of the genesis block of the network
+    pub genesis: String,
+    pub protocol_version: u64,
+    pub software_version: String,
+    pub services: Vec<String>,
+    // Length of its chain
+    pub height: u64,
+}
+
+impl Handshake {
+    // Checks whether we can sync with the node which sent this.
+    // ours: Our own handshake
+    pub fn check(&self, ours: &Handshake) -> Result<(), String> {
+        if self.network != ours.network {
+            return Err(format!("on network {}, not {}", self.network, ours.network));
+        }
+        if self.genesis != ours.genesis {
+            return Err(format!("on genesis {}, not {}", self.genesis, ours.genesis));
+        }
+        if self.protocol_version != ours.protocol_version {
+            return Err(format!("speaks protocol version {}, not {}",
+                               self.protocol_version, ours.protocol_version));
+        }
+        Ok(())
+    }
+}
+
+impl Encode for Handshake {
+    fn encode(&self, out: &mut Vec<u8>) {
+        self.network.encode(out);
+        self.genesis.encode(out);
+        self.protocol_version.encode(out);
+        self.software_version.encode(out);
+        self.services.encode(out);
+        self.height.encode(out);
+    }
+}
+
+impl Decode for Handshake {
+    fn decode(input: &mut &[u8]) -> Result<Handshake, String> {
+        Ok(Handshake {
+            network:          String::decode(input)?,
+            genesis:          String::decode(input)?,
+            protocol_version: u64::decode(input)?,
+            software_version: String::decode(input)?,
+            services:         Vec::decode(input)?,
+            height:           u64::decode(input)?,
+        })
+    }
+}
+
+// Shakes hands with a node over HTTP.
+// Return: Its handshake
+pub fn exchange(client: &reqwest::Client, node: &str, ours: &Handshake) -> Result<Handshake, PeerError> {
+    let body = serde_json::to_string(ours).unwrap();
+    remote::post_json(client, &format!("{}/handshake", node), &body, remote::MAX_RESPONSE_SIZE)
+}
@@ -0,0 +1,130 @@
+// Signing keys.
+// Unlike wallet addresses, which are just a dressed up identifier, these
+// are real Ed25519 key pairs, used by nodes to sign what they produce.
+// Public keys and signatures travel around as hex strings.
+
+use std::fs::File;
+use std::io::{Write, Read};
+
+use crypto::ed25519;
+use serde_json;
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SigningKey {
+    // 32-byte seed the key pair is derived from, as hex.
+    seed: String,
+}
+
+impl SigningKey {
+    // Generates a new key from a random seed.
+    // The seed is made of two v4 UUIDs, so we don't need yet another
+    // crate only for random numbers.
+    pub fn generate() -> SigningKey {
+        let mut seed = vec![];
+        seed.extend_from_slice(Uuid::new_v4().as_bytes());
+        seed.extend_from_slice(Uuid::new_v4().as_bytes());
+        SigningKey { seed: to_hex(&seed) }
+    }
+
+    // Load key from file. If there is no key file, a new key is generated
+    // and saved to it.
+    pub fn from_file(filename: String) -> SigningKey {
+        let f = File::open(filename.clone());
+        match f {
+            Err(_) => {
+                println!("Cannot read key file. Generating a new key on {}.", filename);
+                let key = SigningKey::generate();
+                key.to_file(filename);
+                key
+            },
+            Ok(mut f) => {
+                let mut text = String::new();
+                let _ = f.read_to_string(&mut text);
+                match serde_json::from_str::<SigningKey>(&text) {
+                    Ok(ref key) if key.seed_bytes().is_some() => key.clone(),
+                    _ => panic!("Key file {} is corrupt! Refusing to overwrite it.", filename),
+                }
+            }
+        }
+    }
+
+    // Save key to file
+    pub fn to_file(&self, filename: String) {
+        let serialized = serde_json::to_string_pretty(self)
+            .expect("Unable to serialize key!");
+        let f = File::create(filename);
+        match f {
+            Err(_) => println!("Unable to create file!"),
+            Ok(mut f) => f.write_all(serialized.as_bytes())
+                .expect("Unable to write key to file!"),
+        };
+    }
+
+    fn seed_bytes(&self) -> Option<Vec<u8>> {
+        match from_hex(&self.seed) {
+            Some(ref seed) if seed.len() == 32 => Some(seed.clone()),
+            _ => None,
+        }
+    }
+
+    fn keypair(&self) -> ([u8; 64], [u8; 32]) {
+        ed25519::keypair(&self.seed_bytes().expect("Invalid key seed!"))
+    }
+
+    // Public key, as hex
+    pub fn public_key(&self) -> String {
+        let (_, public) = self.keypair();
+        to_hex(&public)
+    }
+
+    // Signs a message.
+    // Return: signature, as hex
+    pub fn sign(&self, message: &[u8]) -> String {
+        let (secret, _) = self.keypair();
+        to_hex(&ed25519::signature(message, &secret))
+    }
+}
+
+// Verifies the signature of a message.
+// public_key: Signer's public key, as hex
+// signature: Signature, as hex
+// Return: Whether signature is correct
+pub fn verify(public_key: &str, message: &[u8], signature: &str) -> bool {
+    match (from_hex(public_key), from_hex(signature)) {
+        (Some(public), Some(signature)) => {
+            public.len() == 32 && signature.len() == 64
+                && ed25519::verify(message, &public, &signature)
+        },
+        _ => false,
+    }
+}
+
+// Checks whether a string looks like a public key.
+pub fn valid_public_key(public_key: &str) -> bool {
+    match from_hex(public_key) {
+        Some(public) => public.len() == 32,
+        None => false,
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    let hex: Vec<String> = bytes.iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    hex.join("")
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = vec![];
+    for i in 0..hex.len() / 2 {
+        match u8::from_str_radix(&hex[i*2..(i*2)+2], 16) {
+            Ok(b) => bytes.push(b),
+            Err(_) => return None,
+        }
+    }
+    Some(bytes)
+}
@@ -0,0 +1,341 @@
+// Light client.
+// A light client doesn't download whole chains. It only keeps the chain
+// of block headers, checking their links and proof of work, and checks
+// that a payment was confirmed by asking a full node for a Merkle proof
+// of the transaction, which it verifies against its own headers.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{Write, Read};
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+use serde::de::DeserializeOwned;
+use serde_json;
+use url::Url;
+
+use super::{BlockHeader, Blockchain, MerkleProof};
+use config::GenesisConfig;
+use consensus::{pow, ProofOfWork, PowHash};
+use merkle;
+use network::Network;
+use remote::{self, PeerError};
+use sync;
+
+// Most headers we take from a node at once, along with a chain or a proof.
+const MAX_HEADERS: u64 = 100_000;
+
+static LIGHT_HELP_PROMPT: &'static str =
+    "help                 -- Shows this prompt.\n\
+     node reg ADDR        -- Registers an address of format https://127.0.0.1:3000 as a full node.\n\
+     sync                 -- Downloads headers from registered nodes and keeps the best valid chain.\n\
+     status               -- Shows how many headers we have, and the hash of the last one.\n\
+     verify TXID          -- Checks whether the transaction TXID was confirmed, and how deep.\n\
+     quit/exit            -- Closes program, saving headers to headers.json.";
+
+#[derive(Serialize, Deserialize)]
+struct LightClient {
+    headers: Vec<BlockHeader>,
+    nodes: HashSet<String>,
+    #[serde(skip, default = "LightClient::default_engine")]
+    engine: ProofOfWork,
+    // Hash of the genesis block of our network
+    #[serde(skip, default = "LightClient::default_genesis")]
+    genesis: String,
+}
+
+impl LightClient {
+    fn new() -> LightClient {
+        LightClient {
+            headers: vec![],
+            nodes: HashSet::new(),
+            engine: LightClient::default_engine(),
+            genesis: LightClient::default_genesis(),
+        }
+    }
+
+    fn default_genesis() -> String {
+        Blockchain::hash(&Blockchain::genesis_block(&GenesisConfig::default()))
+    }
+
+    fn default_engine() -> ProofOfWork {
+        ProofOfWork::new(PowHash::Sha256, ::consensus::pow::DEFAULT_DIFFICULTY)
+    }
+
+    // Load light client state from file
+    fn from_file(filename: String) -> LightClient {
+        let f = File::open(filename);
+        match f {
+            Err(_) => {
+                println!("Cannot read headers file. Starting with no headers.");
+                LightClient::new()
+            },
+            Ok(mut f) => {
+                let mut text = String::new();
+                let _ = f.read_to_string(&mut text);
+                match serde_json::from_str(&text) {
+                    Ok(client) => client,
+                    Err(_) => {
+                        println!("Cannot parse headers file. Starting with no headers.");
+                        LightClient::new()
+                    }
+                }
+            }
+        }
+    }
+
+    // Saves light client state to file
+    fn to_file(&self, filename: String) {
+        let serialized = serde_json::to_string_pretty(&self)
+            .expect("Unable to serialize headers!");
+        let f = File::create(filename);
+        match f {
+            Err(_) => println!("Unable to create file!"),
+            Ok(mut f) => f.write_all(serialized.as_bytes())
+                .expect("Unable to write headers to file!"),
+        };
+    }
+
+    // Determines if a header chain is valid: every header must point to
+    // the hash of the one before it, with a valid proof of work over
+    // the header itself.
+    fn valid_headers(&self, headers: &[BlockHeader]) -> bool {
+        for i in 1..headers.len() {
+            if headers[i].previous_hash != Blockchain::hash_header(&headers[i - 1]) {
+                return false;
+            }
+            if !self.engine.valid_proof(&pow::work_hash(&headers[i]), headers[i].proof) {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Adopts a header chain if it is valid, longer than ours, and starts
+    // at the genesis of our network.
+    // Return: Whether our headers were replaced
+    fn offer_headers(&mut self, headers: Vec<BlockHeader>) -> bool {
+        if headers.len() <= self.headers.len() || !self.valid_headers(&headers) {
+            return false;
+        }
+        if Blockchain::hash_header(&headers[0]) != self.genesis {
+            return false;
+        }
+        self.headers = headers;
+        true
+    }
+
+    // Fetches a JSON document from a full node.
+    fn fetch<T: DeserializeOwned>(uri: String) -> Result<T, PeerError> {
+        remote::get(&remote::client(), &uri, MAX_HEADERS * sync::MAX_HEADER_SIZE)
+    }
+
+    // Downloads headers from every registered node.
+    // Return: Whether our headers were replaced
+    fn sync(&mut self) -> bool {
+        let mut changed = false;
+        for node in self.nodes.clone() {
+            match LightClient::fetch(format!("{}/headers", node)) {
+                Ok(headers) => changed |= self.offer_headers(headers),
+                Err(e) => println!("Could not fetch headers from {}: {}", node, e),
+            }
+        }
+        changed
+    }
+
+    // Checks a payment proof against our headers.
+    // Return: Number of confirmations of the transaction
+    fn check_proof(&self, proof: &MerkleProof) -> Result<u64, String> {
+        let header = match self.headers.iter().find(|header| header.index == proof.block_index) {
+            Some(header) => header,
+            None => return Err(format!("We don't have a header for block {}.", proof.block_index)),
+        };
+        if !merkle::verify(&proof.txid, proof.position, &proof.branch, &header.merkle_root) {
+            return Err("Merkle proof does not match our header.".to_owned());
+        }
+        let tip = self.headers.last().unwrap().index;
+        Ok(tip - header.index + 1)
+    }
+
+    // Asks registered nodes for a Merkle proof of a transaction, and
+    // checks it.
+    fn verify(&mut self, txid: &str) -> Result<u64, String> {
+        let mut last_error = "There are no registered nodes.".to_owned();
+        for node in self.nodes.clone() {
+            let proof: MerkleProof = match LightClient::fetch(format!("{}/proof/{}", node, txid)) {
+                Ok(proof) => proof,
+                Err(PeerError::Malformed(_)) => {
+                    last_error = format!("Node {} sent a malformed proof.", node);
+                    continue;
+                },
+                Err(e) => {
+                    last_error = format!("Node {}: {}", node, e);
+                    continue;
+                },
+            };
+            if proof.txid != txid {
+                last_error = format!("Node {} sent a proof for another transaction.", node);
+                continue;
+            }
+            // The proof comes with the node's headers, which may be newer
+            self.offer_headers(proof.headers.clone());
+            match self.check_proof(&proof) {
+                Ok(confirmations) => return Ok(confirmations),
+                Err(e) => last_error = format!("Node {}: {}", node, e),
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// Runs the light client REPL.
+pub fn run(network: &Network) {
+    if network.consensus != "pow" {
+        println!("The light client only follows proof of work chains.");
+        return;
+    }
+    let mut client = LightClient::from_file("headers.json".to_owned());
+    client.engine = match PowHash::from_name(network.genesis.pow_hash.as_ref()) {
+        Some(hash) => ProofOfWork::new(hash, network.genesis.pow_difficulty),
+        None => {
+            println!("Unknown proof of work hash \"{}\".", network.genesis.pow_hash);
+            return;
+        },
+    };
+    client.genesis = Blockchain::hash(&Blockchain::genesis_block(&network.genesis));
+    let foreign = match client.headers.first() {
+        Some(genesis) => Blockchain::hash_header(genesis) != client.genesis,
+        None => false,
+    };
+    if foreign {
+        println!("Saved headers belong to another network. Starting with no headers.");
+        client.headers.clear();
+    }
+
+    let mut rl = Editor::<()>::new();
+    println!("Running as a light client. For a list of commands, type `help`.");
+    loop {
+        let readline = rl.readline("LIGHT > ");
+        match readline {
+            Ok(line) => {
+                rl.add_history_entry(&line);
+                let atoms = line.split_whitespace()
+                    .collect::<Vec<&str>>();
+                if atoms.len() == 0 {
+                    continue;
+                }
+                let command = String::from(atoms[0]).to_lowercase();
+                let args = &atoms[1..];
+
+                match command.as_ref() {
+                    "quit" | "exit" => break,
+                    "node" => {
+                        if args.len() != 2 || args[0].to_lowercase() != "reg" {
+                            println!("Please use `node reg ADDR`.");
+                        } else {
+                            match Url::parse(args[1]) {
+                                Ok(_) => {
+                                    client.nodes.insert(args[1].to_owned());
+                                    println!("Node registered successfully.");
+                                },
+                                Err(_) => println!("Please provide a valid URL."),
+                            }
+                        }
+                    },
+                    "sync" => {
+                        println!("Syncing headers...");
+                        match client.sync() {
+                            true  => println!("Headers updated."),
+                            false => println!("Headers up-to-date."),
+                        }
+                    },
+                    "status" => {
+                        match client.headers.last() {
+                            Some(header) => println!("{} headers. Last one is block {}, hash {}.",
+                                                     client.headers.len(), header.index,
+                                                     Blockchain::hash_header(header)),
+                            None => println!("No headers yet. Run `sync`."),
+                        }
+                    },
+                    "verify" => {
+                        if args.len() != 1 {
+                            println!("Please specify a transaction ID.");
+                        } else {
+                            match client.verify(args[0]) {
+                                Ok(confirmations) => println!("Transaction confirmed, {} confirmation(s).",
+                                                              confirmations),
+                                Err(e) => println!("Transaction not verified: {}", e),
+                            }
+                        }
+                    },
+                    "help" => println!("Useful commands:\n{}", LIGHT_HELP_PROMPT),
+                    _ => println!("Not Implemented"),
+                }
+            },
+            Err(ReadlineError::Interrupted) => {
+                println!("C-c");
+                break
+            },
+            Err(ReadlineError::Eof) => {
+                println!("C-d");
+                break
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break
+            }
+        }
+    }
+
+    println!("Saving headers...");
+    client.to_file("headers.json".to_owned());
+}
+
+// ------------------------
+
+// Tests
+#[test]
+fn light_client_proofs() {
+    // A full node with a few transactions...
+    let node = Blockchain::new_identifier();
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert(node.clone(), 15);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    for amount in 1..6 {
+        blockchain.new_transaction(node.clone(), Blockchain::new_identifier(), amount).unwrap();
+    }
+    blockchain.mine_block(node.clone()).unwrap();
+    blockchain.mine_block(node.clone()).unwrap();
+    let txid = blockchain.chain[1].transactions[4].id();
+
+    // ...and a light client following its headers
+    let mut client = LightClient::new();
+    client.genesis = Blockchain::hash(&blockchain.chain[0]);
+    assert!(client.offer_headers(blockchain.headers()));
+    assert!(!client.offer_headers(blockchain.headers()));
+
+    let proof = blockchain.merkle_proof(&txid).expect("Transaction not found");
+    assert_eq!(proof.block_index, 2);
+    assert_eq!(client.check_proof(&proof), Ok(2));
+
+    // A proof for a transaction we don't have fails
+    let mut forged = proof.clone();
+    forged.txid = blockchain.chain[2].transactions[0].id();
+    assert!(client.check_proof(&forged).is_err());
+    assert!(blockchain.merkle_proof("nonexistent").is_none());
+
+    // Headers with a broken proof of work are refused
+    let mut headers = blockchain.headers();
+    headers[2].proof += 1;
+    let mut other = LightClient::new();
+    other.genesis = client.genesis.clone();
+    assert!(!other.offer_headers(headers));
+
+    // So are headers committing to other transactions, relinked and
+    // reusing the original proofs
+    let mut headers = blockchain.headers();
+    headers[1].merkle_root = Blockchain::merkle_root(&blockchain.chain[2].transactions);
+    headers[2].previous_hash = Blockchain::hash_header(&headers[1]);
+    assert!(!other.offer_headers(headers));
+    assert!(other.offer_headers(blockchain.headers()));
+}
@@ -1,9 +1,10 @@
+#![cfg_attr(feature = "bench", feature(test))]
+
 // Blockchain crates and uses
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
 extern crate serde_json;
-extern crate time;
 extern crate crypto;
 extern crate uuid;
 extern crate rust_base58;
@@ -13,7 +14,7 @@ use crypto::sha2::Sha256;
 use std::thread;
 use std::sync::mpsc;
 use uuid::Uuid;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use std::fs::File;
 use rust_base58::{ToBase58, FromBase58};
 
@@ -25,6 +26,9 @@ use rustyline::error::ReadlineError;
 use rustyline::Editor;
 use std::collections::HashSet;
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+use std::fmt;
 
 
 // HTTP server crates and uses
@@ -33,12 +37,51 @@ extern crate url;
 extern crate reqwest;
 
 
-use tiny_http::Method;
 use std::env;
+use std::net::IpAddr;
 use url::Url;
 use std::io::{Write, Read};
 
 
+// Node modules
+mod api;
+mod checkpoints;
+mod clock;
+mod config;
+mod consensus;
+mod encoding;
+mod gossip;
+mod handshake;
+mod keys;
+mod light;
+mod merkle;
+mod network;
+mod p2p;
+mod parallel;
+mod peers;
+mod remote;
+mod sync;
+mod tree;
+
+use clock::{Clock, SystemClock};
+use config::{GenesisConfig, NodeConfig};
+use consensus::ConsensusEngine;
+use std::sync::Arc;
+use tree::BlockTree;
+use api::ApiError;
+use gossip::{Gossip, Inventory};
+use handshake::Handshake;
+use p2p::{Message, P2p};
+use peers::{AddressBook, BanPolicy, Misbehaviour};
+use remote::PeerError;
+
+// Benchmarks
+#[cfg(all(test, feature = "bench"))]
+extern crate test;
+#[cfg(all(test, feature = "bench"))]
+mod benches;
+
+
 // TODO
 // node new            => wallet new (???? or maybe even remove)
 // node del (also change nodes to hashset. nodes will be just addresses)
@@ -63,11 +106,21 @@ static HELP_PROMPT: &'static str =
      alias save           -- Saves aliases to aliases.json.\n\
      alias save FILE      -- Saves aliases to FILE.\n\
      node reg ADDR        -- Registers an address of format https://127.0.0.1:3000 as a node.\n\
-     node del ADDR        -- [TO-DO] Deletes an address from nodes.\n\
-     node show            -- [TO-DO] Shows registered nodes.\n\
+     node del ADDR        -- Deletes an address from nodes, until registered again.\n\
+     node show            -- Shows registered nodes, and how each one is doing.\n\
+     node connect ADDR    -- Opens a P2P connection to an address of format 127.0.0.1:4000.\n\
+     node bans            -- Shows banned nodes, until when and why.\n\
+     node unban ADDR      -- Lifts the ban on an address.\n\
      send VAL DEST        -- [TO-DO] Sends a value VAL from a local identifier to DEST.\n\
      send VAL SRC DEST    -- [TO-DO] Sends a value VAL from SRC to DEST.\n\
      resolve              -- Scans through all registered nodes and resolves chain conflicts.\n\
+     tips                 -- Shows the tip of our chain and of every side chain we know of.\n\
+     signer show          -- Shows authorized block signers and this node's signer key.\n\
+     signer add KEY       -- Votes for authorizing KEY as a block signer on every block we sign.\n\
+     signer remove KEY    -- Votes for deauthorizing KEY as a block signer on every block we sign.\n\
+     signer discard KEY   -- Stops voting on KEY.\n\
+     stake VAL            -- Locks VAL coins of this node's signer key as stake.\n\
+     stake show           -- Shows the stake of each validator.\n\
      wallet new           -- [TO-DO] Creates a new wallet.\n\
      wallet load FILE     -- [TO-DO] Loads wallet saved on FILE.\n\
      wallet save          -- Saves loaded wallet to wallet.json.\n\
@@ -81,6 +134,18 @@ static HELP_PROMPT: &'static str =
 
 // ----
 
+// Block timestamps must be greater than the median timestamp
+// of this many blocks before them.
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// How far ahead of our clock, in seconds, a block may be timestamped.
+const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+
+// Coins a block producer is rewarded with.
+const MINING_REWARD: i64 = 1;
+
+// ----
+
 // Each node is indexed in the blockchain and represents a registered
 // node on the network.
 // This structure will contain the node data required for transactions.
@@ -97,6 +162,25 @@ struct Transaction {
     sender: String,
     recipient: String,
     amount: i64,
+    // Tells apart transactions which move the same amount between the
+    // same addresses, since the same transaction can't go in twice.
+    #[serde(default)]
+    nonce: u64,
+}
+
+impl Transaction {
+    // Draws a random nonce for a new transaction.
+    fn new_nonce() -> u64 {
+        Uuid::new_v4().as_bytes()[..8].iter()
+            .fold(0, |nonce, &byte| (nonce << 8) | byte as u64)
+    }
+
+    // Transaction ID: SHA-256 hash of the transaction's canonical encoding.
+    fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.input(&encoding::to_bytes(self));
+        hasher.result_str()
+    }
 }
 
 // ----
@@ -107,8 +191,298 @@ struct Block {
     index: u64,
     timestamp: u64,
     transactions: Vec<Transaction>,
+    // Root of the Merkle tree over the IDs of transactions. Chains saved
+    // before blocks had one get it computed on load.
+    #[serde(default)]
+    merkle_root: String,
+    proof: u64,
+    previous_hash: String,
+    // Public key of the block producer and its signature over the
+    // block, for consensus engines which sign blocks.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signer: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signature: String,
+    // Signer vote cast by the block producer, for proof of authority.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vote: Option<SignerVote>,
+    // Proof that some validators signed two blocks for the same slot,
+    // for proof of stake.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    evidence: Vec<DoubleSignEvidence>,
+}
+
+// Header of a block: everything but its transactions, which the header
+// commits to through the Merkle root. A block's identity is the hash of
+// its header.
+#[derive(Serialize, Deserialize, Clone, Hash)]
+struct BlockHeader {
+    index: u64,
+    timestamp: u64,
+    merkle_root: String,
     proof: u64,
     previous_hash: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signer: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    signature: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vote: Option<SignerVote>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    evidence: Vec<DoubleSignEvidence>,
+}
+
+impl Block {
+    // Gets the header of this block.
+    fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index:         self.index,
+            timestamp:     self.timestamp,
+            merkle_root:   self.merkle_root.clone(),
+            proof:         self.proof,
+            previous_hash: self.previous_hash.clone(),
+            signer:        self.signer.clone(),
+            signature:     self.signature.clone(),
+            vote:          self.vote.clone(),
+            evidence:      self.evidence.clone(),
+        }
+    }
+}
+
+// A vote for authorizing (or deauthorizing) a block signer.
+#[derive(Serialize, Deserialize, Clone, Hash)]
+struct SignerVote {
+    candidate: String,
+    authorize: bool,
+}
+
+// Proof that a transaction is part of a block, for light clients.
+#[derive(Serialize, Deserialize, Clone)]
+struct MerkleProof {
+    txid: String,
+    // Index of the block holding the transaction, and position of the
+    // transaction inside of it
+    block_index: u64,
+    position: usize,
+    // Merkle branch from the transaction up to the block's Merkle root
+    branch: Vec<String>,
+    // Header chain, from genesis up to the tip
+    headers: Vec<BlockHeader>,
+}
+
+// Headers of two different blocks for the same slot, signed by the same
+// producer.
+#[derive(Serialize, Deserialize, Clone, Hash)]
+struct DoubleSignEvidence {
+    first: BlockHeader,
+    second: BlockHeader,
+}
+
+// ----
+
+// Something noteworthy that happened to the chain.
+#[derive(Clone, Debug, PartialEq)]
+enum ChainEvent {
+    // Our chain was replaced by another one, diverging from ours after
+    // block fork_index. depth blocks of ours were disconnected.
+    Reorg { fork_index: u64, depth: usize, restored: usize, dropped: usize },
+}
+
+// ----
+
+// Consensus rules a block may break.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockRule {
+    Genesis,
+    Checkpoint,
+    Index,
+    PreviousHash,
+    Timestamp,
+    Consensus,
+    MerkleRoot,
+    Size,
+    TransactionSize,
+    Reward,
+    Amount,
+    Overspend,
+    Duplicate,
+}
+
+impl BlockRule {
+    fn description(&self) -> &'static str {
+        match *self {
+            BlockRule::Genesis         => "chain does not start at the genesis of our network",
+            BlockRule::Checkpoint      => "block differs from a checkpoint",
+            BlockRule::Index           => "index does not follow the previous block",
+            BlockRule::PreviousHash    => "previous hash does not match the previous block",
+            BlockRule::Timestamp       => "timestamp is too old, or too far in the future",
+            BlockRule::Consensus       => "consensus data (proof or signature) is invalid",
+            BlockRule::MerkleRoot      => "Merkle root does not match the transactions",
+            BlockRule::Size            => "block is too large",
+            BlockRule::TransactionSize => "a transaction is too large",
+            BlockRule::Reward          => "block needs exactly one reward, of the right amount",
+            BlockRule::Amount          => "transaction amount is not positive",
+            BlockRule::Overspend       => "sender cannot afford a transaction",
+            BlockRule::Duplicate       => "transaction is already on the chain, or twice in the block",
+        }
+    }
+
+    // Whether the rule is about the transactions of a block, which its
+    // hash only covers through the Merkle root: whoever sent the block
+    // may have tampered with them, and someone else may have it right.
+    fn body(&self) -> bool {
+        match *self {
+            BlockRule::MerkleRoot | BlockRule::Size | BlockRule::TransactionSize | BlockRule::Reward
+                | BlockRule::Amount | BlockRule::Overspend | BlockRule::Duplicate => true,
+            _ => false,
+        }
+    }
+}
+
+// Why a chain is invalid: the first block breaking a rule, and the rule.
+#[derive(Clone, Debug, PartialEq)]
+struct InvalidBlock {
+    // Position of the block on the chain, starting at 1 like indexes do
+    index: u64,
+    rule: BlockRule,
+}
+
+impl fmt::Display for InvalidBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "block {}: {}", self.index, self.rule.description())
+    }
+}
+
+// What validating a block needs to know about the chain before it.
+#[derive(Clone, Default)]
+struct ChainState {
+    balances: HashMap<String, i64>,
+    txids: HashSet<String>,
+}
+
+impl ChainState {
+    fn new() -> ChainState {
+        ChainState {
+            balances: HashMap::new(),
+            txids: HashSet::new(),
+        }
+    }
+
+    // State after a chain which is known to be valid.
+    fn from_chain(chain: &[Block]) -> ChainState {
+        let mut state = ChainState::new();
+        for transaction in chain.iter().flat_map(|block| block.transactions.iter()) {
+            let _ = state.apply_transaction(transaction);
+        }
+        state
+    }
+
+    // Applies a transaction if it may come next.
+    // Rewards (from "0") are not checked here, only recorded.
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), BlockRule> {
+        let changes = {
+            let mut delta = StateDelta::new(self);
+            delta.apply_transaction(transaction)?;
+            delta.changes
+        };
+        self.balances.extend(changes.balances);
+        self.txids.extend(changes.txids);
+        Ok(())
+    }
+
+    // Takes back a transaction which was applied last.
+    fn undo_transaction(&mut self, transaction: &Transaction) {
+        if transaction.sender != "0" {
+            *self.balances.entry(transaction.sender.clone()).or_insert(0) += transaction.amount;
+        }
+        *self.balances.entry(transaction.recipient.clone()).or_insert(0) -= transaction.amount;
+        self.txids.remove(&transaction.id());
+    }
+}
+
+// Transactions applied on top of some state, without touching it, so
+// that a block can be checked against our state without copying it.
+struct StateDelta<'a> {
+    base: &'a ChainState,
+    // Balances which changed, and transactions applied
+    changes: ChainState,
+}
+
+impl<'a> StateDelta<'a> {
+    fn new(base: &'a ChainState) -> StateDelta<'a> {
+        StateDelta {
+            base: base,
+            changes: ChainState::new(),
+        }
+    }
+
+    fn balance(&self, address: &str) -> i64 {
+        self.changes.balances.get(address)
+            .or_else(|| self.base.balances.get(address))
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    // Applies a transaction if it may come next, see
+    // ChainState::apply_transaction.
+    fn apply_transaction(&mut self, transaction: &Transaction) -> Result<(), BlockRule> {
+        let id = transaction.id();
+        if self.base.txids.contains(&id) || self.changes.txids.contains(&id) {
+            return Err(BlockRule::Duplicate);
+        }
+        if transaction.sender != "0" {
+            if transaction.amount <= 0 {
+                return Err(BlockRule::Amount);
+            }
+            let funds = self.balance(&transaction.sender);
+            if funds < transaction.amount {
+                return Err(BlockRule::Overspend);
+            }
+            self.changes.balances.insert(transaction.sender.clone(), funds - transaction.amount);
+        }
+        let funds = self.balance(&transaction.recipient);
+        self.changes.balances.insert(transaction.recipient.clone(), funds + transaction.amount);
+        self.changes.txids.insert(id);
+        Ok(())
+    }
+}
+
+// Size limits of a network, in bytes of canonical encoding.
+#[derive(Clone, Copy)]
+struct SizeLimits {
+    block: usize,
+    transaction: usize,
+}
+
+impl SizeLimits {
+    fn from_genesis(genesis: &GenesisConfig) -> SizeLimits {
+        SizeLimits {
+            block: genesis.max_block_size,
+            transaction: genesis.max_transaction_size,
+        }
+    }
+}
+
+impl Default for SizeLimits {
+    fn default() -> SizeLimits {
+        SizeLimits::from_genesis(&GenesisConfig::default())
+    }
+}
+
+// ----
+
+// What became of a block handed to the node.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BlockStatus {
+    // It is on our chain now
+    Connected,
+    // It is valid, but on a branch with less work than ours
+    SideChain,
+    // We don't know its parent yet
+    Orphan,
+    // We already had it
+    Duplicate,
+    Invalid(BlockRule),
 }
 
 // ----
@@ -118,37 +492,137 @@ struct Block {
 struct Blockchain {
     chain: Vec<Block>,
     current_transactions: Vec<Transaction>,
-    nodes: HashSet<String>,
+    // Double signing evidence waiting to go into the next mined block.
+    #[serde(default)]
+    pending_evidence: Vec<DoubleSignEvidence>,
+    // Consensus engine in use. It is not part of the saved chain; the
+    // node sets it from its configuration after loading.
+    #[serde(skip, default = "consensus::default_engine")]
+    engine: Arc<dyn ConsensusEngine>,
+    // Clock used for timestamping and validating blocks.
+    #[serde(skip, default = "Blockchain::system_clock")]
+    clock: Arc<dyn Clock>,
+    // Events not yet handled by the node.
+    #[serde(skip)]
+    events: Vec<ChainEvent>,
+    // Every block we know about, including side chains and orphans.
+    // It is rebuilt from our chain after loading.
+    #[serde(skip)]
+    tree: BlockTree,
+    // State after our chain. It is rebuilt along with the tree.
+    #[serde(skip)]
+    state: ChainState,
+    // What the transactions on the mempool change on top of it.
+    #[serde(skip)]
+    mempool_state: ChainState,
+    // Hash of the block at each checkpointed index. The node sets them
+    // from its configuration.
+    #[serde(skip)]
+    checkpoints: BTreeMap<u64, String>,
+    // Size limits of our network.
+    #[serde(skip)]
+    limits: SizeLimits,
+    // Name of our network, and the services we offer, to tell nodes we
+    // shake hands with. The node sets them from its configuration.
+    #[serde(skip)]
+    network: String,
+    #[serde(skip)]
+    services: Vec<String>,
 }
 
 impl Blockchain {
-    // Blockchain constructor.
+    // Blockchain constructor, for the main network. Nodes start from
+    // their own network's genesis instead, see with_genesis.
+    #[cfg(test)]
     fn new() -> Blockchain {
+        Blockchain::with_genesis(&GenesisConfig::default())
+    }
+
+    // Creates a blockchain holding only the genesis block of a network.
+    fn with_genesis(genesis: &GenesisConfig) -> Blockchain {
         let mut blockchain = Blockchain {
-            chain:                vec![],
+            chain:                vec![Blockchain::genesis_block(genesis)],
             current_transactions: vec![],
-            nodes:                HashSet::new(),
+            pending_evidence:     vec![],
+            engine:               consensus::default_engine(),
+            clock:                Blockchain::system_clock(),
+            events:               vec![],
+            tree:                 BlockTree::new(),
+            state:                ChainState::new(),
+            mempool_state:        ChainState::new(),
+            checkpoints:          BTreeMap::new(),
+            limits:               SizeLimits::from_genesis(genesis),
+            network:              network::DEFAULT_NETWORK.to_owned(),
+            services:             vec![handshake::SERVICE_BLOCKS.to_owned()],
         };
-        // Create genesis block
-        blockchain.new_block(100, Some(String::from("1")));
+        blockchain.rebuild_tree();
         blockchain
     }
 
+    // Creates the genesis block of a network. Every node on the network
+    // creates the very same block, so it must not depend on the clock.
+    // Its previous hash is the hash of the whole genesis configuration,
+    // consensus parameters included, so networks differing on any of
+    // them never share a genesis block.
+    fn genesis_block(genesis: &GenesisConfig) -> Block {
+        let transactions: Vec<Transaction> = genesis.allocations.iter()
+            .map(|(address, &amount)| Transaction {
+                sender: "0".to_owned(),
+                recipient: address.clone(),
+                amount: amount,
+                nonce: 0,
+            })
+            .collect();
+        Block {
+            index: 1,
+            timestamp: genesis.timestamp,
+            merkle_root: Blockchain::merkle_root(&transactions),
+            transactions: transactions,
+            proof: genesis.proof,
+            previous_hash: {
+                let mut hasher = Sha256::new();
+                hasher.input(&encoding::to_bytes(genesis));
+                hasher.result_str()
+            },
+            signer: String::new(),
+            signature: String::new(),
+            vote: None,
+            evidence: vec![],
+        }
+    }
+
+    fn system_clock() -> Arc<dyn Clock> {
+        Arc::new(SystemClock)
+    }
+
+    // What we tell nodes we shake hands with.
+    fn handshake(&self) -> Handshake {
+        Handshake {
+            network:          self.network.clone(),
+            genesis:          Blockchain::hash(&self.chain[0]),
+            protocol_version: handshake::PROTOCOL_VERSION,
+            software_version: BLOCKCHAIN_RS_VERSION.to_owned(),
+            services:         self.services.clone(),
+            height:           self.chain.len() as u64,
+        }
+    }
+
     // Load blockchain from file
-    fn from_file(filename: String) -> Blockchain {
+    // genesis: Genesis of our network. A new blockchain starts from it.
+    fn from_file(filename: String, genesis: &GenesisConfig) -> Blockchain {
         let f = File::open(filename);
         match f {
             Err(_) => {
                 println!("Cannot read blockchain file. Creating a new one.");
-                Blockchain::new()
+                Blockchain::with_genesis(genesis)
             },
             Ok(mut f) => {
                 let mut text = String::new();
                 match f.read_to_string(&mut text) {
-                    Ok(_) => Blockchain::from_str(&text),
+                    Ok(_) => Blockchain::from_str(&text, genesis),
                     Err(_) => {
                         println!("Cannot read blockchain file text. Creating a new one.");
-                        Blockchain::new()
+                        Blockchain::with_genesis(genesis)
                     }
                 }
             }
@@ -156,17 +630,59 @@ impl Blockchain {
     }
 
     // Load blockchain from string
-    fn from_str(string: &String) -> Blockchain {
-        let deserialized = serde_json::from_str(string);
+    // genesis: Genesis of our network. A new blockchain starts from it.
+    fn from_str(string: &String, genesis: &GenesisConfig) -> Blockchain {
+        let deserialized: Result<Blockchain, _> = serde_json::from_str(string);
         match deserialized {
-            Ok(blockchain) => blockchain,
+            Ok(mut blockchain) => {
+                for block in blockchain.chain.iter_mut().filter(|block| block.merkle_root.is_empty()) {
+                    block.merkle_root = Blockchain::merkle_root(&block.transactions);
+                }
+                let expected = Blockchain::hash(&Blockchain::genesis_block(genesis));
+                match blockchain.chain.first() {
+                    Some(block) if Blockchain::hash(block) == expected => {},
+                    _ => {
+                        println!("Blockchain belongs to another network. Creating a new one.");
+                        return Blockchain::with_genesis(genesis);
+                    },
+                }
+                blockchain.limits = SizeLimits::from_genesis(genesis);
+                blockchain.rebuild_tree();
+                blockchain
+            },
             Err(_)         => {
                 println!("Cannot parse blockchain. Creating a new one.");
-                Blockchain::new()
+                Blockchain::with_genesis(genesis)
             }
         }
     }
 
+    // Replaces the consensus engine. Cumulative work depends on the
+    // engine, so the block tree is rebuilt.
+    fn set_engine(&mut self, engine: Arc<dyn ConsensusEngine>) {
+        self.engine = engine;
+        self.rebuild_tree();
+    }
+
+    // Rebuilds the block tree from our chain alone, along with the
+    // state after it.
+    fn rebuild_tree(&mut self) {
+        self.tree = BlockTree::new();
+        for block in self.chain.clone() {
+            self.add_to_tree(block);
+        }
+        self.state = ChainState::from_chain(&self.chain);
+        self.revalidate_mempool();
+    }
+
+    // Puts a block whose parent is on the tree (or a genesis block) on
+    // the tree, without validating it.
+    fn add_to_tree(&mut self, block: Block) {
+        let parent_work = self.tree.work(&block.previous_hash).unwrap_or(0);
+        let work = parent_work + self.engine.block_work(&block.header());
+        self.tree.insert(Blockchain::hash(&block), block, work);
+    }
+
     // Saves blockchain to file
     fn to_file(&self, filename: String) {
         // TODO: There must be a way not to lose the chain here...
@@ -181,104 +697,144 @@ impl Blockchain {
         };
     }
 
-    // Creates a new block in the blockchain
-    // proof: The proof given by the PoW algorithm
-    // previous_hash: Hash of previous block
-    // Return: New created block
-    fn new_block(&mut self, proof: u64, previous_hash: Option<String>) -> &Block {
-        let block = Block {
-            index: self.chain.len() as u64 + 1,
-            timestamp: time::precise_time_ns(),
-            transactions: self.current_transactions.clone(),
-            proof: proof,
-            previous_hash: match previous_hash {
-                Some(hash) => hash,
-                None       => Blockchain::hash(self.chain.last().unwrap())
-            }
-        };
-        self.current_transactions.clear();
-        self.chain.push(block.clone());
-        //println!("Created block {} with hash {}", block.index, Blockchain::hash(&block));
-        self.chain.last().unwrap() // We already pushed a block, so it's ok to unwrap here
-    }
-
     // Creates a new transaction to go into the next mined block.
     // sender: Address of sender
     // recipient: Address of recipient
     // amount: Amount of cash
-    // Return: Index of block which will hold this transaction
-    fn new_transaction(&mut self, sender: String, recipient: String, amount: i64) -> u64 {
-        self.current_transactions.push(Transaction {
+    // Return: Index of block which will hold this transaction, or Err with
+    // the rule it breaks, if it may not go on the chain after our mempool.
+    fn new_transaction(&mut self, sender: String, recipient: String, amount: i64) -> Result<u64, String> {
+        let transaction = Transaction {
             sender: sender.clone(),
             recipient: recipient.clone(),
-            amount: amount
-        });
-        
-        self.chain.last()
+            amount: amount,
+            nonce: Transaction::new_nonce(),
+        };
+        self.admit_transaction(transaction)?;
+
+        Ok(self.chain.last()
             .expect("Blockchain is empty! Where is the genesis block?")
-            .index + 1
+            .index + 1)
     }
 
-    // Creates a SHA-256 hash of a block
+    // Creates a SHA-256 hash of a block, which is the hash of its header
     // block: The block
     // Return: hash string
     fn hash(block: &Block) -> String {
-        // Correct this:
-        // 1. Serialize block to JSON
-        let json = serde_json::to_string(&block).unwrap();
-        //println!("JSON Block: {}", json);
-        // 2. Transform string into [u8]
-        let json_b = json.into_bytes();
-        // 3. Hash [u8] using crate sha2 https://crates.io/crates/sha2
+        Blockchain::hash_header(&block.header())
+    }
+
+    // Creates a SHA-256 hash of a block header
+    // header: The header
+    // Return: hash string
+    fn hash_header(header: &BlockHeader) -> String {
+        // Hash the canonical encoding, never the JSON, so the hash does
+        // not depend on how the header happens to be serialized.
         let mut hasher = Sha256::new();
-        hasher.input(&json_b);
+        hasher.input(&encoding::to_bytes(header));
         hasher.result_str()
     }
 
-    // Simple Proof of Work algorithm.
-    // Find a number p' such that hash(pp') contains leading 4 zeroes,
-    // where p is the previous p'. p is the previous proof, p' is the new
-    // proof.
-    // last_proof: last computed proof of work
-    fn proof_of_work(&self, last_proof: u64) -> u64 {
-        let mut proof: u64 = 0;
-        while !Blockchain::valid_proof(last_proof, proof) {
-            proof += 1;
-        }
-        proof
+    // Creates the hash a block producer signs: the hash of the block
+    // header, without its signature.
+    fn seal_hash(header: &BlockHeader) -> String {
+        let mut unsigned = header.clone();
+        unsigned.signature.clear();
+        Blockchain::hash_header(&unsigned)
     }
 
-    // Validates the proof of work.
-    // last_proof: Previous proof of work
-    // proof: Current proof of work
-    // Return: Whether proof is correct
-    fn valid_proof(last_proof: u64, proof: u64) -> bool {
-        // I need to check if this is correct later!
-        let mut hasher = Sha256::new();
-        let guess = last_proof.to_string() + proof.to_string().as_ref();
-        hasher.input(&guess.into_bytes());
-        let result = hasher.result_str();
-        //println!("Hasher result string: {}", result);
-        // Now check if first four characters are zeroes
-        &result[..4] == "0000"
+    // Computes the Merkle root over the IDs of transactions.
+    fn merkle_root(transactions: &[Transaction]) -> String {
+        let ids: Vec<String> = transactions.iter()
+            .map(|transaction| transaction.id())
+            .collect();
+        merkle::root(&ids)
+    }
+
+    // Checks that no transaction comes twice. The Merkle root pairs the
+    // last node of a level with itself, so repeating the last
+    // transactions of a block doesn't change its root, nor its hash.
+    fn distinct_transactions(transactions: &[Transaction]) -> bool {
+        let mut txids = HashSet::new();
+        transactions.iter().all(|transaction| txids.insert(transaction.id()))
     }
 
     // Mines a new block and appends it to the chain.
+    // The consensus engine is responsible for sealing the block.
     // identifier: Identifier for whoever is mining. Will receive a reward.
-    fn mine_block(&mut self, identifier: String) {
+    // Return: Err with a reason if the engine refused to produce the block.
+    fn mine_block(&mut self, identifier: String) -> Result<(), String> {
         let last_block = self.chain.last().unwrap().clone();
-        let last_proof = last_block.proof;
 
-        // Mine
-        let proof = self.proof_of_work(last_proof);
+        // Leave out whatever the chain would not accept
+        if !self.current_transactions.is_empty() {
+            self.revalidate_mempool();
+        }
+
+        // Reward. Its nonce is the block index, so that rewards for the
+        // same miner are not duplicates.
+        let reward = Transaction {
+            sender: "0".to_owned(),
+            recipient: identifier,
+            amount: MINING_REWARD,
+            nonce: last_block.index + 1,
+        };
+
+        // Forge the new block, then let the engine seal it.
+        // If our clock is behind the chain, stamp it with the earliest
+        // timestamp the chain accepts instead.
+        let timestamp = std::cmp::max(self.clock.now(),
+                                      Blockchain::median_time_past(&self.chain) + 1);
+        let mut block = Block {
+            index: last_block.index + 1,
+            timestamp: timestamp,
+            transactions: vec![reward.clone()],
+            merkle_root: Blockchain::merkle_root(&[reward.clone()]),
+            proof: 0,
+            previous_hash: Blockchain::hash(&last_block),
+            signer: String::new(),
+            signature: String::new(),
+            vote: None,
+            evidence: self.pending_evidence.clone(),
+        };
+
+        // Fill it with as many pending transactions as fit, in order.
+        // The rest wait for the next block.
+        let mut size = encoding::to_bytes(&block).len();
+        let mut fitting = self.current_transactions.iter()
+            .take_while(|transaction| {
+                size += encoding::encoded_len(*transaction);
+                size <= self.limits.block
+            })
+            .count();
+        // Sealing fills in more (a signature, a vote...), so if that makes
+        // the block too large, leave out transactions from the end until
+        // it fits, and seal it again.
+        loop {
+            let mut transactions: Vec<Transaction> = self.current_transactions[..fitting].to_vec();
+            transactions.push(reward.clone());
+            block.merkle_root = Blockchain::merkle_root(&transactions);
+            block.transactions = transactions;
+
+            self.engine.seal(&self.chain, &mut block)?;
 
-        // Reward
-        self.new_transaction("0".to_owned(), identifier, 1);
+            let mut size = encoding::to_bytes(&block).len();
+            if size <= self.limits.block || fitting == 0 {
+                break;
+            }
+            while size > self.limits.block && fitting > 0 {
+                fitting -= 1;
+                size -= encoding::encoded_len(&self.current_transactions[fitting]);
+            }
+        }
 
-        // Forge the new block
-        //let previous_hash = Blockchain::hash(&last_block);
-        //self.new_block(proof, Some(previous_hash));
-        self.new_block(proof, None);
+        self.current_transactions.drain(..fitting);
+        self.pending_evidence.clear();
+        let _ = Blockchain::apply_block(&mut self.state, &block);
+        self.add_to_tree(block.clone());
+        self.chain.push(block);
+        self.revalidate_mempool();
+        Ok(())
     }
 
     // Creates a new unique node identifier.
@@ -287,138 +843,727 @@ impl Blockchain {
         str::replace(Uuid::new_v4().to_string().as_ref(), "-", "")
     }
 
-    // Determines if a blockchain is valid.
-    // chain: Vector of blocks, normally fetched from remote node
-    fn valid_chain(chain: &Vec<Block>) -> bool {
-        for i in 1..chain.len() {
-            // Check if hash of block is correct
-            if chain[i].previous_hash != Blockchain::hash(&chain[i - 1]) {
-                return false;
-            }
-            // Check if proof of work is correct
-            if !Blockchain::valid_proof(chain[i - 1].proof, chain[i].proof) {
-                return false;
-            }
-        }
-        true
-    }
-
-    // This is our Consensus Algorithm. It resolves conflicts
-    // by replacing our chain with the longest one on the
-    // network.
-    // Return: Whether our chain was replaced or not.
-    fn resolve_conflicts(&mut self) -> bool {
-        // We're only looking for chains bigger than ours
-        let mut max_length = self.chain.len();
-        let mut new_chain: Option<Vec<Block>> = None;
-
-        // Grab and verify the chains from all nodes on the network
-        for node in &self.nodes {
-            if node != "local" { // TODO: I don't need to check this anymore!
-                let proto_uri = format!("{}/chain", node);
-                //println!("Fetching chain from {}", proto_uri);
-
-                // Reqwest glue code
-                let mut res = reqwest::get(proto_uri.as_ref() as &str).unwrap();
-
-                if res.status().is_success() {
-                    let mut body = String::new();
-                    let _ = res.read_to_string(&mut body);
-                    let chain: Vec<Block> = match serde_json::from_str(&body) {
-                        Ok(deserialized) => deserialized,
-                        Err(_) => vec![],
-                    };
-                    //println!("Comparing chain: {}", body);
+    // Median timestamp of the last MEDIAN_TIME_SPAN blocks of a chain.
+    // chain: Blocks before the one being timestamped
+    fn median_time_past(chain: &[Block]) -> u64 {
+        let start = chain.len().saturating_sub(MEDIAN_TIME_SPAN);
+        Blockchain::median(chain[start..].iter().map(|block| block.timestamp).collect())
+    }
 
-                    // We're looking only for chains bigger than ours.
-                    if chain.len() > max_length && Blockchain::valid_chain(&chain) {
-                        new_chain = Some(chain.clone());
-                        max_length = chain.len();
-                    }
-                } else {
-                    //println!("Error fetching remote chain: HTTP {}", res.status());
-                }
-            }
-        }
-        match new_chain {
-            Some(chain) => {
-                self.chain = chain.clone();
-                true
-            },
-            None => false
+    // Median of some timestamps, or 0 if there are none.
+    fn median(mut timestamps: Vec<u64>) -> u64 {
+        timestamps.sort();
+        match timestamps.len() {
+            0 => 0,
+            n => timestamps[n / 2],
         }
     }
 
-    // EXTRA: Generate a 25-byte binary address from an identifier.
-    // Notice that the identifier can be any string here. You should use
-    // a pubkey instead of the dumb hashing I use on this example.
-    // Also, this is the algorithm described for Bitcoin; i just wanted
-    // to have a good-looking address, after all.
-    fn generate_address_bin(identifier: &String) -> String {
-        // We perform SHA-256 on the pubkey, which is our identifier.
-        let sha256step = {
-            let mut hasher = Sha256::new();
-            hasher.input(&identifier.clone().into_bytes());
-            hasher.result_str()
-        };
-        //println!("First SHA-256 step: {}", sha256step);
+    // Determines if a blockchain is valid.
+    // chain: Vector of blocks, normally fetched from remote node
+    // Return: Err with the first block breaking a rule, and the rule
+    fn valid_chain(&self, chain: &[Block]) -> Result<(), InvalidBlock> {
+        self.valid_chain_with(chain, parallel::threads())
+    }
 
-        // Perform RIPEMD-160 on the hash
-        let ripemd160step = {
-            let mut hasher = crypto::ripemd160::Ripemd160::new();
-            hasher.input(&sha256step.into_bytes());
-            hasher.result_str()
-        };
+    // Determines if a blockchain is valid, spreading the work across
+    // threads. Whatever can be checked on each block alone (its header,
+    // proof, Merkle root...) is checked in parallel; then transactions
+    // are applied in order, one block after the other.
+    // threads: How many threads to use
+    fn valid_chain_with(&self, chain: &[Block], threads: usize) -> Result<(), InvalidBlock> {
+        let invalid = |position: usize, rule| InvalidBlock { index: position as u64 + 1, rule: rule };
 
-        // Add version byte to front of hash (00 here is Bitcoin's main net,
-        // so we'll just do the same)
-        let ripemd160step = "00".to_owned() + ripemd160step.as_ref();
+        // Hash every block just once
+        let hashes = parallel::map(chain.len(), threads, |i| Blockchain::hash(&chain[i]));
 
-        //println!("RIPEMD-160 step plus version: {}", ripemd160step);
+        // Check if it starts at the genesis of our network, and if the
+        // genesis header commits to its transactions
+        match chain.first() {
+            Some(genesis) if hashes[0] == Blockchain::hash(&self.chain[0]) => {
+                if genesis.merkle_root != Blockchain::merkle_root(&genesis.transactions) {
+                    return Err(invalid(0, BlockRule::MerkleRoot));
+                }
+            },
+            _ => return Err(invalid(0, BlockRule::Genesis)),
+        }
+        let pinned = self.checkpointed(chain, &hashes)?;
 
-        // Perform two SHA256 on it
-        let sha256step2n3 = {
-            let mut hasher1 = Sha256::new();
-            let mut hasher2 = Sha256::new();
-            hasher1.input(&ripemd160step.clone().into_bytes());
-            hasher2.input(&hasher1.result_str().into_bytes());
-            hasher2.result_str()
-        };
-        //println!("SHA-256 steps 2 and 3: {}", sha256step2n3);
+        let checks = parallel::map(chain.len() - 1, threads, |i| {
+            let i = i + 1;
+            self.check_header(&chain[..i], &hashes[i - 1], &chain[i], i < pinned)
+                .and_then(|_| self.check_body(&chain[i]))
+        });
+        let failed = checks.iter().position(|check| check.is_err()).map(|i| i + 1);
 
-        // Get address checksum; first 4 bytes (8 characters) of last step
-        let checksum = String::from(&sha256step2n3[..8]);
-        //println!("Checksum: {}", checksum);
+        // Transactions only go as far as the first block found invalid
+        let mut state = ChainState::from_chain(&chain[..1]);
+        for i in 1..failed.unwrap_or(chain.len()) {
+            Blockchain::apply_block(&mut state, &chain[i]).map_err(|rule| invalid(i, rule))?;
+        }
+        match failed {
+            Some(i) => Err(invalid(i, checks[i - 1].unwrap_err())),
+            None => Ok(()),
+        }
+    }
 
-        // Add checksum to extended RIPEMD-160 address, generating our
-        // 25-byte binary address.
-        // So this is already our binary address.
-        checksum + ripemd160step.as_ref()
+    // Checks a chain against our checkpoints.
+    // hashes: Hash of each block of the chain
+    // Return: Err if the chain has a different block at some checkpoint.
+    // Otherwise, how many of its first blocks lead up to a checkpoint.
+    fn checkpointed(&self, chain: &[Block], hashes: &[String]) -> Result<usize, InvalidBlock> {
+        let mut pinned = 0;
+        for (&index, hash) in &self.checkpoints {
+            match hashes.get(index as usize - 1) {
+                Some(ours) if ours == hash => pinned = index as usize,
+                Some(_) => return Err(InvalidBlock { index: index, rule: BlockRule::Checkpoint }),
+                None => break,
+            }
+        }
+        // The checkpoint only vouches for the blocks it links back to
+        let linked = (1..pinned)
+            .all(|i| chain[i].previous_hash == hashes[i - 1]);
+        Ok(if linked { pinned } else { 0 })
     }
 
-    // EXTRA: Generate a cute address from our binary address.
-    fn generate_address(bin_addr: &String) -> String {
-        assert_eq!(bin_addr.len(), 50);
-        // We need to gen a vec of numbers from string pairs of letters,
-        // then convert to base 58.
-        let mut binvec = vec![];
+    // Determines if a block may come after chain.
+    // chain: Every block before this one, from genesis up to its parent
+    // state: State after chain, which is left untouched
+    fn valid_block(&self, chain: &[Block], state: &ChainState, block: &Block) -> Result<(), BlockRule> {
+        let parent_hash = match chain.last() {
+            Some(parent) => Blockchain::hash(parent),
+            None => return Err(BlockRule::Genesis),
+        };
+        self.check_header(chain, &parent_hash, block, false)?;
+        self.check_body(block)?;
+        let mut delta = StateDelta::new(state);
+        for transaction in &block.transactions {
+            delta.apply_transaction(transaction)?;
+        }
+        Ok(())
+    }
 
-        for i in 0..25 {
-            let hex = &bin_addr[i*2..(i*2)+2];
-            binvec.push(i64::from_str_radix(hex, 16).unwrap() as u8);
+    // Checks the header of a block coming after chain.
+    // chain: Every block before this one, from genesis up to its parent
+    // parent_hash: Hash of its parent
+    // checkpointed: Whether the block is known to lead up to a checkpoint,
+    // so its consensus data needs no checking
+    fn check_header(&self, chain: &[Block], parent_hash: &str, block: &Block,
+                    checkpointed: bool) -> Result<(), BlockRule> {
+        // Check if index and hash of the previous block are correct
+        let parent = match chain.last() {
+            Some(parent) => parent,
+            None => return Err(BlockRule::Genesis),
+        };
+        if block.index != parent.index + 1 {
+            return Err(BlockRule::Index);
+        }
+        if block.previous_hash != parent_hash {
+            return Err(BlockRule::PreviousHash);
+        }
+        // Check if it agrees with our checkpoints
+        if let Some(hash) = self.checkpoints.get(&block.index) {
+            if Blockchain::hash(block) != *hash {
+                return Err(BlockRule::Checkpoint);
+            }
         }
+        // Check if timestamp is newer than the median of the last blocks,
+        // and not too far in the future
+        if block.timestamp <= Blockchain::median_time_past(chain)
+            || block.timestamp > self.clock.now() + MAX_FUTURE_BLOCK_TIME {
+            return Err(BlockRule::Timestamp);
+        }
+        // Check if the consensus data is correct
+        if !checkpointed && !self.engine.verify_header(chain, block) {
+            return Err(BlockRule::Consensus);
+        }
+        Ok(())
+    }
 
-        binvec.to_base58()
+    // Checks a header chain before downloading its blocks: every header
+    // must follow the one before it, agree with our checkpoints, be
+    // timestamped like a block would, and carry a valid seal.
+    // parent: The block on our tree before the first header
+    // Return: Err with the first header breaking a rule, and the rule
+    fn check_headers(&self, parent: &Block, headers: &[BlockHeader]) -> Result<(), InvalidBlock> {
+        // Timestamps of the last blocks, oldest first
+        let mut timestamps = VecDeque::new();
+        let mut ancestor = Some(parent);
+        while let Some(block) = ancestor {
+            if timestamps.len() >= MEDIAN_TIME_SPAN {
+                break;
+            }
+            timestamps.push_front(block.timestamp);
+            ancestor = self.tree.get(&block.previous_hash);
+        }
+        let mut previous_hash = Blockchain::hash(parent);
+        let mut previous_index = parent.index;
+        for header in headers {
+            let invalid = |rule| InvalidBlock { index: header.index, rule: rule };
+            if header.index != previous_index + 1 {
+                return Err(invalid(BlockRule::Index));
+            }
+            if header.previous_hash != previous_hash {
+                return Err(invalid(BlockRule::PreviousHash));
+            }
+            let hash = Blockchain::hash_header(header);
+            if self.checkpoints.get(&header.index).map_or(false, |checkpoint| *checkpoint != hash) {
+                return Err(invalid(BlockRule::Checkpoint));
+            }
+            if header.timestamp <= Blockchain::median(timestamps.iter().cloned().collect())
+                || header.timestamp > self.clock.now() + MAX_FUTURE_BLOCK_TIME {
+                return Err(invalid(BlockRule::Timestamp));
+            }
+            if !self.engine.verify_seal(header) {
+                return Err(invalid(BlockRule::Consensus));
+            }
+            if timestamps.len() >= MEDIAN_TIME_SPAN {
+                timestamps.pop_front();
+            }
+            timestamps.push_back(header.timestamp);
+            previous_hash = hash;
+            previous_index = header.index;
+        }
+        Ok(())
     }
 
-    fn generate_binaddr_from(address: &String) -> String {
-        let binvec2: Vec<_>  = address.from_base58().unwrap();
-        let binaddr2str: Vec<String> = binvec2.iter()
-            .map(|b| format!("{:02x}", b))
+    // Checks whatever about a block's transactions doesn't depend on
+    // the chain: sizes, duplicates, Merkle root and reward.
+    fn check_body(&self, block: &Block) -> Result<(), BlockRule> {
+        if block.transactions.iter()
+            .any(|transaction| encoding::encoded_len(transaction) > self.limits.transaction) {
+            return Err(BlockRule::TransactionSize);
+        }
+        if encoding::to_bytes(block).len() > self.limits.block {
+            return Err(BlockRule::Size);
+        }
+        if !Blockchain::distinct_transactions(&block.transactions) {
+            return Err(BlockRule::Duplicate);
+        }
+        if block.merkle_root != Blockchain::merkle_root(&block.transactions) {
+            return Err(BlockRule::MerkleRoot);
+        }
+        let rewards: Vec<&Transaction> = block.transactions.iter()
+            .filter(|transaction| transaction.sender == "0")
             .collect();
-        binaddr2str.join("")
+        if rewards.len() != 1 || rewards[0].amount != MINING_REWARD {
+            return Err(BlockRule::Reward);
+        }
+        Ok(())
     }
-}
+
+    // Applies the transactions of a block to state, checking balances
+    // and duplicates.
+    // state: State of the chain before this block
+    fn apply_block(state: &mut ChainState, block: &Block) -> Result<(), BlockRule> {
+        for transaction in &block.transactions {
+            state.apply_transaction(transaction)?;
+        }
+        Ok(())
+    }
+
+    // Hands a block to the node, which may arrive in any order and on
+    // any branch. Valid blocks go on the block tree, and if they make a
+    // branch heavier than ours, we switch to it.
+    // Return: What became of the block
+    fn accept_block(&mut self, block: Block) -> BlockStatus {
+        let hash = Blockchain::hash(&block);
+        let index = block.index;
+        match self.add_block(block, false) {
+            BlockStatus::Connected => {},
+            status => return status,
+        }
+        self.switch_to_best_tip();
+        match self.chain.get(index as usize - 1) {
+            Some(ours) if Blockchain::hash(ours) == hash => BlockStatus::Connected,
+            _ => BlockStatus::SideChain,
+        }
+    }
+
+    // Puts a block on the block tree, along with any orphans waiting for
+    // it, but doesn't switch branches.
+    // validated: Whether the block was already validated, along with a
+    // whole chain
+    // Return: Connected if the block went on the tree
+    fn add_block(&mut self, block: Block, validated: bool) -> BlockStatus {
+        let hash = Blockchain::hash(&block);
+        if self.tree.contains(&hash) {
+            return BlockStatus::Duplicate;
+        }
+        if self.tree.work(&block.previous_hash).is_none() {
+            self.tree.add_orphan(hash, block);
+            return BlockStatus::Orphan;
+        }
+        if !validated {
+            // Blocks extending our chain are checked against our state;
+            // those on side chains against their branch, replayed
+            let extends = block.previous_hash == Blockchain::hash(self.chain.last().unwrap());
+            let valid = if extends {
+                self.valid_block(&self.chain, &self.state, &block)
+            } else {
+                let branch = self.tree.branch(&block.previous_hash);
+                self.valid_block(&branch, &ChainState::from_chain(&branch), &block)
+            };
+            if let Err(rule) = valid {
+                return BlockStatus::Invalid(rule);
+            }
+        }
+        self.add_to_tree(block);
+
+        // Its children may be waiting already
+        for (_, orphan) in self.tree.take_orphans(&hash) {
+            self.add_block(orphan, false);
+        }
+        BlockStatus::Connected
+    }
+
+    // Switches to the heaviest branch of the block tree, if it has more
+    // work than ours.
+    // Return: Whether our chain was replaced
+    fn switch_to_best_tip(&mut self) -> bool {
+        let ours = self.tree.work(&Blockchain::hash(self.chain.last().unwrap())).unwrap_or(0);
+        match self.tree.tips().into_iter().next() {
+            Some(ref tip) if tip.work > ours => {
+                // Only take the blocks after the fork point off the tree
+                let mut blocks = vec![];
+                let mut hash = tip.hash.clone();
+                let fork = loop {
+                    if let Some(position) = self.chain_position(&hash) {
+                        break position + 1;
+                    }
+                    match self.tree.get(&hash) {
+                        Some(block) => blocks.push(block.clone()),
+                        None => return false,
+                    }
+                    hash = blocks.last().unwrap().previous_hash.clone();
+                };
+                blocks.reverse();
+                self.switch_branch(fork, blocks);
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // This is our Consensus Algorithm. It resolves conflicts by syncing
+    // headers from every node, then downloading the blocks of the
+    // heaviest header chain, if it has more work than ours (for proof of
+    // work, if it is the longest one on the network). Those blocks go on
+    // our block tree, and we follow the branch with the most work.
+    // book: Where our nodes are, and how they are doing
+    // Return: Whether our chain was replaced or not, and which nodes
+    // failed us
+    fn resolve_conflicts(&mut self, book: &mut AddressBook) -> Resolution {
+        let client = remote::client();
+        let mut resolution = Resolution::default();
+        let locator = sync::locator(&self.chain);
+        let ours = self.tree.work(&Blockchain::hash(self.chain.last().unwrap())).unwrap_or(0);
+
+        // Grab and check the headers we are missing from all nodes on the
+        // network, keeping the heaviest ones
+        let mut sources: Vec<(String, HashSet<String>)> = vec![];
+        let mut best: Option<(u64, Vec<BlockHeader>, Vec<String>)> = None;
+        let handshake = self.handshake();
+        for node in &book.nodes() {
+            // Only sync with nodes on our network, speaking our protocol
+            let theirs = match handshake::exchange(&client, node, &handshake) {
+                Ok(theirs) => theirs,
+                Err(reason) => {
+                    println!("Cannot shake hands with {}: {}", node, reason);
+                    peer_failed(book, node, &reason, self.clock.now());
+                    resolution.failures.insert(node.clone(), reason);
+                    continue;
+                },
+            };
+            let mismatch = theirs.check(&handshake).err();
+            // Headers of more blocks than it has are no good
+            let limit = theirs.height as usize + sync::HEIGHT_MARGIN;
+            book.shook_hands(node, theirs, mismatch.clone());
+            if let Some(mismatch) = mismatch {
+                println!("Not syncing with {}: it is {}.", node, mismatch);
+                resolution.failures.insert(node.clone(), PeerError::Incompatible(mismatch));
+                continue;
+            }
+            let headers = match sync::fetch_headers(&client, node, &locator, limit) {
+                Ok(headers) => headers,
+                Err(reason) => {
+                    println!("Cannot sync headers from {}: {}", node, reason);
+                    peer_failed(book, node, &reason, self.clock.now());
+                    resolution.failures.insert(node.clone(), reason);
+                    continue;
+                },
+            };
+            book.reached(node, self.clock.now(), headers.last().map(|header| header.index), None);
+            let (parent, parent_work) = match headers.first() {
+                Some(first) => match (self.tree.get(&first.previous_hash), self.tree.work(&first.previous_hash)) {
+                    (Some(parent), Some(work)) => (parent, work),
+                    _ => {
+                        println!("Headers from {} do not connect to our chain.", node);
+                        let reason = PeerError::Misbehaved("headers do not connect to our chain".to_owned());
+                        peer_failed(book, node, &reason, self.clock.now());
+                        resolution.failures.insert(node.clone(), reason);
+                        continue;
+                    },
+                },
+                None => continue,
+            };
+            if let Err(reason) = self.check_headers(parent, &headers) {
+                println!("Headers from {} are invalid, {}.", node, reason);
+                punish(book, node, Misbehaviour::from_rule(&reason.rule), self.clock.now());
+                resolution.failures.insert(node.clone(), PeerError::Misbehaved(format!("invalid headers, {}", reason)));
+                continue;
+            }
+            let work = parent_work + headers.iter()
+                .map(|header| self.engine.block_work(header))
+                .sum::<u64>();
+            let hashes: Vec<String> = headers.iter().map(Blockchain::hash_header).collect();
+            sources.push((node.clone(), hashes.iter().cloned().collect()));
+            if best.as_ref().map_or(true, |&(best_work, _, _)| work > best_work) {
+                best = Some((work, headers, hashes));
+            }
+        }
+        let (headers, hashes) = match best {
+            Some((work, headers, hashes)) if work > ours => (headers, hashes),
+            _ => return resolution,
+        };
+
+        // Download only the blocks we don't have yet, then check each one
+        // against the state of the branch it grows from
+        let have = hashes.iter().take_while(|hash| self.tree.work(hash).is_some()).count();
+        let base = if have == 0 { headers[0].previous_hash.clone() } else { hashes[have - 1].clone() };
+        let mut failed = BTreeMap::new();
+        let blocks = sync::fetch_blocks(&client, &sync::ranges(&hashes[have..], &sources),
+                                        self.limits.block, &mut failed);
+        for (node, reason) in failed {
+            peer_failed(book, &node, &reason, self.clock.now());
+            resolution.failures.insert(node, reason);
+        }
+        let blocks = match blocks {
+            Ok(blocks) => blocks,
+            Err(reason) => {
+                println!("Cannot sync blocks: {}", reason);
+                return resolution;
+            },
+        };
+        let (mut branch, mut state) = self.branch_state(&base);
+        for (node, block) in blocks {
+            if let Err(rule) = self.valid_block(&branch, &state, &block) {
+                // Whoever sent the block vouched for it
+                let reason = InvalidBlock { index: block.index, rule: rule };
+                println!("Synced block from {} is invalid, {}.", node, reason);
+                punish(book, &node, Misbehaviour::from_rule(&reason.rule), self.clock.now());
+                resolution.failures.insert(node, PeerError::Misbehaved(format!("invalid chain, {}", reason)));
+                break;
+            }
+            let _ = Blockchain::apply_block(&mut state, &block);
+            self.add_block(block.clone(), true);
+            branch.push(block);
+        }
+        self.collect_evidence(&branch);
+        resolution.replaced = self.switch_to_best_tip();
+        resolution
+    }
+
+    // Switches our chain to another, valid one.
+    // chain: The new chain
+    #[cfg(test)]
+    fn reorganize(&mut self, chain: Vec<Block>) {
+        // Find the fork point: the first block where both chains differ
+        let fork = self.chain.iter().zip(chain.iter())
+            .take_while(|&(ours, theirs)| Blockchain::hash(ours) == Blockchain::hash(theirs))
+            .count();
+        self.switch_branch(fork, chain.into_iter().skip(fork).collect());
+    }
+
+    // Switches our chain to another branch, whose blocks are valid.
+    // Our blocks after the fork point are disconnected and their
+    // transactions (except for rewards) go back to the mempool. Then the
+    // new blocks are connected, and whatever on the mempool became
+    // invalid is dropped. If any of our blocks were disconnected, a Reorg
+    // event is emitted.
+    // fork: Position of the first block where both branches differ
+    // blocks: Blocks of the branch from there on
+    fn switch_branch(&mut self, fork: usize, blocks: Vec<Block>) {
+        // Disconnect our blocks, returning their transactions to the mempool
+        let disconnected: Vec<Block> = self.chain.drain(fork..).collect();
+        for transaction in disconnected.iter().rev().flat_map(|block| block.transactions.iter().rev()) {
+            self.state.undo_transaction(transaction);
+        }
+        let mut mempool: Vec<Transaction> = disconnected.iter()
+            .flat_map(|block| block.transactions.iter())
+            .filter(|transaction| transaction.sender != "0")
+            .cloned()
+            .collect();
+        let restored = mempool.len();
+        mempool.extend(self.current_transactions.drain(..));
+        self.current_transactions = mempool;
+
+        // Connect the new blocks
+        for block in blocks {
+            if !self.tree.contains(&Blockchain::hash(&block)) {
+                self.add_to_tree(block.clone());
+            }
+            let _ = Blockchain::apply_block(&mut self.state, &block);
+            self.chain.push(block);
+        }
+
+        let dropped = self.revalidate_mempool();
+        if !disconnected.is_empty() {
+            self.events.push(ChainEvent::Reorg {
+                fork_index: fork as u64,
+                depth: disconnected.len(),
+                restored: restored,
+                dropped: dropped,
+            });
+        }
+    }
+
+    // Drops transactions from the mempool which are no longer valid on
+    // top of our chain: those already confirmed, rewards, those too large
+    // for our network, and those whose sender cannot afford them anymore.
+    // Confirmed means the same ID, nonce included, so a payment just like
+    // a confirmed one is still a payment of its own.
+    // Return: How many transactions were dropped
+    fn revalidate_mempool(&mut self) -> usize {
+        self.mempool_state = ChainState::new();
+        let before = self.current_transactions.len();
+        let pending: Vec<Transaction> = self.current_transactions.drain(..).collect();
+        for transaction in pending {
+            if transaction.sender != "0"
+                && encoding::encoded_len(&transaction) <= self.limits.transaction
+                && self.apply_pending(&transaction).is_ok() {
+                self.current_transactions.push(transaction);
+            }
+        }
+        before - self.current_transactions.len()
+    }
+
+    // Applies a transaction on top of our chain and mempool, if it may
+    // come after them, without putting it on the mempool.
+    fn apply_pending(&mut self, transaction: &Transaction) -> Result<(), BlockRule> {
+        let mut delta = StateDelta {
+            base: &self.state,
+            changes: std::mem::replace(&mut self.mempool_state, ChainState::new()),
+        };
+        let applied = delta.apply_transaction(transaction);
+        self.mempool_state = delta.changes;
+        applied
+    }
+
+    // Finds a block by its index on our chain, or by its hash on the
+    // block tree.
+    fn block(&self, id: &str) -> Option<&Block> {
+        match id.parse::<u64>() {
+            Ok(index) if index >= 1 => self.chain.get(index as usize - 1),
+            _ => self.tree.get(id),
+        }
+    }
+
+    // Finds a block on our chain by its hash.
+    // Return: Its position on the chain
+    fn chain_position(&self, hash: &str) -> Option<usize> {
+        let index = self.tree.get(hash)?.index as usize;
+        match self.chain.get(index.wrapping_sub(1)) {
+            Some(block) if Blockchain::hash(block) == hash => Some(index - 1),
+            _ => None,
+        }
+    }
+
+    // Gets the branch of the block tree ending at a block, along with the
+    // state after it. Blocks of our chain start from our state, taking
+    // back the blocks after them; others are replayed from genesis.
+    // hash: Hash of a block on the tree
+    fn branch_state(&self, hash: &str) -> (Vec<Block>, ChainState) {
+        match self.chain_position(hash) {
+            Some(position) => {
+                let mut state = self.state.clone();
+                for transaction in self.chain[position + 1..].iter().rev()
+                    .flat_map(|block| block.transactions.iter().rev()) {
+                    state.undo_transaction(transaction);
+                }
+                (self.chain[..position + 1].to_vec(), state)
+            },
+            None => {
+                let branch = self.tree.branch(hash);
+                let state = ChainState::from_chain(&branch);
+                (branch, state)
+            },
+        }
+    }
+
+    // Gets the headers after the last block of our chain on a locator,
+    // up to sync::MAX_HEADERS of them.
+    // Return: The headers, or none if no block of the locator is on our
+    // chain (not even genesis)
+    fn headers_after(&self, locator: &[String]) -> Vec<BlockHeader> {
+        match locator.iter().filter_map(|hash| self.chain_position(hash)).next() {
+            Some(position) => self.chain[position + 1..].iter()
+                .take(sync::MAX_HEADERS)
+                .map(|block| block.header())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // Gets the blocks of our chain from one hash to another, both
+    // included, up to sync::MAX_BLOCKS of them.
+    // Return: None if either block is not on our chain, or they are
+    // the wrong way around
+    fn blocks_between(&self, from: &str, to: &str) -> Option<Vec<Block>> {
+        match (self.chain_position(from), self.chain_position(to)) {
+            (Some(first), Some(last)) if first <= last => {
+                let last = std::cmp::min(last, first + sync::MAX_BLOCKS - 1);
+                Some(self.chain[first..last + 1].to_vec())
+            },
+            _ => None,
+        }
+    }
+
+    // Finds a transaction on the mempool.
+    fn pending_transaction(&self, txid: &str) -> Option<&Transaction> {
+        self.current_transactions.iter().find(|transaction| transaction.id() == txid)
+    }
+
+    // Adds a transaction heard of from another node to the mempool, if it
+    // could go on the chain after those already there.
+    // Return: Err with the reason if it couldn't
+    fn admit_transaction(&mut self, transaction: Transaction) -> Result<(), String> {
+        if transaction.sender == "0" {
+            return Err("rewards only come with blocks".to_owned());
+        }
+        if encoding::encoded_len(&transaction) > self.limits.transaction {
+            return Err(BlockRule::TransactionSize.description().to_owned());
+        }
+        self.apply_pending(&transaction).map_err(|rule| rule.description().to_owned())?;
+        self.current_transactions.push(transaction);
+        Ok(())
+    }
+
+    // Gets the header of every block on the chain.
+    fn headers(&self) -> Vec<BlockHeader> {
+        self.chain.iter()
+            .map(|block| block.header())
+            .collect()
+    }
+
+    // Builds a proof that a transaction was included in the chain.
+    // txid: ID of the transaction
+    // Return: The proof, or None if no block has this transaction
+    fn merkle_proof(&self, txid: &str) -> Option<MerkleProof> {
+        for block in &self.chain {
+            let ids: Vec<String> = block.transactions.iter()
+                .map(|transaction| transaction.id())
+                .collect();
+            if let Some(position) = ids.iter().position(|id| id == txid) {
+                return Some(MerkleProof {
+                    txid: txid.to_owned(),
+                    block_index: block.index,
+                    position: position,
+                    branch: merkle::branch(&ids, position),
+                    headers: self.headers(),
+                });
+            }
+        }
+        None
+    }
+
+    // Looks for blocks of a remote chain which were signed by the same
+    // producer as a different block of ours, at the same index. Each one
+    // is kept as evidence, to be included in the next block we mine.
+    // chain: Vector of blocks, normally fetched from remote node
+    fn collect_evidence(&mut self, chain: &Vec<Block>) {
+        for (ours, theirs) in self.chain.iter().zip(chain.iter()) {
+            if ours.signer.is_empty() || ours.signer != theirs.signer {
+                continue;
+            }
+            let ours_hash = Blockchain::seal_hash(&ours.header());
+            let theirs_hash = Blockchain::seal_hash(&theirs.header());
+            if ours_hash == theirs_hash
+                || !keys::verify(&ours.signer, ours_hash.as_bytes(), &ours.signature)
+                || !keys::verify(&theirs.signer, theirs_hash.as_bytes(), &theirs.signature) {
+                continue;
+            }
+            let known = self.pending_evidence.iter()
+                .any(|evidence| evidence.first.signer == ours.signer);
+            if !known {
+                self.pending_evidence.push(DoubleSignEvidence {
+                    first: ours.header(),
+                    second: theirs.header(),
+                });
+            }
+        }
+    }
+
+    // EXTRA: Generate a 25-byte binary address from an identifier.
+    // Notice that the identifier can be any string here. You should use
+    // a pubkey instead of the dumb hashing I use on this example.
+    // Also, this is the algorithm described for Bitcoin; i just wanted
+    // to have a good-looking address, after all.
+    fn generate_address_bin(identifier: &String) -> String {
+        // We perform SHA-256 on the pubkey, which is our identifier.
+        let sha256step = {
+            let mut hasher = Sha256::new();
+            hasher.input(&identifier.clone().into_bytes());
+            hasher.result_str()
+        };
+        //println!("First SHA-256 step: {}", sha256step);
+
+        // Perform RIPEMD-160 on the hash
+        let ripemd160step = {
+            let mut hasher = crypto::ripemd160::Ripemd160::new();
+            hasher.input(&sha256step.into_bytes());
+            hasher.result_str()
+        };
+
+        // Add version byte to front of hash (00 here is Bitcoin's main net,
+        // so we'll just do the same)
+        let ripemd160step = "00".to_owned() + ripemd160step.as_ref();
+
+        //println!("RIPEMD-160 step plus version: {}", ripemd160step);
+
+        // Perform two SHA256 on it
+        let sha256step2n3 = {
+            let mut hasher1 = Sha256::new();
+            let mut hasher2 = Sha256::new();
+            hasher1.input(&ripemd160step.clone().into_bytes());
+            hasher2.input(&hasher1.result_str().into_bytes());
+            hasher2.result_str()
+        };
+        //println!("SHA-256 steps 2 and 3: {}", sha256step2n3);
+
+        // Get address checksum; first 4 bytes (8 characters) of last step
+        let checksum = String::from(&sha256step2n3[..8]);
+        //println!("Checksum: {}", checksum);
+
+        // Add checksum to extended RIPEMD-160 address, generating our
+        // 25-byte binary address.
+        // So this is already our binary address.
+        checksum + ripemd160step.as_ref()
+    }
+
+    // EXTRA: Generate a cute address from our binary address.
+    fn generate_address(bin_addr: &String) -> String {
+        assert_eq!(bin_addr.len(), 50);
+        // We need to gen a vec of numbers from string pairs of letters,
+        // then convert to base 58.
+        let mut binvec = vec![];
+
+        for i in 0..25 {
+            let hex = &bin_addr[i*2..(i*2)+2];
+            binvec.push(i64::from_str_radix(hex, 16).unwrap() as u8);
+        }
+
+        binvec.to_base58()
+    }
+
+    fn generate_binaddr_from(address: &String) -> String {
+        let binvec2: Vec<_>  = address.from_base58().unwrap();
+        let binaddr2str: Vec<String> = binvec2.iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        binaddr2str.join("")
+    }
+}
 
 // ---
 
@@ -531,11 +1676,256 @@ enum ReplCommand {
     Print,
     Dump,
     RegNode { url: String },
+    DelNode { url: String },
+    ShowNodes,
+    ShowBans,
+    Unban { url: String },
     GetChain,
     Resolve,
+    ShowSigners,
+    ShowStakes,
+    ShowTips,
+    Stake { amount: i64 },
+    Propose { candidate: String, authorize: bool },
+    Discard { candidate: String },
     Quit,
 
     HttpGetChain,
+    HttpGetHeaders { locator: Option<Vec<String>> },
+    HttpGetBlocks { from: String, to: String },
+    HttpGetProof { txid: String },
+    HttpGetBlock { id: String },
+    HttpGetNodes,
+    HttpGetStatus,
+    HttpPostBlock { block: Block },
+    HttpNewTransaction { from: String, to: String, amount: i64 },
+    HttpMine { miner: String },
+    HttpRegNodes { urls: Vec<String> },
+    HttpResolve,
+    HttpGetTransaction { txid: String },
+    HttpPostInv { inventory: Inventory },
+
+    // Fetched by the gossip thread from node from, which was asked for id
+    GossipBlock { id: String, block: Block, from: String },
+    GossipTransaction { id: String, transaction: Transaction, from: String },
+    GossipPeers { peers: Vec<String>, from: String },
+    // Blocks leading up to an orphan block, in order
+    GossipAncestors { blocks: Vec<Block>, from: String },
+    // Could not fetch a block or transaction
+    GossipFailed { id: String },
+    HttpGetPeers,
+    // Time to ask our nodes for their peers
+    ExchangePeers,
+    // How the gossip thread did with one of our nodes
+    NodeReached { node: String, height: Option<u64>, latency: Option<u64> },
+    NodeFailed { node: String, error: PeerError },
+    NodeShookHands { node: String, handshake: Handshake, latency: u64 },
+    // A node shaking hands with us over HTTP
+    HttpHandshake { handshake: Handshake },
+
+    // From our p2p connections
+    P2pConnected { peer: u64, height: u64 },
+    P2pMessage { peer: u64, message: Message },
+    P2pDisconnected { peer: u64 },
+    ConnectPeer { address: String },
+}
+
+
+// What came of resolving conflicts with our nodes.
+#[derive(Default)]
+struct Resolution {
+    replaced: bool,
+    // How many of our blocks were disconnected, if replaced
+    reorg_depth: usize,
+    // Nodes which failed us, and how
+    failures: BTreeMap<String, PeerError>,
+}
+
+// Resolves conflicts with other nodes, reporting any reorg.
+fn resolve(blockchain: &mut Blockchain, book: &mut AddressBook) -> Resolution {
+    let mut resolution = blockchain.resolve_conflicts(book);
+    for event in blockchain.events.drain(..) {
+        match event {
+            ChainEvent::Reorg { fork_index, depth: d, restored, dropped } => {
+                println!("Daemon: reorg of depth {} after block {}: {} transaction(s) \
+                          back to the mempool, {} dropped as invalid.",
+                         d, fork_index, restored, dropped);
+                resolution.reorg_depth = d;
+            },
+        }
+    }
+    resolution
+}
+
+
+// Records that a node misbehaved, and tells if it got banned for it.
+// Return: Whether it just got banned
+fn punish(book: &mut AddressBook, node: &str, misbehaviour: Misbehaviour, now: u64) -> bool {
+    if !book.banned(node) && book.misbehaved(node, misbehaviour, now) {
+        println!("Daemon: banned {}, which {}.", node, misbehaviour.description());
+        return true;
+    }
+    false
+}
+
+// Records that a request to a node failed, and whether the node was to
+// blame for it.
+// Return: Whether it just got banned
+fn peer_failed(book: &mut AddressBook, node: &str, error: &PeerError, now: u64) -> bool {
+    if error.transient() {
+        book.failed(node);
+    }
+    match Misbehaviour::from_error(error) {
+        Some(misbehaviour) => punish(book, node, misbehaviour, now),
+        None => false,
+    }
+}
+
+// Announces blocks and transactions to every node, over HTTP and p2p.
+// from: The HTTP node we heard of them from, which has them already
+fn announce(gossip: &mut Gossip, book: &AddressBook, p2p: &P2p, blocks: Vec<String>,
+            transactions: Vec<String>, from: Option<&str>) {
+    p2p.broadcast(Message::Inv { blocks: blocks.clone(), transactions: transactions.clone() }, None);
+    gossip.announce(&book.nodes(), blocks, transactions, from);
+}
+
+// Announces the last block of our chain, which we just mined.
+fn announce_tip(gossip: &mut Gossip, blockchain: &Blockchain, book: &AddressBook, p2p: &P2p) {
+    let hash = Blockchain::hash(blockchain.chain.last().unwrap());
+    announce(gossip, book, p2p, vec![hash], vec![], None);
+}
+
+// Announces the last transaction on the mempool, which we just added.
+fn announce_pending(gossip: &mut Gossip, blockchain: &Blockchain, book: &AddressBook, p2p: &P2p) {
+    if let Some(transaction) = blockchain.current_transactions.last() {
+        announce(gossip, book, p2p, vec![], vec![transaction.id()], None);
+    }
+}
+
+// Describes what a node told us about itself on handshake.
+fn describe_handshake(handshake: &Handshake) -> String {
+    format!("network: {}, protocol: {}, software: {}, services: {}",
+            handshake.network, handshake.protocol_version, handshake.software_version,
+            handshake.services.join(", "))
+}
+
+// Catches up with a p2p node which just connected, if it is ahead of us.
+// Nodes from a banned IP address are dropped instead.
+// height: Length of its chain
+fn p2p_connected(blockchain: &Blockchain, book: &AddressBook, p2p: &P2p, peer: u64, height: u64) {
+    if p2p.ip(peer).map_or(false, |ip| book.banned(&ip)) {
+        p2p.disconnect(peer);
+        return;
+    }
+    if height > blockchain.chain.len() as u64 {
+        p2p.send(peer, Message::GetHeaders { locator: sync::locator(&blockchain.chain) });
+    }
+}
+
+// Handles a message from a p2p node: answers it, asks for whatever it
+// announced that we are missing, and announces whatever it got us to the
+// other p2p nodes. Nodes sending invalid blocks or headers are dropped, and punished
+// by IP address.
+// Return: Hashes of the blocks and IDs of the transactions it got us
+fn handle_p2p(blockchain: &mut Blockchain, book: &mut AddressBook, p2p: &P2p, peer: u64,
+              message: Message) -> (Vec<String>, Vec<String>) {
+    match message {
+        Message::Inv { blocks, transactions } => {
+            let blocks: Vec<String> = blocks.into_iter()
+                .filter(|hash| !blockchain.tree.contains(hash))
+                .collect();
+            let transactions: Vec<String> = transactions.into_iter()
+                .filter(|txid| blockchain.pending_transaction(txid).is_none())
+                .collect();
+            if !blocks.is_empty() || !transactions.is_empty() {
+                p2p.send(peer, Message::GetData { blocks: blocks, transactions: transactions });
+            }
+        },
+        Message::GetData { blocks, transactions } => {
+            for hash in &blocks {
+                if let Some(block) = blockchain.block(hash) {
+                    p2p.send(peer, Message::Block(block.clone()));
+                }
+            }
+            for txid in &transactions {
+                if let Some(transaction) = blockchain.pending_transaction(txid) {
+                    p2p.send(peer, Message::Tx(transaction.clone()));
+                }
+            }
+        },
+        Message::Block(block) => {
+            let index = block.index;
+            let hash = Blockchain::hash(&block);
+            let status = blockchain.accept_block(block);
+            println!("Daemon: received block {} from peer {}: {:?}", index, peer, status);
+            match status {
+                BlockStatus::Connected | BlockStatus::SideChain => {
+                    p2p.broadcast(Message::Inv { blocks: vec![hash.clone()], transactions: vec![] }, Some(peer));
+                    return (vec![hash], vec![]);
+                },
+                // Ask for the blocks leading up to it
+                BlockStatus::Orphan => p2p.send(peer, Message::GetHeaders { locator: sync::locator(&blockchain.chain) }),
+                BlockStatus::Invalid(rule) => {
+                    if let Some(ip) = p2p.ip(peer) {
+                        punish(book, &ip, Misbehaviour::from_rule(&rule), blockchain.clock.now());
+                    }
+                    p2p.disconnect(peer);
+                },
+                BlockStatus::Duplicate => {},
+            }
+        },
+        Message::Tx(transaction) => {
+            let txid = transaction.id();
+            if blockchain.admit_transaction(transaction).is_ok() {
+                p2p.broadcast(Message::Inv { blocks: vec![], transactions: vec![txid.clone()] }, Some(peer));
+                return (vec![], vec![txid]);
+            }
+        },
+        Message::GetHeaders { locator } => {
+            p2p.send(peer, Message::Headers(blockchain.headers_after(&locator)));
+        },
+        Message::Headers(headers) => {
+            // Headers must link up to a block we know, and be checked like
+            // blocks would be, before we ask for any of them
+            let first = match headers.first() {
+                Some(first) => first,
+                None => return (vec![], vec![]),
+            };
+            let misbehaviour = if headers.len() > sync::MAX_HEADERS {
+                Some(Misbehaviour::Oversized)
+            } else {
+                match blockchain.tree.get(&first.previous_hash) {
+                    Some(parent) => blockchain.check_headers(parent, &headers).err()
+                        .map(|reason| Misbehaviour::from_rule(&reason.rule)),
+                    None => Some(Misbehaviour::Malformed),
+                }
+            };
+            if let Some(misbehaviour) = misbehaviour {
+                println!("Daemon: invalid headers from peer {}: {}", peer, misbehaviour.description());
+                if let Some(ip) = p2p.ip(peer) {
+                    punish(book, &ip, misbehaviour, blockchain.clock.now());
+                }
+                p2p.disconnect(peer);
+                return (vec![], vec![]);
+            }
+            let wanted: Vec<String> = headers.iter()
+                .map(Blockchain::hash_header)
+                .filter(|hash| !blockchain.tree.contains(hash))
+                .collect();
+            if wanted.is_empty() {
+                return (vec![], vec![]);
+            }
+            p2p.send(peer, Message::GetData { blocks: wanted, transactions: vec![] });
+            // A full batch of new headers means there are more. The blocks
+            // are sent before the next headers, so those link up to them.
+            if headers.len() == sync::MAX_HEADERS {
+                let last = Blockchain::hash_header(headers.last().unwrap());
+                p2p.send(peer, Message::GetHeaders { locator: vec![last] });
+            }
+        },
+        _ => {},
+    }
+    (vec![], vec![])
 }
 
 
@@ -591,6 +1981,10 @@ fn save_aliases(aliases: &HashMap<String, String>, filename: String) {
 // The consensus is also missing! We need to implement the consensus.
 fn main() {
     let mut node_port = "3000".to_owned(); // Default HTTP service port
+    let mut p2p_port: Option<String> = None; // P2P port, 1000 above the HTTP port unless given
+    let mut bind: Option<String> = None; // HTTP bind address, from the configuration unless given
+    let mut config_file = "config.json".to_owned(); // Default configuration file
+    let mut light_mode = false;
 
     println!("blockchain-rs {}", BLOCKCHAIN_RS_VERSION);
     println!("Copyright (C) 2017 Lucas Vieira.");
@@ -609,9 +2003,16 @@ fn main() {
                         println!("Command line options:");
                         println!(" -h | --help       Shows help prompt, then exit.");
                         println!(" --port=XXXX       Uses port XXXX as HTTP port, instead of 3000.");
+                        println!(" --p2p-port=XXXX   Uses port XXXX as P2P port, instead of the HTTP port plus 1000.");
+                        println!(" --bind=ADDR       Binds the HTTP server to ADDR, instead of the configured one.");
+                        println!(" --config=FILE     Reads node configuration from FILE, instead of config.json.");
+                        println!(" --light           Runs as a light client, following block headers only.");
                         println!("\nREPL commands:\n{}", HELP_PROMPT);
                         return;
                     },
+                    "--light" => {
+                        light_mode = true;
+                    },
                     _ => {
                         // Unknown command
                     },
@@ -624,30 +2025,159 @@ fn main() {
                     "--port" => {
                         node_port = argcfg.to_owned();
                     },
+                    "--p2p-port" => {
+                        p2p_port = Some(argcfg.to_owned());
+                    },
+                    "--bind" => {
+                        bind = Some(argcfg.to_owned());
+                    },
+                    "--config" => {
+                        config_file = argcfg.to_owned();
+                    },
                     _ => {},
                 }
             }
         }
     }
 
+    // Node configuration
+    let config = NodeConfig::from_file(config_file);
+    let network = match network::from_config(&config) {
+        Ok(network) => network,
+        Err(reason) => {
+            println!("{}", reason);
+            return;
+        },
+    };
+    println!("Joining network: {}", network.name);
+    if light_mode {
+        light::run(&network);
+        return;
+    }
+    let engine = match consensus::from_config(&config, &network) {
+        Ok(engine) => engine,
+        Err(reason) => {
+            println!("{}", reason);
+            return;
+        },
+    };
+    println!("Using consensus engine: {}", engine.name());
+    let checkpoints = match checkpoints::from_config(&config, &network) {
+        Ok(checkpoints) => checkpoints,
+        Err(reason) => {
+            println!("{}", reason);
+            return;
+        },
+    };
+    let auth = match api::Auth::from_config(&config) {
+        Ok(auth) => auth,
+        Err(reason) => {
+            println!("{}", reason);
+            return;
+        },
+    };
+    let bind = bind.unwrap_or(config.http_bind.clone());
+    let loopback = bind == "localhost" || bind.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false);
+    if auth.is_open() && !loopback {
+        println!("Warning: the HTTP server takes connections from other machines, but needs no authentication.");
+    }
+    let seeds = config.seeds.clone();
+    let ban_policy = BanPolicy {
+        threshold: config.ban_threshold,
+        duration:  config.ban_duration,
+    };
+
     // Communication channels
     let (tx, rx) = mpsc::channel();        // REPL to Daemon
     let (ty, ry) = mpsc::channel();        // Daemon to REPL
     let (tz, rz) = mpsc::channel();        // Daemon to HTTP service
     let txhttp = tx.clone();               // HTTP service to Daemon using REPL commands
+    let txgossip = tx.clone();             // Gossip to Daemon
+    let txtimer = tx.clone();              // Peer exchange timer to Daemon
+    let txp2p = tx.clone();                // P2P connections to Daemon
+    // Other nodes reach us where configured, or where we bind. Binding to
+    // every address, we can't tell which one they reach us at, so we
+    // don't announce anything unless configured.
+    let unspecified = bind.parse::<IpAddr>().map(|ip| ip.is_unspecified()).unwrap_or(false);
+    let host = if unspecified { "127.0.0.1".to_owned() } else { bind.clone() };
+    let public_url = match config.public_url.clone() {
+        Some(url) => {
+            if Url::parse(&url).is_err() {
+                println!("public_url {} is not a valid URL.", url);
+                return;
+            }
+            Some(url)
+        },
+        None if unspecified => {
+            println!("Warning: the HTTP server binds to every address, but public_url is not configured, \
+                      so other nodes can't be told where to fetch from us: not announcing over HTTP.");
+            None
+        },
+        None => Some(format!("http://{}:{}", host, node_port)),
+    };
+    let local_url = public_url.clone().unwrap_or(format!("http://{}:{}", host, node_port));
+    let p2p_bind = config.p2p_bind.clone().unwrap_or(bind.clone());
+    let p2p_port = p2p_port.unwrap_or_else(|| match node_port.parse::<u16>().ok().and_then(|port| port.checked_add(1000)) {
+        Some(port) => port.to_string(),
+        None => "4000".to_owned(),
+    });
+    let p2p_peers = config.p2p_peers.clone();
+    // Largest request body we read: a block, as JSON
+    let max_body = SizeLimits::from_genesis(&network.genesis).block as u64 * remote::JSON_OVERHEAD;
 
     
 
     /* ===== DAEMON ===== */
     let daemon = thread::spawn(move || {
         // Create blockchain
-        let mut blockchain = Blockchain::from_file("blockchain.json".to_owned());
+        let mut blockchain = Blockchain::from_file("blockchain.json".to_owned(), &network.genesis);
+        blockchain.set_engine(engine);
+        blockchain.checkpoints = checkpoints;
+        blockchain.network = network.name.clone();
+        blockchain.services = vec![handshake::SERVICE_BLOCKS.to_owned()];
+        if let Err(reason) = blockchain.valid_chain(&blockchain.chain) {
+            println!("Daemon: the saved chain is not valid for the {} engine, {}!",
+                     blockchain.engine.name(), reason);
+        }
+
+        let mut gossip = Gossip::start(public_url, blockchain.limits, txgossip);
+
+        // Talk to other nodes over p2p, too
+        let p2p = P2p::new(blockchain.handshake(), blockchain.limits, txp2p);
+        match p2p.listen(&format!("{}:{}", p2p_bind, p2p_port)) {
+            Ok(address) => {
+                println!("Daemon: P2P listening on {}", address);
+                blockchain.services.push(handshake::SERVICE_P2P.to_owned());
+            },
+            Err(e) => println!("Daemon: cannot listen for P2P on port {}: {}", p2p_port, e),
+        }
+        for address in &p2p_peers {
+            p2p.connect(address);
+        }
+
+        // Find peers from the nodes we know, starting from the seeds
+        let mut book = AddressBook::from_file("peers.json".to_owned());
+        book.policy = ban_policy;
+        book.expire_bans(blockchain.clock.now());
+        for seed in network.seeds.iter().chain(seeds.iter()) {
+            book.add(seed);
+        }
+        book.top_up(&local_url);
+        gossip.ask_peers(&book.registered(), blockchain.handshake());
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(peers::EXCHANGE_INTERVAL));
+            if txtimer.send(ReplCommand::ExchangePeers).is_err() {
+                break;
+            }
+        });
 
         let _ = ty.send(Ok("DAEMON READY".to_owned()));
         
         loop {
+            p2p.set_local(blockchain.handshake());
             match rx.recv().unwrap() { // TODO: Workaround for this unwrap
                 ReplCommand::Quit => {
+                    book.to_file("peers.json".to_owned());
                     let _ = ty.send(Ok("DAEMON QUIT".to_owned()));
                     break
                 },
@@ -655,18 +2185,29 @@ fn main() {
                     let _ = ty.send(Ok(serde_json::to_string_pretty(&blockchain).unwrap()));
                 },
                 ReplCommand::Transaction { from, to, amount } => {
-                    blockchain.new_transaction(from.clone(), to.clone(), amount);
-                    let _ = ty.send(Ok("TRANSACTION COMPLETED".to_owned())); // TODO: Validate from balance?
+                    let _ = match blockchain.new_transaction(from.clone(), to.clone(), amount) {
+                        Ok(_) => {
+                            announce_pending(&mut gossip, &blockchain, &book, &p2p);
+                            ty.send(Ok("TRANSACTION COMPLETED".to_owned()))
+                        },
+                        Err(reason) => ty.send(Err(reason)),
+                    };
                 },
                 ReplCommand::Mine { miner } => {
                     let now = SystemTime::now();
-                    blockchain.mine_block(miner.clone());
-                    let _ = match now.elapsed() {
-                        Ok(elapsed) => {
-                            let ans = format!("BLOCK MINED IN {} SECONDS", elapsed.as_secs());
-                            ty.send(Ok(ans.clone()))
+                    let mined = blockchain.mine_block(miner.clone());
+                    if mined.is_ok() {
+                        announce_tip(&mut gossip, &blockchain, &book, &p2p);
+                    }
+                    let _ = match mined {
+                        Ok(_) => match now.elapsed() {
+                            Ok(elapsed) => {
+                                let ans = format!("BLOCK MINED IN {} SECONDS", elapsed.as_secs());
+                                ty.send(Ok(ans.clone()))
+                            },
+                            Err(_) => ty.send(Err("MINING ERROR".to_owned())),
                         },
-                        Err(_) => ty.send(Err("MINING ERROR".to_owned())),
+                        Err(reason) => ty.send(Err(format!("MINING ERROR: {}", reason))),
                     };
                 },
                 ReplCommand::Save { filename } => {
@@ -674,23 +2215,435 @@ fn main() {
                     let _ = ty.send(Ok("FILE SAVED".to_owned()));
                 },
                 ReplCommand::RegNode { url } => {
-                    blockchain.nodes.insert(url.clone());
+                    if book.banned(&url) {
+                        let _ = ty.send(Err("BANNED".to_owned()));
+                        continue;
+                    }
+                    book.register(&url);
+                    book.to_file("peers.json".to_owned());
+                    // Its announcements are only taken once we shook hands
+                    gossip.ask_peers(&[url], blockchain.handshake());
                     let _ = ty.send(Ok("REGISTERED".to_owned()));
                 },
-                ReplCommand::Resolve => {
-                    let changed = blockchain.resolve_conflicts();
-                    let _ = match changed {
-                        true  => ty.send(Ok("CHAIN UPDATED".to_owned())),
-                        false => ty.send(Ok("CHAIN UP-TO-DATE".to_owned())),
-                    };
+                ReplCommand::ShowBans => {
+                    let now = blockchain.clock.now();
+                    book.expire_bans(now);
+                    let bans = book.bans();
+                    if bans.is_empty() {
+                        println!("No banned nodes.");
+                    }
+                    for (node, ban) in bans {
+                        println!("{}  banned for {}s more: {}", node, ban.until.saturating_sub(now), ban.reason);
+                    }
+                    let _ = ty.send(Ok("BANS SHOWN".to_owned()));
+                },
+                ReplCommand::Unban { url } => {
+                    let _ = match book.unban(&url) {
+                        true => {
+                            book.to_file("peers.json".to_owned());
+                            ty.send(Ok("UNBANNED".to_owned()))
+                        },
+                        false => ty.send(Err("NOT BANNED".to_owned())),
+                    };
+                },
+                ReplCommand::DelNode { url } => {
+                    let _ = match book.unregister(&url) {
+                        true => {
+                            book.to_file("peers.json".to_owned());
+                            ty.send(Ok("DELETED".to_owned()))
+                        },
+                        false => ty.send(Err("NOT A NODE".to_owned())),
+                    };
+                },
+                ReplCommand::ShowNodes => {
+                    let now = blockchain.clock.now();
+                    let nodes = book.node_status();
+                    if nodes.is_empty() {
+                        println!("No registered nodes.");
+                    }
+                    for (node, status) in nodes {
+                        let contact = match status.last_contact {
+                            0 => "never".to_owned(),
+                            last => format!("{}s ago", now.saturating_sub(last)),
+                        };
+                        let height = status.height.map(|h| h.to_string()).unwrap_or("?".to_owned());
+                        let latency = status.latency.map(|l| format!("{}ms", l)).unwrap_or("?".to_owned());
+                        println!("{}  last contact: {}, height: {}, latency: {}, failures: {}, misbehaviour: {}",
+                                 node, contact, height, latency, status.failures, book.score(&node));
+                        if let Some(ref handshake) = status.handshake {
+                            println!("    {}", describe_handshake(handshake));
+                        }
+                        if let Some(ref mismatch) = status.mismatch {
+                            println!("    not syncing: it is {}", mismatch);
+                        }
+                    }
+                    for peer in p2p.peers() {
+                        println!("P2P peer {}: {} ({}), height: {}", peer.id, peer.address,
+                                 if peer.outbound { "outbound" } else { "inbound" }, peer.handshake.height);
+                        println!("    {}", describe_handshake(&peer.handshake));
+                    }
+                    let _ = ty.send(Ok("NODES SHOWN".to_owned()));
+                },
+                ReplCommand::Resolve => {
+                    let resolution = resolve(&mut blockchain, &mut book);
+                    book.to_file("peers.json".to_owned());
+                    let mut response = match resolution.replaced {
+                        true  => format!("CHAIN UPDATED (REORG DEPTH {})", resolution.reorg_depth),
+                        false => "CHAIN UP-TO-DATE".to_owned(),
+                    };
+                    for (node, reason) in &resolution.failures {
+                        response.push_str(&format!("\n  {} failed: {}", node, reason));
+                    }
+                    let _ = ty.send(Ok(response));
                 },
                 ReplCommand::GetChain => {
                     let chain_serialized: String = serde_json::to_string(&blockchain.chain).unwrap();
                     let _ = ty.send(Ok(chain_serialized.clone()));
                 },
+                ReplCommand::ShowSigners => {
+                    let ans = match blockchain.engine.signers(&blockchain.chain) {
+                        Some(signers) => {
+                            let mut ans = format!("Authorized signers:\n{}", signers.join("\n"));
+                            if let Some(key) = blockchain.engine.local_signer() {
+                                ans += format!("\n\nThis node signs with key {}", key).as_ref();
+                            }
+                            Ok(ans)
+                        },
+                        None => Err(format!("The {} engine has no signers.", blockchain.engine.name())),
+                    };
+                    let _ = ty.send(ans);
+                },
+                ReplCommand::ShowStakes => {
+                    let ans = match blockchain.engine.stakes(&blockchain.chain) {
+                        Some(stakes) => {
+                            let lines: Vec<String> = stakes.iter()
+                                .map(|&(ref validator, stake)| format!("{}: ${}", validator, stake))
+                                .collect();
+                            Ok(format!("Validator stakes:\n{}", lines.join("\n")))
+                        },
+                        None => Err(format!("The {} engine has no stake.", blockchain.engine.name())),
+                    };
+                    let _ = ty.send(ans);
+                },
+                ReplCommand::ShowTips => {
+                    let active = Blockchain::hash(blockchain.chain.last().unwrap());
+                    let lines: Vec<String> = blockchain.tree.tips().iter()
+                        .map(|tip| format!("{} block {}, hash {}, work {}",
+                                           if tip.hash == active { "*" } else { " " },
+                                           tip.index, tip.hash, tip.work))
+                        .collect();
+                    let _ = ty.send(Ok(format!("Chain tips (* is ours), {} orphan(s) waiting:\n{}",
+                                               blockchain.tree.orphan_count(), lines.join("\n"))));
+                },
+                ReplCommand::Stake { amount } => {
+                    let engine = blockchain.engine.clone();
+                    let _ = match (engine.local_signer(), engine.stake_address()) {
+                        (Some(validator), Some(address)) => {
+                            match blockchain.new_transaction(validator, address.to_owned(), amount) {
+                                Ok(index) => {
+                                    announce_pending(&mut gossip, &blockchain, &book, &p2p);
+                                    ty.send(Ok(format!("STAKE WILL BE LOCKED ON BLOCK {}", index)))
+                                },
+                                Err(reason) => ty.send(Err(reason)),
+                            }
+                        },
+                        _ => ty.send(Err(format!("The {} engine has no stake.", engine.name()))),
+                    };
+                },
+                ReplCommand::Propose { candidate, authorize } => {
+                    let _ = match blockchain.engine.propose(candidate, authorize) {
+                        Ok(_) => ty.send(Ok("PROPOSAL REGISTERED".to_owned())),
+                        Err(reason) => ty.send(Err(reason)),
+                    };
+                },
+                ReplCommand::Discard { candidate } => {
+                    let _ = match blockchain.engine.discard(&candidate) {
+                        Ok(_) => ty.send(Ok("PROPOSAL DISCARDED".to_owned())),
+                        Err(reason) => ty.send(Err(reason)),
+                    };
+                },
                 ReplCommand::HttpGetChain => {
                     let chain_serialized: String = serde_json::to_string(&blockchain.chain).unwrap();
-                    let _ = tz.send(chain_serialized.clone());
+                    let _ = tz.send(Ok(chain_serialized.clone()));
+                },
+                ReplCommand::HttpGetHeaders { locator } => {
+                    let headers = match locator {
+                        Some(locator) => blockchain.headers_after(&locator),
+                        None => blockchain.headers(),
+                    };
+                    let _ = tz.send(Ok(serde_json::to_string(&headers).unwrap()));
+                },
+                ReplCommand::HttpGetBlocks { from, to } => {
+                    let _ = match blockchain.blocks_between(&from, &to) {
+                        Some(blocks) => tz.send(Ok(serde_json::to_string(&blocks).unwrap())),
+                        None => tz.send(Err(ApiError::not_found(format!("Blocks {} to {} are not on our chain",
+                                                                        from, to)))),
+                    };
+                },
+                ReplCommand::HttpGetProof { txid } => {
+                    let _ = match blockchain.merkle_proof(&txid) {
+                        Some(proof) => tz.send(Ok(serde_json::to_string(&proof).unwrap())),
+                        None => tz.send(Err(ApiError::not_found(format!("Transaction {} not found", txid)))),
+                    };
+                },
+                ReplCommand::HttpGetBlock { id } => {
+                    let _ = match blockchain.block(&id) {
+                        Some(block) => tz.send(Ok(serde_json::to_string(block).unwrap())),
+                        None => tz.send(Err(ApiError::not_found(format!("Block {} not found", id)))),
+                    };
+                },
+                ReplCommand::HttpGetNodes => {
+                    let _ = tz.send(Ok(serde_json::to_string(&api::Nodes { nodes: book.registered() }).unwrap()));
+                },
+                ReplCommand::HttpHandshake { handshake } => {
+                    // Answer with ours either way, so that it can tell
+                    // what is wrong too
+                    if let Err(mismatch) = handshake.check(&blockchain.handshake()) {
+                        println!("Daemon: a node shook hands from another network: it is {}.", mismatch);
+                    }
+                    let _ = tz.send(Ok(serde_json::to_string(&blockchain.handshake()).unwrap()));
+                },
+                ReplCommand::HttpGetStatus => {
+                    let tip = Blockchain::hash(blockchain.chain.last().unwrap());
+                    let status = api::Status {
+                        network: network.name.clone(),
+                        engine: blockchain.engine.name().to_owned(),
+                        length: blockchain.chain.len(),
+                        work: blockchain.tree.work(&tip).unwrap_or(0),
+                        tip: tip,
+                        mempool: blockchain.current_transactions.len(),
+                        nodes: book.nodes().len(),
+                        known_peers: book.len(),
+                        orphans: blockchain.tree.orphan_count(),
+                    };
+                    let _ = tz.send(Ok(serde_json::to_string(&status).unwrap()));
+                },
+                ReplCommand::HttpPostBlock { block } => {
+                    let index = block.index;
+                    let hash = Blockchain::hash(&block);
+                    let _ = match blockchain.accept_block(block) {
+                        BlockStatus::Invalid(rule) => {
+                            tz.send(Err(ApiError::bad_request(format!("Block {} is invalid: {}",
+                                                                      index, rule.description()))))
+                        },
+                        status => {
+                            println!("Daemon: received block {}: {:?}", index, status);
+                            if status == BlockStatus::Connected || status == BlockStatus::SideChain {
+                                announce(&mut gossip, &book, &p2p, vec![hash], vec![], None);
+                            }
+                            let accepted = api::BlockAccepted { status: format!("{:?}", status) };
+                            tz.send(Ok(serde_json::to_string(&accepted).unwrap()))
+                        },
+                    };
+                },
+                ReplCommand::HttpNewTransaction { from, to, amount } => {
+                    let _ = match blockchain.new_transaction(from, to, amount) {
+                        Ok(index) => {
+                            announce_pending(&mut gossip, &blockchain, &book, &p2p);
+                            let created = api::TransactionCreated {
+                                message: format!("Transaction will be added to block {}", index),
+                                index: index,
+                            };
+                            tz.send(Ok(serde_json::to_string(&created).unwrap()))
+                        },
+                        Err(reason) => tz.send(Err(ApiError::bad_request(reason))),
+                    };
+                },
+                ReplCommand::HttpMine { miner } => {
+                    let _ = match blockchain.mine_block(miner) {
+                        Ok(_) => {
+                            announce_tip(&mut gossip, &blockchain, &book, &p2p);
+                            tz.send(Ok(serde_json::to_string(blockchain.chain.last().unwrap()).unwrap()))
+                        },
+                        Err(reason) => tz.send(Err(ApiError::new(409, reason))),
+                    };
+                },
+                ReplCommand::HttpRegNodes { urls } => {
+                    for url in &urls {
+                        book.register(url);
+                    }
+                    book.to_file("peers.json".to_owned());
+                    let registered = book.registered();
+                    let urls: Vec<String> = urls.into_iter().filter(|url| registered.contains(url)).collect();
+                    gossip.ask_peers(&urls, blockchain.handshake());
+                    let _ = tz.send(Ok(serde_json::to_string(&api::Nodes { nodes: book.registered() }).unwrap()));
+                },
+                ReplCommand::HttpResolve => {
+                    let resolution = resolve(&mut blockchain, &mut book);
+                    book.to_file("peers.json".to_owned());
+                    let resolved = api::Resolved {
+                        replaced: resolution.replaced,
+                        reorg_depth: resolution.reorg_depth,
+                        length: blockchain.chain.len(),
+                        failures: resolution.failures.iter()
+                            .map(|(node, reason)| (node.clone(), reason.to_string()))
+                            .collect(),
+                    };
+                    let _ = tz.send(Ok(serde_json::to_string(&resolved).unwrap()));
+                },
+                ReplCommand::HttpGetTransaction { txid } => {
+                    let _ = match blockchain.pending_transaction(&txid) {
+                        Some(transaction) => tz.send(Ok(serde_json::to_string(transaction).unwrap())),
+                        None => tz.send(Err(ApiError::not_found(format!("Transaction {} is not pending", txid)))),
+                    };
+                },
+                ReplCommand::HttpPostInv { mut inventory } => {
+                    if book.banned(&inventory.from) {
+                        let _ = tz.send(Err(ApiError::new(403, format!("{} is banned", inventory.from))));
+                        continue;
+                    }
+                    // We only fetch from our nodes we shook hands with, not
+                    // wherever we are told to
+                    if !book.handshaken(&inventory.from) {
+                        let message = format!("{} is not a node we shook hands with", inventory.from);
+                        let _ = tz.send(Err(ApiError::new(403, message)));
+                        continue;
+                    }
+                    // Leave out whatever we have already
+                    inventory.blocks.retain(|hash| !blockchain.tree.contains(hash));
+                    inventory.transactions.retain(|txid| blockchain.pending_transaction(txid).is_none());
+                    let wanted = gossip.receive(inventory);
+                    let fetching = api::Fetching {
+                        blocks: wanted.blocks.len(),
+                        transactions: wanted.transactions.len(),
+                    };
+                    let _ = tz.send(Ok(serde_json::to_string(&fetching).unwrap()));
+                },
+                ReplCommand::GossipBlock { id, block, from } => {
+                    let index = block.index;
+                    let hash = Blockchain::hash(&block);
+                    if hash != id {
+                        gossip.fetched(&id, false);
+                        let error = PeerError::Misbehaved(format!("sent block {} when asked for {}", hash, id));
+                        println!("Daemon: {} {}.", from, error);
+                        if peer_failed(&mut book, &from, &error, blockchain.clock.now()) {
+                            book.to_file("peers.json".to_owned());
+                        }
+                        continue;
+                    }
+                    let status = blockchain.accept_block(block);
+                    println!("Daemon: received block {} from {}: {:?}", index, from, status);
+                    // A bad body may be the sender's doing: the next node
+                    // announcing the block gets asked for it
+                    let seen = match status {
+                        BlockStatus::Orphan => false,
+                        BlockStatus::Invalid(ref rule) => !rule.body(),
+                        _ => true,
+                    };
+                    gossip.fetched(&hash, seen);
+                    match status {
+                        BlockStatus::Connected | BlockStatus::SideChain =>
+                            announce(&mut gossip, &book, &p2p, vec![hash], vec![], Some(&from)),
+                        // Ask for the blocks leading up to it
+                        BlockStatus::Orphan => gossip.fetch_ancestors(&from, sync::locator(&blockchain.chain)),
+                        BlockStatus::Invalid(rule) => {
+                            if punish(&mut book, &from, Misbehaviour::from_rule(&rule), blockchain.clock.now()) {
+                                book.to_file("peers.json".to_owned());
+                            }
+                        },
+                        BlockStatus::Duplicate => {},
+                    }
+                },
+                ReplCommand::GossipAncestors { blocks, from } => {
+                    gossip.fetched_ancestors(&from);
+                    let mut accepted = vec![];
+                    for block in blocks {
+                        let hash = Blockchain::hash(&block);
+                        match blockchain.accept_block(block) {
+                            BlockStatus::Connected | BlockStatus::SideChain => accepted.push(hash),
+                            BlockStatus::Invalid(rule) => {
+                                if punish(&mut book, &from, Misbehaviour::from_rule(&rule), blockchain.clock.now()) {
+                                    book.to_file("peers.json".to_owned());
+                                }
+                                break;
+                            },
+                            _ => {},
+                        }
+                    }
+                    if !accepted.is_empty() {
+                        println!("Daemon: received {} block(s) from {}.", accepted.len(), from);
+                        for hash in &accepted {
+                            gossip.fetched(hash, true);
+                        }
+                        announce_tip(&mut gossip, &blockchain, &book, &p2p);
+                    }
+                },
+                ReplCommand::GossipFailed { id } => {
+                    gossip.fetched(&id, false);
+                },
+                ReplCommand::GossipPeers { peers, from } => {
+                    book.seen(&from, blockchain.clock.now());
+                    let learned = peers.iter()
+                        .filter(|peer| **peer != local_url && book.add(peer))
+                        .count();
+                    if learned > 0 {
+                        println!("Daemon: learned {} peer(s) from {}.", learned, from);
+                        book.top_up(&local_url);
+                    }
+                    book.to_file("peers.json".to_owned());
+                },
+                ReplCommand::ExchangePeers => {
+                    book.expire_bans(blockchain.clock.now());
+                    book.top_up(&local_url);
+                    gossip.ask_peers(&book.registered(), blockchain.handshake());
+                },
+                ReplCommand::NodeReached { node, height, latency } => {
+                    book.reached(&node, blockchain.clock.now(), height, latency);
+                },
+                ReplCommand::NodeShookHands { node, handshake, latency } => {
+                    book.reached(&node, blockchain.clock.now(), Some(handshake.height), Some(latency));
+                    let mismatch = handshake.check(&blockchain.handshake()).err();
+                    if let Some(ref mismatch) = mismatch {
+                        println!("Daemon: not syncing with {}: it is {}.", node, mismatch);
+                    }
+                    book.shook_hands(&node, handshake, mismatch);
+                    book.to_file("peers.json".to_owned());
+                },
+                ReplCommand::NodeFailed { node, error } => {
+                    if peer_failed(&mut book, &node, &error, blockchain.clock.now()) {
+                        book.to_file("peers.json".to_owned());
+                    }
+                },
+                ReplCommand::P2pConnected { peer, height } => {
+                    println!("Daemon: P2P peer {} connected, with {} blocks.", peer, height);
+                    p2p_connected(&blockchain, &book, &p2p, peer, height);
+                },
+                ReplCommand::P2pMessage { peer, message } => {
+                    let ip = p2p.ip(peer);
+                    let (blocks, transactions) = handle_p2p(&mut blockchain, &mut book, &p2p, peer, message);
+                    if ip.map_or(false, |ip| book.banned(&ip)) {
+                        book.to_file("peers.json".to_owned());
+                    }
+                    if !blocks.is_empty() || !transactions.is_empty() {
+                        gossip.announce(&book.nodes(), blocks, transactions, None);
+                    }
+                },
+                ReplCommand::P2pDisconnected { peer } => {
+                    println!("Daemon: P2P peer {} disconnected.", peer);
+                },
+                ReplCommand::ConnectPeer { address } => {
+                    p2p.connect(&address);
+                    let _ = ty.send(Ok("CONNECTING".to_owned()));
+                },
+                ReplCommand::HttpGetPeers => {
+                    let good = gossip::Peers { peers: book.good(blockchain.clock.now()) };
+                    let _ = tz.send(Ok(serde_json::to_string(&good).unwrap()));
+                },
+                ReplCommand::GossipTransaction { id, transaction, from } => {
+                    let txid = transaction.id();
+                    if txid != id {
+                        gossip.fetched(&id, false);
+                        let error = PeerError::Misbehaved(format!("sent transaction {} when asked for {}", txid, id));
+                        println!("Daemon: {} {}.", from, error);
+                        if peer_failed(&mut book, &from, &error, blockchain.clock.now()) {
+                            book.to_file("peers.json".to_owned());
+                        }
+                        continue;
+                    }
+                    gossip.fetched(&txid, true);
+                    if blockchain.admit_transaction(transaction).is_ok() {
+                        announce(&mut gossip, &book, &p2p, vec![], vec![txid], Some(&from));
+                    }
                 },
                 _ => {
                     let _ = ty.send(Err("DAEMON NOT IMPLEMENTED".to_owned()));
@@ -714,19 +2667,53 @@ fn main() {
     // so I could use the variables above, specially the channels, so I'm just cutting the
     // crap here and doing a lightweight solution.
     // Clients, though, are going to use hyper, since I faced some problems with reqwest.
-    println!("Starting server on port {}", node_port);
+    println!("Starting HTTP server on {}:{}", bind, node_port);
+    let server = match tiny_http::Server::new(tiny_http::ServerConfig {
+        addr: format!("{}:{}", bind, node_port),
+        ssl: None,
+    }) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Cannot start the HTTP server: {}", e);
+            return;
+        },
+    };
     let server = thread::spawn(move || {
-        let server = tiny_http::Server::http(format!("127.0.0.1:{}", node_port)).unwrap();
         loop {
             match server.recv() {
-                Ok(req) => {
-                    if req.method() == &Method::Get && req.url() == "/chain" { // curl -X GET "http://127.0.0.1:3000/chain"
-                        let _ = txhttp.send(ReplCommand::HttpGetChain);
-                        let response = tiny_http::Response::from_string(rz.recv().unwrap());
-                        let _ = req.respond(response);
-                    } else {
-                        let _ = req.respond(tiny_http::Response::empty(404));
+                Ok(mut req) => {
+                    let authorization = req.headers().iter()
+                        .find(|header| header.field.equiv("Authorization"))
+                        .map(|header| header.value.as_str().to_owned());
+                    // Nothing is read before the request is authorized, and
+                    // only POST endpoints take a body, up to a limit
+                    let body = auth.check(req.method(), req.url(), authorization.as_ref().map(|a| a.as_str()))
+                        .and_then(|_| match api::takes_body(req.method(), req.url()) {
+                            true => api::read_body(req.as_reader(), max_body),
+                            false => Ok(String::new()),
+                        });
+                    let answer = body.and_then(|body| api::route(req.method(), req.url(), &body));
+                    let answer = match answer {
+                        Ok((command, status)) => {
+                            let _ = txhttp.send(command);
+                            rz.recv().unwrap().map(|body| (status, body))
+                        },
+                        Err(error) => Err(error),
+                    };
+                    let (status, body) = match answer {
+                        Ok(answer) => answer,
+                        Err(error) => (error.status, error.body()),
+                    };
+                    let json = tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .unwrap();
+                    let mut response = tiny_http::Response::from_string(body)
+                        .with_status_code(status)
+                        .with_header(json);
+                    if status == 401 {
+                        response.add_header(tiny_http::Header::from_bytes(&b"WWW-Authenticate"[..],
+                                                                          auth.challenge().as_bytes()).unwrap());
                     }
+                    let _ = req.respond(response);
                 },
                 Err(_) => {
                     // Something here shouldn't have happened. Hmmm.
@@ -797,8 +2784,10 @@ fn main() {
                                             match Url::parse(url.as_ref()) {
                                                 Ok(_) => {
                                                     let _ = tx.send(ReplCommand::RegNode { url: url.clone() });
-                                                    let _ = ry.recv().unwrap();
-                                                    println!("Node registered successfully.");
+                                                    match ry.recv().unwrap() {
+                                                        Ok(_) => println!("Node registered successfully."),
+                                                        Err(_) => println!("{} is banned. Use `node unban` first.", url),
+                                                    }
                                                 },
                                                 Err(_) => {
                                                     println!("Please provide a valid URL.");
@@ -806,9 +2795,45 @@ fn main() {
                                             }
                                         }
                                     },
+                                    "del" => {
+                                        if args.len() != 2 {
+                                            println!("Please specify an address for the node.");
+                                        } else {
+                                            let _ = tx.send(ReplCommand::DelNode { url: args[1].to_owned() });
+                                            match ry.recv().unwrap() {
+                                                Ok(_) => println!("Node deleted successfully."),
+                                                Err(_) => println!("{} is not a registered node.", args[1]),
+                                            }
+                                        }
+                                    },
                                     "show" => {
-                                        // Show registered nodes. Ask daemon to do that.
-                                        println!("Not Implemented");
+                                        // Daemon prints them, along with how they are doing
+                                        let _ = tx.send(ReplCommand::ShowNodes);
+                                        let _ = ry.recv().unwrap();
+                                    },
+                                    "connect" => {
+                                        if args.len() != 2 {
+                                            println!("Please specify an address like 127.0.0.1:4000.");
+                                        } else {
+                                            let _ = tx.send(ReplCommand::ConnectPeer { address: args[1].to_owned() });
+                                            let _ = ry.recv().unwrap();
+                                            println!("Connecting to {}...", args[1]);
+                                        }
+                                    },
+                                    "bans" => {
+                                        let _ = tx.send(ReplCommand::ShowBans);
+                                        let _ = ry.recv().unwrap();
+                                    },
+                                    "unban" => {
+                                        if args.len() != 2 {
+                                            println!("Please specify an address for the node.");
+                                        } else {
+                                            let _ = tx.send(ReplCommand::Unban { url: args[1].to_owned() });
+                                            match ry.recv().unwrap() {
+                                                Ok(_) => println!("Node unbanned successfully."),
+                                                Err(_) => println!("{} is not banned.", args[1]),
+                                            }
+                                        }
                                     },
                                     _ => println!("Unknown subcommand for \"node\"."),
                                 }
@@ -853,6 +2878,74 @@ fn main() {
                             let _ = tx.send(ReplCommand::Resolve);
                             println!("Resolving finished. Daemon response: {}", ry.recv().unwrap().unwrap());
                         },
+                        "tips" => {
+                            let _ = tx.send(ReplCommand::ShowTips);
+                            match ry.recv().unwrap() {
+                                Ok(status) => println!("{}", status),
+                                Err(status) => println!("Error: {}", status),
+                            };
+                        },
+                        "signer" => {
+                            if args.len() < 1 {
+                                println!("Please specify what to do with signers.");
+                            } else {
+                                let arg0 = String::from(args[0]).to_lowercase();
+                                let command = match arg0.as_ref() {
+                                    "show" => Some(ReplCommand::ShowSigners),
+                                    "add" | "remove" | "discard" => {
+                                        if args.len() != 2 {
+                                            println!("Please specify the signer's public key.");
+                                            None
+                                        } else {
+                                            let candidate = String::from(args[1]);
+                                            match arg0.as_ref() {
+                                                "discard" => Some(ReplCommand::Discard { candidate: candidate }),
+                                                _ => Some(ReplCommand::Propose {
+                                                    candidate: candidate,
+                                                    authorize: arg0 == "add",
+                                                }),
+                                            }
+                                        }
+                                    },
+                                    _ => {
+                                        println!("Unknown subcommand for \"signer\".");
+                                        None
+                                    },
+                                };
+
+                                if let Some(command) = command {
+                                    let _ = tx.send(command);
+                                    match ry.recv().unwrap() {
+                                        Ok(status) => println!("{}", status),
+                                        Err(status) => println!("Error: {}", status),
+                                    };
+                                }
+                            }
+                        },
+                        "stake" => {
+                            let command = match args.len() {
+                                1 if args[0].to_lowercase() == "show" => Some(ReplCommand::ShowStakes),
+                                1 => match args[0].parse::<i64>() {
+                                    Ok(amount) if amount > 0 => Some(ReplCommand::Stake { amount: amount }),
+                                    _ => {
+                                        println!("Please specify a positive amount to stake.");
+                                        None
+                                    },
+                                },
+                                _ => {
+                                    println!("Please specify an amount to stake, or \"show\".");
+                                    None
+                                },
+                            };
+
+                            if let Some(command) = command {
+                                let _ = tx.send(command);
+                                match ry.recv().unwrap() {
+                                    Ok(status) => println!("{}", status),
+                                    Err(status) => println!("Error: {}", status),
+                                };
+                            }
+                        },
                         //"send" => {},
                         "save" => {
                             let mut filename = None;
@@ -1004,12 +3097,50 @@ fn main() {
 // Tests
 #[test]
 fn proof_of_work() {
-    let mut blockchain = Blockchain::new();
+    let blockchain = Blockchain::new();
+    let engine = consensus::ProofOfWork::new(consensus::PowHash::Sha256,
+                                             consensus::pow::DEFAULT_DIFFICULTY);
+    let header = blockchain.chain.last().unwrap().header();
     println!("First proof of work: {}",
-             blockchain.proof_of_work(blockchain
-                                      .chain
-                                      .last().unwrap()
-                                      .proof));
+             engine.proof_of_work(&consensus::pow::work_hash(&header)));
+}
+
+#[test]
+fn pow_hashes() {
+    // A chain mined with scrypt is valid for scrypt
+    let scrypt: Arc<dyn ConsensusEngine> = Arc::new(
+        consensus::ProofOfWork::new(consensus::PowHash::Scrypt, 1));
+    let sha256: Arc<dyn ConsensusEngine> = Arc::new(
+        consensus::ProofOfWork::new(consensus::PowHash::Sha256, 1));
+    let mut blockchain = Blockchain::new();
+    blockchain.engine = scrypt.clone();
+    for _ in 0..4 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+
+    // But not for sha256, and the other way around
+    blockchain.engine = sha256.clone();
+    assert!(blockchain.valid_chain(&blockchain.chain).is_err());
+    let mut other = Blockchain::new();
+    other.engine = sha256;
+    for _ in 0..4 {
+        other.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    assert!(other.valid_chain(&other.chain).is_ok());
+    other.engine = scrypt;
+    assert!(other.valid_chain(&other.chain).is_err());
+
+    // Difficulty must be something a hash can meet
+    let mut config = NodeConfig::default();
+    config.network = "custom".to_owned();
+    for &difficulty in &[0, consensus::pow::MAX_DIFFICULTY + 1] {
+        config.genesis = Some(GenesisConfig { pow_difficulty: difficulty, ..GenesisConfig::default() });
+        assert!(network::from_config(&config).is_err());
+    }
+    config.genesis = Some(GenesisConfig { pow_difficulty: consensus::pow::MAX_DIFFICULTY,
+                                          ..GenesisConfig::default() });
+    assert!(network::from_config(&config).is_ok());
 }
 
 #[test]
@@ -1021,18 +3152,18 @@ fn serialize_deserialize() {
     let friend = Blockchain::new_identifier();
     
     for _ in 0..3 {
-        blockchain.mine_block(node.clone());
+        blockchain.mine_block(node.clone()).unwrap();
     }
     blockchain.new_transaction(node.clone(),
                                friend.clone(),
-                               1);
+                               1).unwrap();
     blockchain.new_transaction(node.clone(),
                                friend.clone(),
-                               2);
+                               2).unwrap();
     blockchain.new_transaction(friend.clone(),
                                node.clone(),
-                               1);
-    blockchain.mine_block(node.clone());
+                               1).unwrap();
+    blockchain.mine_block(node.clone()).unwrap();
 
     // Serialize to string, then reverse it, then serialize
     // the deserialized
@@ -1088,7 +3219,7 @@ fn wallet_gen() {
     // Mine seven blocks for first address
     for i in 0..7 {
         println!("Mining block #{}, rewarding $1 to address #1", i + 1);
-        blockchain.mine_block(wallet.addresses[0].clone());
+        blockchain.mine_block(wallet.addresses[0].clone()).unwrap();
     }
 
     // First address is generous and will give 1 currency to other addresses
@@ -1096,18 +3227,18 @@ fn wallet_gen() {
         println!("Address #1 will send 1 currency to address #{}", i + 1);
         blockchain.new_transaction(wallet.addresses[0].clone(),
                                    wallet.addresses[i].clone(),
-                                   1);
+                                   1).unwrap();
     }
 
     // First address is specially fond of address #3 and will give it two more
     println!("Address #1 will send 1 currency to address #3");
     blockchain.new_transaction(wallet.addresses[0].clone(),
                                wallet.addresses[2].clone(),
-                               1);
+                               1).unwrap();
 
     // Mine a new block to confirm changes
     println!("Mining new block for #1 to confirm previous transactions...");
-    blockchain.mine_block(wallet.addresses[0].clone());
+    blockchain.mine_block(wallet.addresses[0].clone()).unwrap();
 
     // Checking balance...
     wallet.calculate_balances(&blockchain.chain);
@@ -1133,3 +3264,1276 @@ fn wallet_gen() {
                  transaction.sender, transaction.recipient, transaction.amount);
     }
 }
+
+#[test]
+fn consensus_engine() {
+    // The default engine is the hashcash-style proof of work
+    let config = NodeConfig::default();
+    let mut network = network::from_config(&config).unwrap();
+    let engine = consensus::from_config(&config, &network)
+        .expect("Default consensus engine not found");
+    assert_eq!(engine.name(), "pow");
+    network.consensus = "nonexistent".to_owned();
+    assert!(consensus::from_config(&config, &network).is_err());
+
+    // Blocks sealed by the engine must be accepted by it,
+    // and a tampered proof must not
+    let mut blockchain = Blockchain::new();
+    blockchain.engine = engine;
+    for _ in 0..2 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+
+    let mut tampered = blockchain.chain.clone();
+    tampered[2].proof += 1;
+    assert!(blockchain.valid_chain(&tampered).is_err());
+
+    // The proof covers the header, so it can't be reused on a block
+    // with other transactions, even when relinked
+    let mut tampered = blockchain.chain.clone();
+    tampered[1].transactions[0].recipient = Blockchain::new_identifier();
+    tampered[1].merkle_root = Blockchain::merkle_root(&tampered[1].transactions);
+    tampered[2].previous_hash = Blockchain::hash(&tampered[1]);
+    assert!(blockchain.valid_chain(&tampered).is_err());
+}
+
+#[test]
+fn proof_of_authority() {
+    // Two signers take turns producing blocks; a third one waits
+    // to be voted in
+    let keys = vec![keys::SigningKey::generate(),
+                    keys::SigningKey::generate(),
+                    keys::SigningKey::generate()];
+    let genesis_signers = vec![keys[0].public_key(), keys[1].public_key()];
+    let engines: Vec<Arc<dyn ConsensusEngine>> = keys.iter()
+        .map(|key| {
+            let engine = consensus::ProofOfAuthority::new(genesis_signers.clone(), key.clone())
+                .expect("Unable to create proof of authority engine");
+            Arc::new(engine) as Arc<dyn ConsensusEngine>
+        })
+        .collect();
+
+    // Block 2 belongs to whoever comes first on the sorted signer list
+    let mut sorted = genesis_signers.clone();
+    sorted.sort();
+    let first = if sorted[0] == genesis_signers[0] { 0 } else { 1 };
+    let second = 1 - first;
+
+    let mut blockchain = Blockchain::new();
+    blockchain.engine = engines[second].clone();
+    assert!(blockchain.mine_block("miner".to_owned()).is_err());
+    assert!(blockchain.current_transactions.is_empty());
+
+    // Both signers vote for the third one, which needs both votes
+    for engine in &engines[..2] {
+        engine.propose(keys[2].public_key(), true).unwrap();
+    }
+    for i in 0..2 {
+        blockchain.engine = engines[[first, second][i]].clone();
+        blockchain.mine_block("miner".to_owned()).unwrap();
+        assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    }
+    let signers = blockchain.engine.signers(&blockchain.chain).unwrap();
+    assert_eq!(signers.len(), 3);
+    assert!(signers.contains(&keys[2].public_key()));
+
+    // A block signed by someone else is invalid
+    let mut forged = blockchain.chain.clone();
+    forged[2].signature = keys[2].sign(Blockchain::seal_hash(&forged[2].header()).as_bytes());
+    assert!(blockchain.valid_chain(&forged).is_err());
+
+    // So is one properly signed by an authorized signer out of its turn
+    let mut out_of_turn = blockchain.chain.clone();
+    out_of_turn[1].signer = keys[second].public_key();
+    out_of_turn[1].signature = keys[second].sign(Blockchain::seal_hash(&out_of_turn[1].header()).as_bytes());
+    assert!(keys::verify(&out_of_turn[1].signer, Blockchain::seal_hash(&out_of_turn[1].header()).as_bytes(),
+                         &out_of_turn[1].signature));
+    assert!(blockchain.valid_chain(&out_of_turn).is_err());
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+}
+
+#[test]
+fn proof_of_stake() {
+    use std::collections::BTreeMap;
+
+    let keys = vec![keys::SigningKey::generate(), keys::SigningKey::generate()];
+    let mut stakes = BTreeMap::new();
+    stakes.insert(keys[0].public_key(), 10);
+    stakes.insert(keys[1].public_key(), 10);
+    let engines: Vec<Arc<dyn ConsensusEngine>> = keys.iter()
+        .map(|key| {
+            let engine = consensus::ProofOfStake::new(stakes.clone(), key.clone())
+                .expect("Unable to create proof of stake engine");
+            Arc::new(engine) as Arc<dyn ConsensusEngine>
+        })
+        .collect();
+
+    // Only the elected proposer is able to produce each block
+    let mut blockchain = Blockchain::new();
+    for _ in 0..4 {
+        let mut produced = vec![];
+        for engine in &engines {
+            let mut attempt = blockchain.clone();
+            attempt.engine = engine.clone();
+            if attempt.mine_block(keys[0].public_key()).is_ok() {
+                produced.push(attempt);
+            }
+        }
+        assert_eq!(produced.len(), 1);
+        blockchain = produced.pop().unwrap();
+        assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    }
+
+    // Locked stake shows up on the stake table once mined
+    let address = blockchain.engine.stake_address().unwrap().to_owned();
+    blockchain.new_transaction(keys[0].public_key(), address.clone(), 3).unwrap();
+    while blockchain.current_transactions.len() > 0 {
+        for engine in &engines {
+            blockchain.engine = engine.clone();
+            let _ = blockchain.mine_block(keys[0].public_key());
+        }
+    }
+    let table = blockchain.engine.stakes(&blockchain.chain).unwrap();
+    assert!(table.contains(&(keys[0].public_key(), 13)));
+
+    // The last proposer signs a competing block for the same slot
+    let mut competing = blockchain.chain.clone();
+    {
+        let last = competing.last_mut().unwrap();
+        let producer = if last.signer == keys[0].public_key() { &keys[0] } else { &keys[1] };
+        last.transactions.clear();
+        last.merkle_root = Blockchain::merkle_root(&last.transactions);
+        last.signature = producer.sign(Blockchain::seal_hash(&last.header()).as_bytes());
+    }
+    let offender = competing.last().unwrap().signer.clone();
+    blockchain.collect_evidence(&competing);
+    assert_eq!(blockchain.pending_evidence.len(), 1);
+
+    // Once evidence is mined, the offender loses its stake
+    while blockchain.pending_evidence.len() > 0 {
+        for engine in &engines {
+            blockchain.engine = engine.clone();
+            let _ = blockchain.mine_block(keys[0].public_key());
+        }
+    }
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    let validators = blockchain.engine.signers(&blockchain.chain).unwrap();
+    assert_eq!(validators.len(), 1);
+    assert!(!validators.contains(&offender));
+
+    // A forged body under a real header doesn't leave its stakes behind
+    let mut verifier = blockchain.clone();
+    verifier.engine = Arc::new(consensus::ProofOfStake::new(stakes.clone(), keys[0].clone()).unwrap());
+    let mut forged = blockchain.chain.clone();
+    forged[2].transactions.push(Transaction {
+        sender: keys::SigningKey::generate().public_key(),
+        recipient: address.clone(),
+        amount: 1_000_000,
+        nonce: Transaction::new_nonce(),
+    });
+    assert_eq!(Blockchain::hash(&forged[2]), Blockchain::hash(&blockchain.chain[2]));
+    assert!(verifier.valid_chain(&forged).is_err());
+    assert!(verifier.valid_chain(&blockchain.chain).is_ok());
+}
+
+// Seals a block of chain again after tampering with it, so that only
+// what was tampered with is wrong with it.
+#[cfg(test)]
+fn reseal(blockchain: &Blockchain, chain: &mut [Block], index: usize) {
+    let (before, after) = chain.split_at_mut(index);
+    blockchain.engine.seal(before, &mut after[0]).unwrap();
+}
+
+#[test]
+fn block_timestamps() {
+    let clock = Arc::new(clock::FixedClock::new(1_500_000_000));
+    let mut blockchain = Blockchain::with_genesis(&GenesisConfig {
+        timestamp: clock.now(),
+        ..GenesisConfig::default()
+    });
+    blockchain.clock = clock.clone();
+
+    // Blocks mined within the same second are stamped past the median
+    // of the last blocks, so the chain stays valid
+    for _ in 0..15 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+
+    // A block not newer than the median of the last 11 blocks is rejected
+    let mut old = blockchain.chain.clone();
+    let median = Blockchain::median_time_past(&old[..15]);
+    old[15].timestamp = median;
+    reseal(&blockchain, &mut old, 15);
+    assert!(blockchain.valid_chain(&old).is_err());
+
+    // So is a block too far ahead of our clock...
+    let mut future = blockchain.chain.clone();
+    future[15].timestamp = clock.now() + MAX_FUTURE_BLOCK_TIME + 1;
+    reseal(&blockchain, &mut future, 15);
+    assert!(blockchain.valid_chain(&future).is_err());
+
+    // ...until our clock catches up
+    clock.set(clock.now() + MAX_FUTURE_BLOCK_TIME);
+    assert!(blockchain.valid_chain(&future).is_ok());
+}
+
+#[test]
+fn merkle_root() {
+    let node = Blockchain::new_identifier();
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert(node.clone(), 10);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    for amount in 1..4 {
+        blockchain.new_transaction(node.clone(), Blockchain::new_identifier(), amount).unwrap();
+    }
+    blockchain.mine_block(node.clone()).unwrap();
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+
+    // An odd number of leaves pairs the last one with itself
+    let ids: Vec<String> = blockchain.chain[1].transactions.iter()
+        .map(|transaction| transaction.id())
+        .collect();
+    assert_eq!(ids.len(), 4);
+    let expected = merkle::parent(&merkle::parent(&ids[0], &ids[1]),
+                                  &merkle::parent(&ids[2], &ids[3]));
+    assert_eq!(blockchain.chain[1].merkle_root, expected);
+    assert_eq!(merkle::root(&ids[..3]),
+               merkle::parent(&merkle::parent(&ids[0], &ids[1]),
+                              &merkle::parent(&ids[2], &ids[2])));
+
+    // The block hash covers the transactions only through the root,
+    // so tampering with them is caught by checking the root
+    let mut tampered = blockchain.chain.clone();
+    let hash = Blockchain::hash(&tampered[1]);
+    tampered[1].transactions[0].amount = 1000;
+    assert_eq!(Blockchain::hash(&tampered[1]), hash);
+    assert!(blockchain.valid_chain(&tampered).is_err());
+
+    // Chains saved before blocks had a root get it back on load
+    let mut saved = serde_json::to_value(&blockchain).unwrap();
+    for block in saved["chain"].as_array_mut().unwrap() {
+        block.as_object_mut().unwrap().remove("merkle_root");
+    }
+    let loaded = Blockchain::from_str(&saved.to_string(), &genesis);
+    assert_eq!(loaded.chain.len(), 2);
+    assert_eq!(loaded.chain[1].merkle_root, expected);
+    assert!(loaded.valid_chain(&loaded.chain).is_ok());
+
+    // Repeating the last transaction of a block with an odd number of
+    // them keeps its root, but repeats are refused before the root counts
+    for _ in 0..2 {
+        blockchain.new_transaction(node.clone(), Blockchain::new_identifier(), 1).unwrap();
+    }
+    blockchain.mine_block(node.clone()).unwrap();
+    let mut repeated = blockchain.chain[2].clone();
+    assert_eq!(repeated.transactions.len(), 3);
+    let last = repeated.transactions[2].clone();
+    repeated.transactions.push(last);
+    assert_eq!(Blockchain::merkle_root(&repeated.transactions), repeated.merkle_root);
+    assert_eq!(blockchain.check_body(&repeated), Err(BlockRule::Duplicate));
+    assert!(blockchain.check_body(&blockchain.chain[2]).is_ok());
+}
+
+#[test]
+fn canonical_encoding() {
+    let transaction = Transaction {
+        sender: "0".to_owned(),
+        recipient: "ab".to_owned(),
+        amount: -2,
+        nonce: 3,
+    };
+    assert_eq!(encoding::to_bytes(&transaction),
+               vec![2,
+                    0, 0, 0, 0, 0, 0, 0, 1, b'0',
+                    0, 0, 0, 0, 0, 0, 0, 2, b'a', b'b',
+                    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+                    0, 0, 0, 0, 0, 0, 0, 3]);
+
+    // However the JSON looks, the hash stays the same
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    let header = blockchain.chain[1].header();
+    let json = format!("{{ \"previous_hash\" : \"{}\", \"proof\":{}, \"merkle_root\": \"{}\",\n\
+                        \"timestamp\": {},   \"index\": {} }}",
+                       header.previous_hash, header.proof, header.merkle_root,
+                       header.timestamp, header.index);
+    let reordered: BlockHeader = serde_json::from_str(&json).unwrap();
+    assert_eq!(Blockchain::hash_header(&reordered), Blockchain::hash(&blockchain.chain[1]));
+}
+
+#[test]
+fn chain_reorganization() {
+    let payer = Blockchain::new_identifier();
+    let payee = Blockchain::new_identifier();
+    let mut blockchain = Blockchain::new();
+    for _ in 0..4 {
+        blockchain.mine_block(payer.clone()).unwrap();
+    }
+
+    // Another node builds a longer branch from here, confirming a payment
+    let mut other = blockchain.clone();
+    other.new_transaction(payer.clone(), payee.clone(), 1).unwrap();
+    let confirmed = other.current_transactions[0].clone();
+    for _ in 0..2 {
+        other.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+
+    // Meanwhile, we confirm another payment, and have their payment, an
+    // unfunded one and one just like theirs (but another payment, with a
+    // nonce of its own) waiting on the mempool
+    blockchain.new_transaction(payer.clone(), Blockchain::new_identifier(), 1).unwrap();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    blockchain.current_transactions.push(confirmed.clone());
+    assert!(blockchain.new_transaction(payee.clone(), payer.clone(), 5).is_err());
+    blockchain.current_transactions.push(Transaction {
+        sender: payee.clone(),
+        recipient: payer.clone(),
+        amount: 5,
+        nonce: Transaction::new_nonce(),
+    });
+    blockchain.new_transaction(payer.clone(), payee.clone(), 1).unwrap();
+
+    assert!(blockchain.valid_chain(&other.chain).is_ok());
+    blockchain.reorganize(other.chain.clone());
+    assert_eq!(blockchain.events, vec![ChainEvent::Reorg {
+        fork_index: 5,
+        depth: 1,
+        restored: 1,
+        dropped: 2,
+    }]);
+
+    // Our payment is back to the mempool, along with the one like theirs;
+    // the one they confirmed and the unfunded one are gone
+    assert_eq!(Blockchain::hash(blockchain.chain.last().unwrap()),
+               Blockchain::hash(other.chain.last().unwrap()));
+    assert_eq!(blockchain.current_transactions.len(), 2);
+    assert_eq!(blockchain.current_transactions[0].sender, payer);
+    assert!(blockchain.current_transactions[0].recipient != payee);
+    assert_eq!(blockchain.current_transactions[1].recipient, payee);
+    assert!(blockchain.current_transactions[1].id() != confirmed.id());
+}
+
+#[test]
+fn block_tree() {
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+
+    // Two other nodes branch off from here: one a block ahead of us...
+    let mut short = blockchain.clone();
+    short.mine_block(Blockchain::new_identifier()).unwrap();
+    // ...and another two blocks ahead
+    let mut long = blockchain.clone();
+    for _ in 0..3 {
+        long.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+
+    // Same work as ours, so it stays on a side chain
+    assert_eq!(blockchain.accept_block(short.chain[2].clone()), BlockStatus::SideChain);
+    assert_eq!(blockchain.accept_block(short.chain[2].clone()), BlockStatus::Duplicate);
+
+    // Out of order blocks wait for their parents
+    assert_eq!(blockchain.accept_block(long.chain[4].clone()), BlockStatus::Orphan);
+    assert_eq!(blockchain.accept_block(long.chain[3].clone()), BlockStatus::Orphan);
+    assert_eq!(blockchain.tree.orphan_count(), 2);
+
+    // A broken block is refused
+    let mut forged = long.chain[2].clone();
+    forged.proof += 1;
+    assert_eq!(blockchain.accept_block(forged), BlockStatus::Invalid(BlockRule::Consensus));
+    assert_eq!(blockchain.chain.len(), 3);
+
+    // Once the parent arrives, the heavier branch takes over
+    assert_eq!(blockchain.accept_block(long.chain[2].clone()), BlockStatus::Connected);
+    assert_eq!(blockchain.tree.orphan_count(), 0);
+    assert_eq!(Blockchain::hash(blockchain.chain.last().unwrap()),
+               Blockchain::hash(long.chain.last().unwrap()));
+    assert_eq!(blockchain.events.len(), 1);
+
+    let tips = blockchain.tree.tips();
+    assert_eq!(tips.len(), 3);
+    assert_eq!(tips[0].hash, Blockchain::hash(&long.chain[4]));
+    assert!(tips[0].work > tips[1].work);
+
+    // Blocks extending our chain are checked against our state, which
+    // followed the switch
+    let replayed = ChainState::from_chain(&blockchain.chain);
+    assert_eq!(blockchain.state.txids, replayed.txids);
+    assert!(replayed.balances.iter().all(|(address, balance)| blockchain.state.balances.get(address) == Some(balance)));
+    long.mine_block(Blockchain::new_identifier()).unwrap();
+    let mut overspent = long.chain.clone();
+    overspent[5].transactions.push(Transaction {
+        sender: long.chain[1].transactions[0].recipient.clone(),
+        recipient: Blockchain::new_identifier(),
+        amount: 2,
+        nonce: 0,
+    });
+    overspent[5].merkle_root = Blockchain::merkle_root(&overspent[5].transactions);
+    reseal(&long, &mut overspent, 5);
+    assert_eq!(blockchain.accept_block(overspent[5].clone()), BlockStatus::Invalid(BlockRule::Overspend));
+    assert_eq!(blockchain.accept_block(long.chain[5].clone()), BlockStatus::Connected);
+    assert_eq!(blockchain.state.txids, ChainState::from_chain(&blockchain.chain).txids);
+}
+
+#[test]
+fn checkpoints() {
+    let mut blockchain = Blockchain::new();
+    for _ in 0..3 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    let mut rewrite = blockchain.clone();
+    rewrite.chain.truncate(2);
+    rewrite.rebuild_tree();
+    for _ in 0..3 {
+        rewrite.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    assert!(blockchain.valid_chain(&rewrite.chain).is_ok());
+
+    // Once block 3 is checkpointed, history before it can't be rewritten
+    let hash = Blockchain::hash(&blockchain.chain[2]);
+    blockchain.checkpoints.insert(3, hash.clone());
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    assert!(blockchain.valid_chain(&rewrite.chain).is_err());
+    assert_eq!(blockchain.accept_block(rewrite.chain[2].clone()),
+               BlockStatus::Invalid(BlockRule::Checkpoint));
+
+    // Proofs leading up to the checkpoint are not checked again,
+    // but those after it are
+    blockchain.engine = Arc::new(consensus::ProofOfWork::new(consensus::PowHash::Sha256, 64));
+    assert!(blockchain.valid_chain(&blockchain.chain[..3].to_vec()).is_ok());
+    assert!(blockchain.valid_chain(&blockchain.chain).is_err());
+
+    // Checkpoints must be well-formed and agree with each other
+    let mut config = NodeConfig::default();
+    let network = network::from_config(&config).unwrap();
+    config.checkpoints.push(checkpoints::Checkpoint { index: 3, hash: hash.clone() });
+    assert_eq!(checkpoints::from_config(&config, &network).unwrap().get(&3), Some(&hash));
+    config.checkpoints.push(checkpoints::Checkpoint { index: 3, hash: "00".repeat(32) });
+    assert!(checkpoints::from_config(&config, &network).is_err());
+    config.checkpoints = vec![checkpoints::Checkpoint { index: 0, hash: hash.clone() }];
+    assert!(checkpoints::from_config(&config, &network).is_err());
+
+    // Built-in networks pin their genesis block
+    for name in &["main", "test"] {
+        let mut config = NodeConfig::default();
+        config.network = name.to_string();
+        let network = network::from_config(&config).unwrap();
+        let genesis = Blockchain::hash(&Blockchain::genesis_block(&network.genesis));
+        assert_eq!(checkpoints::from_config(&config, &network).unwrap().get(&1), Some(&genesis));
+    }
+
+    // So chains starting from another genesis contradict a checkpoint
+    let config = NodeConfig::default();
+    let mut blockchain = Blockchain::new();
+    blockchain.checkpoints = checkpoints::from_config(&config, &network::from_config(&config).unwrap()).unwrap();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    let mut other = Blockchain::with_genesis(&GenesisConfig { proof: 101, ..GenesisConfig::default() });
+    other.mine_block(Blockchain::new_identifier()).unwrap();
+    let hashes: Vec<String> = other.chain.iter().map(Blockchain::hash).collect();
+    assert_eq!(blockchain.checkpointed(&other.chain, &hashes),
+               Err(InvalidBlock { index: 1, rule: BlockRule::Checkpoint }));
+    assert!(blockchain.valid_chain(&other.chain).is_err());
+}
+
+#[test]
+fn genesis() {
+    // Nodes on the same network create the same genesis block, whenever
+    // they start
+    let main = NodeConfig::default();
+    let genesis = network::from_config(&main).unwrap().genesis;
+    let blockchain = Blockchain::with_genesis(&genesis);
+    assert_eq!(Blockchain::hash(&blockchain.chain[0]),
+               Blockchain::hash(&Blockchain::new().chain[0]));
+    assert_eq!(blockchain.chain[0].timestamp, config::GENESIS_TIMESTAMP);
+
+    // A custom network may give coins away on genesis
+    let mut custom = NodeConfig::default();
+    custom.network = "custom".to_owned();
+    custom.genesis = Some(GenesisConfig::default());
+    let mut genesis = network::from_config(&custom).unwrap().genesis;
+    genesis.allocations.insert("alice".to_owned(), 50);
+    let mut other = Blockchain::with_genesis(&genesis);
+    assert_eq!(ChainState::from_chain(&other.chain).balances.get("alice"), Some(&50));
+    other.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(other.valid_chain(&other.chain).is_ok());
+
+    // Chains from other networks are rejected
+    assert!(blockchain.valid_chain(&other.chain).is_err());
+    assert!(other.valid_chain(&blockchain.chain).is_err());
+    let serialized = serde_json::to_string(&other).unwrap();
+    let loaded = Blockchain::from_str(&serialized, &GenesisConfig::default());
+    assert_eq!(loaded.chain.len(), 1);
+    let loaded = Blockchain::from_str(&serialized, &genesis);
+    assert_eq!(loaded.chain.len(), 2);
+
+    // Built-in networks can't be redefined, and others must be defined
+    let mut config = NodeConfig::default();
+    config.genesis = Some(GenesisConfig::default());
+    assert!(network::from_config(&config).is_err());
+    config.network = "test".to_owned();
+    assert!(network::from_config(&config).is_err());
+    config.genesis = None;
+    assert!(network::from_config(&config).is_ok());
+    config.network = "nonexistent".to_owned();
+    assert!(network::from_config(&config).is_err());
+
+    // Networks differing only on their consensus parameters don't share
+    // a genesis
+    let genesis_hash = |genesis: &GenesisConfig| Blockchain::hash(&Blockchain::genesis_block(genesis));
+    let default = GenesisConfig::default();
+    let harder = GenesisConfig { pow_difficulty: default.pow_difficulty + 1, ..GenesisConfig::default() };
+    assert!(genesis_hash(&harder) != genesis_hash(&default));
+    let poa = GenesisConfig { consensus: "poa".to_owned(), ..GenesisConfig::default() };
+    assert!(genesis_hash(&poa) != genesis_hash(&default));
+    let mut custom = NodeConfig::default();
+    custom.network = "custom".to_owned();
+    custom.genesis = Some(GenesisConfig::default());
+    custom.consensus = "pos".to_owned();
+    assert_eq!(network::from_config(&custom).unwrap().genesis.consensus, "pos");
+}
+
+#[test]
+fn block_validation() {
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert("alice".to_owned(), 5);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 2).unwrap();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+    let payment = blockchain.chain[1].transactions[0].clone();
+
+    // Tampers with the transactions of the last block, keeping its
+    // Merkle root and proof of work right
+    let tampered = |change: &dyn Fn(&mut Vec<Transaction>)| {
+        let mut chain = blockchain.chain.clone();
+        let last = chain.len() - 1;
+        change(&mut chain[last].transactions);
+        chain[last].merkle_root = Blockchain::merkle_root(&chain[last].transactions);
+        reseal(&blockchain, &mut chain, last);
+        blockchain.valid_chain(&chain).map_err(|invalid| (invalid.index, invalid.rule))
+    };
+    let transaction = |sender: &str, amount| Transaction {
+        sender: sender.to_owned(),
+        recipient: "carol".to_owned(),
+        amount: amount,
+        nonce: Transaction::new_nonce(),
+    };
+
+    assert_eq!(tampered(&|_| {}), Ok(()));
+    assert_eq!(tampered(&|txs| txs.push(transaction("0", 1))), Err((3, BlockRule::Reward)));
+    assert_eq!(tampered(&|txs| txs[0].amount = 2), Err((3, BlockRule::Reward)));
+    assert_eq!(tampered(&|txs| txs.clear()), Err((3, BlockRule::Reward)));
+    assert_eq!(tampered(&|txs| txs.push(transaction("alice", 3))), Ok(()));
+    assert_eq!(tampered(&|txs| txs.push(transaction("alice", 4))), Err((3, BlockRule::Overspend)));
+    assert_eq!(tampered(&|txs| txs.push(transaction("bob", 0))), Err((3, BlockRule::Amount)));
+    assert_eq!(tampered(&|txs| txs.push(payment.clone())), Err((3, BlockRule::Duplicate)));
+    assert_eq!(tampered(&|txs| {
+        let padding = transaction("alice", 1);
+        txs.extend((0..30000).map(|_| padding.clone()));
+    }), Err((3, BlockRule::Size)));
+
+    // Indexes must follow each other
+    let mut skipped = blockchain.chain.clone();
+    skipped[2].index += 1;
+    assert_eq!(blockchain.valid_chain(&skipped),
+               Err(InvalidBlock { index: 3, rule: BlockRule::Index }));
+
+    // Transactions the chain would not accept are refused, and mining
+    // leaves them out if they got on the mempool anyway
+    assert_eq!(blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 10),
+               Err(BlockRule::Overspend.description().to_owned()));
+    blockchain.current_transactions.push(transaction("alice", 10));
+    blockchain.current_transactions.push(payment.clone());
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    assert_eq!(blockchain.chain[3].transactions.len(), 1);
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+}
+
+#[test]
+fn parallel_validation() {
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert("alice".to_owned(), 5);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    blockchain.set_engine(Arc::new(consensus::ProofOfWork::new(consensus::PowHash::Sha256, 1)));
+    for _ in 0..100 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    for &threads in &[1, 4] {
+        assert_eq!(blockchain.valid_chain_with(&blockchain.chain, threads), Ok(()));
+    }
+
+    // The first block breaking a rule is reported, whether it was caught
+    // when applying transactions (before the next block's broken link)...
+    let mut chain = blockchain.chain.clone();
+    chain[30].transactions.push(Transaction {
+        sender: "alice".to_owned(),
+        recipient: "bob".to_owned(),
+        amount: 6,
+        nonce: 0,
+    });
+    chain[30].merkle_root = Blockchain::merkle_root(&chain[30].transactions);
+    reseal(&blockchain, &mut chain, 30);
+    for &threads in &[1, 4] {
+        assert_eq!(blockchain.valid_chain_with(&chain, threads),
+                   Err(InvalidBlock { index: 31, rule: BlockRule::Overspend }));
+        assert_eq!(blockchain.valid_chain_with(&chain[..31], threads),
+                   Err(InvalidBlock { index: 31, rule: BlockRule::Overspend }));
+    }
+    // ...or by the parallel checks
+    let mut chain = blockchain.chain.clone();
+    chain[70].merkle_root = "0".repeat(64);
+    reseal(&blockchain, &mut chain, 70);
+    for &threads in &[1, 4] {
+        assert_eq!(blockchain.valid_chain_with(&chain, threads),
+                   Err(InvalidBlock { index: 71, rule: BlockRule::MerkleRoot }));
+    }
+}
+
+#[test]
+fn size_limits() {
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert("alice".to_owned(), 100);
+    genesis.max_block_size = 1000;
+    genesis.max_transaction_size = 100;
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+
+    // Transactions too large for the network never make it to the mempool
+    assert!(blockchain.new_transaction("alice".to_owned(), "b".repeat(100), 1).is_err());
+    assert!(blockchain.current_transactions.is_empty());
+
+    // Blocks take as many transactions as fit, and the rest wait
+    for _ in 0..20 {
+        blockchain.new_transaction("alice".to_owned(), Blockchain::new_identifier(), 1).unwrap();
+    }
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(encoding::to_bytes(&blockchain.chain[1]).len() <= 1000);
+    assert!(blockchain.chain[1].transactions.len() > 1);
+    assert!(!blockchain.current_transactions.is_empty());
+    while !blockchain.current_transactions.is_empty() {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    let confirmed: usize = blockchain.chain.iter().map(|block| block.transactions.len() - 1).sum();
+    assert_eq!(confirmed, 20);
+    assert!(blockchain.valid_chain(&blockchain.chain).is_ok());
+
+    // Blocks signed when sealed leave room for the signature
+    let key = keys::SigningKey::generate();
+    let mut signed = Blockchain::with_genesis(&genesis);
+    signed.engine = Arc::new(consensus::ProofOfAuthority::new(vec![key.public_key()], key).unwrap());
+    for _ in 0..20 {
+        signed.new_transaction("alice".to_owned(), Blockchain::new_identifier(), 1).unwrap();
+    }
+    signed.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(encoding::to_bytes(&signed.chain[1]).len() <= 1000);
+    assert!(signed.chain[1].transactions.len() > 1);
+    assert_eq!(signed.check_body(&signed.chain[1]), Ok(()));
+    assert!(signed.valid_chain(&signed.chain).is_ok());
+
+    // Other nodes' blocks are held to the same limits
+    let mut chain = blockchain.chain.clone();
+    chain[1].transactions.push(Transaction {
+        sender: "alice".to_owned(),
+        recipient: "b".repeat(100),
+        amount: 1,
+        nonce: 0,
+    });
+    chain[1].merkle_root = Blockchain::merkle_root(&chain[1].transactions);
+    reseal(&blockchain, &mut chain, 1);
+    assert_eq!(blockchain.valid_chain(&chain),
+               Err(InvalidBlock { index: 2, rule: BlockRule::TransactionSize }));
+    // The first transaction left out of it would not fit
+    let left_out = chain[2].transactions[0].clone();
+    chain[1].transactions.pop();
+    chain[1].transactions.push(left_out);
+    chain[1].merkle_root = Blockchain::merkle_root(&chain[1].transactions);
+    reseal(&blockchain, &mut chain, 1);
+    assert_eq!(blockchain.valid_chain(&chain),
+               Err(InvalidBlock { index: 2, rule: BlockRule::Size }));
+}
+
+#[test]
+fn http_api() {
+    use tiny_http::Method;
+
+    match api::route(&Method::Get, "/block/3?pretty", "") {
+        Ok((ReplCommand::HttpGetBlock { id }, 200)) => assert_eq!(id, "3"),
+        _ => panic!("GET /block/ID should fetch a block"),
+    }
+    let body = r#"{"sender": "alice", "recipient": "bob", "amount": 5}"#;
+    match api::route(&Method::Post, "/transactions/new", body) {
+        Ok((ReplCommand::HttpNewTransaction { from, to, amount }, 201)) => {
+            assert_eq!((from.as_ref(), to.as_ref(), amount), ("alice", "bob", 5));
+        },
+        _ => panic!("POST /transactions/new should create a transaction"),
+    }
+    match api::route(&Method::Post, "/nodes/register", r#"{"nodes": ["http://127.0.0.1:3001"]}"#) {
+        Ok((ReplCommand::HttpRegNodes { urls }, 201)) => assert_eq!(urls, vec!["http://127.0.0.1:3001"]),
+        _ => panic!("POST /nodes/register should register nodes"),
+    }
+
+    // Errors come with their status code
+    let status = |method: Method, url: &str, body: &str| match api::route(&method, url, body) {
+        Ok(_) => 200,
+        Err(error) => error.status,
+    };
+    assert_eq!(status(Method::Get, "/status", ""), 200);
+    assert_eq!(status(Method::Post, "/transactions/new", "{}"), 400);
+    assert_eq!(status(Method::Post, "/transactions/new", r#"{"sender": "a", "recipient": "b", "amount": 0}"#), 400);
+    assert_eq!(status(Method::Post, "/nodes/register", r#"{"nodes": ["not a url"]}"#), 400);
+    assert_eq!(status(Method::Post, "/block", "not a block"), 400);
+    assert_eq!(status(Method::Get, "/mine", ""), 405);
+    assert_eq!(status(Method::Delete, "/chain", ""), 405);
+    assert_eq!(status(Method::Get, "/nonexistent", ""), 404);
+    assert_eq!(ApiError::not_found("Block 5 not found").body(), r#"{"error":"Block 5 not found"}"#);
+
+    // Blocks are found by index or hash
+    let mut blockchain = Blockchain::new();
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    let hash = Blockchain::hash(&blockchain.chain[1]);
+    assert_eq!(blockchain.block("2").map(Blockchain::hash), Some(hash.clone()));
+    assert_eq!(blockchain.block(&hash).map(|block| block.index), Some(2));
+    assert!(blockchain.block("0").is_none());
+    assert!(blockchain.block("3").is_none());
+
+    // Bodies are only read for POST endpoints, and only so large
+    assert!(api::takes_body(&Method::Post, "/block"));
+    assert!(!api::takes_body(&Method::Get, "/chain"));
+    assert!(!api::takes_body(&Method::Post, "/chain"));
+    assert_eq!(api::read_body(&b"{}"[..], 2), Ok("{}".to_owned()));
+    assert_eq!(api::read_body(&b"{ }"[..], 2).map_err(|error| error.status), Err(413));
+}
+
+#[test]
+fn gossip() {
+    let (tx, _rx) = mpsc::channel();
+    let mut gossip = Gossip::start(Some("http://127.0.0.1:3000".to_owned()), SizeLimits::default(), tx);
+
+    // Whatever we announced or fetched already is not fetched again
+    gossip.announce(&[], vec!["mined".to_owned()], vec![], None);
+    let inventory = Inventory {
+        from: "http://127.0.0.1:1".to_owned(),
+        blocks: vec!["mined".to_owned(), "new".to_owned()],
+        transactions: vec!["tx".to_owned()],
+    };
+    let wanted = gossip.receive(inventory.clone());
+    assert_eq!(wanted.blocks, vec!["new"]);
+    assert_eq!(wanted.transactions, vec!["tx"]);
+    assert!(gossip.receive(inventory.clone()).is_empty());
+
+    // Until we have it, whoever announces it next gets asked for it
+    gossip.fetched("new", false);
+    gossip.fetched("tx", true);
+    let wanted = gossip.receive(inventory.clone());
+    assert_eq!(wanted.blocks, vec!["new"]);
+    assert!(wanted.transactions.is_empty());
+    gossip.fetched("new", true);
+    assert!(gossip.receive(inventory).is_empty());
+
+    // Transactions from other nodes must be able to go on the chain
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert("alice".to_owned(), 5);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    let payment = |amount| Transaction {
+        sender: "alice".to_owned(),
+        recipient: "bob".to_owned(),
+        amount: amount,
+        nonce: Transaction::new_nonce(),
+    };
+    let first = payment(3);
+    assert!(blockchain.admit_transaction(first.clone()).is_ok());
+    assert!(blockchain.admit_transaction(first.clone()).is_err());
+    assert!(blockchain.admit_transaction(payment(3)).is_err());
+    assert!(blockchain.admit_transaction(payment(2)).is_ok());
+    let mut reward = payment(1);
+    reward.sender = "0".to_owned();
+    assert!(blockchain.admit_transaction(reward).is_err());
+    assert_eq!(blockchain.pending_transaction(&first.id()).map(Transaction::id), Some(first.id()));
+    assert_eq!(blockchain.current_transactions.len(), 2);
+
+    // Once mined, they stay spent
+    blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    assert!(blockchain.current_transactions.is_empty());
+    assert!(blockchain.admit_transaction(first.clone()).is_err());
+    assert!(blockchain.admit_transaction(payment(1)).is_err());
+}
+
+#[test]
+fn headers_first_sync() {
+    let mut blockchain = Blockchain::new();
+    blockchain.set_engine(Arc::new(consensus::ProofOfWork::new(consensus::PowHash::Sha256, 1)));
+    for _ in 0..29 {
+        blockchain.mine_block(Blockchain::new_identifier()).unwrap();
+    }
+    let hashes: Vec<String> = blockchain.chain.iter().map(Blockchain::hash).collect();
+
+    // Locators are dense near the tip, and always end at genesis
+    let locator = sync::locator(&blockchain.chain);
+    assert_eq!(locator[..10], hashes[20..].iter().rev().cloned().collect::<Vec<_>>()[..]);
+    assert_eq!(locator.last(), Some(&hashes[0]));
+    assert!(locator.len() < 20);
+    assert_eq!(sync::locator(&blockchain.chain[..1]), vec![hashes[0].clone()]);
+
+    // A node 20 blocks behind gets only the headers it misses
+    let mut behind = blockchain.clone();
+    behind.chain.truncate(10);
+    behind.rebuild_tree();
+    let headers = blockchain.headers_after(&sync::locator(&behind.chain));
+    assert_eq!(headers.len(), 20);
+    assert_eq!(headers[0].index, 11);
+    assert!(behind.check_headers(&behind.chain[9], &headers).is_ok());
+    assert_eq!(behind.check_headers(&behind.chain[8], &headers).unwrap_err().rule, BlockRule::Index);
+    let mut forged = headers.clone();
+    forged[5].proof += 1;
+    assert!(behind.check_headers(&behind.chain[9], &forged).is_err());
+
+    // Headers are checked like blocks would be, before downloading any
+    let mut forged = headers[..1].to_vec();
+    while behind.engine.verify_seal(&forged[0]) {
+        forged[0].proof += 1;
+    }
+    assert_eq!(behind.check_headers(&behind.chain[9], &forged).unwrap_err().rule, BlockRule::Consensus);
+    let mut forged = headers[..1].to_vec();
+    forged[0].timestamp = behind.chain[5].timestamp;
+    assert_eq!(behind.check_headers(&behind.chain[9], &forged).unwrap_err().rule, BlockRule::Timestamp);
+    behind.checkpoints.insert(11, "00".repeat(32));
+    assert_eq!(behind.check_headers(&behind.chain[9], &headers).unwrap_err(),
+               InvalidBlock { index: 11, rule: BlockRule::Checkpoint });
+    behind.checkpoints.clear();
+    assert!(blockchain.headers_after(&["0".repeat(64)]).is_empty());
+
+    // Blocks are served by hash range
+    let blocks = blockchain.blocks_between(&hashes[10], &hashes[19]).unwrap();
+    assert_eq!(blocks.iter().map(Blockchain::hash).collect::<Vec<_>>(), hashes[10..20].to_vec());
+    assert!(blockchain.blocks_between(&hashes[19], &hashes[10]).is_none());
+    assert!(behind.blocks_between(&hashes[5], &hashes[19]).is_none());
+
+    // Ranges are downloaded from whichever nodes have them
+    let wanted: Vec<String> = (0..250).map(|i| i.to_string()).collect();
+    let sources = vec![
+        ("http://a".to_owned(), wanted.iter().cloned().collect()),
+        ("http://b".to_owned(), wanted[..150].iter().cloned().collect()),
+    ];
+    let ranges = sync::ranges(&wanted, &sources);
+    assert_eq!(ranges.iter().map(|range| range.hashes.len()).collect::<Vec<_>>(), vec![100, 100, 50]);
+    assert_eq!(ranges[0].sources, vec!["http://a", "http://b"]);
+    assert_eq!(ranges[1].sources, vec!["http://a"]);
+
+    // Downloaded blocks must be the ones asked for, with their own bodies
+    let range = sync::Range { hashes: hashes[10..20].to_vec(), sources: vec!["http://a".to_owned()] };
+    assert!(sync::check_range("http://a", &range, &blocks).is_ok());
+    assert!(sync::check_range("http://a", &range, &blocks[1..]).is_err());
+    let mut forged = blocks.clone();
+    forged[3].transactions[0].amount += 1;
+    assert_eq!(Blockchain::hash(&forged[3]), hashes[13]);
+    match sync::check_range("http://a", &range, &forged) {
+        Err(PeerError::Misbehaved(_)) => (),
+        _ => panic!("Took a block whose body doesn't match its header"),
+    }
+}
+
+#[test]
+fn address_book() {
+    let mut book = AddressBook::new();
+    assert!(!book.add("not an address"));
+    assert!(book.add("http://127.0.0.1:3001"));
+    assert!(!book.add("http://127.0.0.1:3001"));
+
+    // Only addresses we heard from lately are shared, most recent first
+    let now = 1_000_000;
+    assert!(book.good(now).is_empty());
+    book.seen("http://127.0.0.1:3001", now - 100);
+    book.seen("http://127.0.0.1:3002", now);
+    assert_eq!(book.good(now), vec!["http://127.0.0.1:3002", "http://127.0.0.1:3001"]);
+    assert_eq!(book.good(now + peers::GOOD_PEER_AGE - 50), vec!["http://127.0.0.1:3002"]);
+
+    // When full, addresses we never heard from go first
+    for port in 0..peers::MAX_ADDRESSES {
+        book.add(&format!("http://10.0.0.1:{}", port));
+    }
+    assert_eq!(book.len(), peers::MAX_ADDRESSES);
+    assert_eq!(book.candidates()[..2].to_vec(), vec!["http://127.0.0.1:3002", "http://127.0.0.1:3001"]);
+
+    // Nodes are registered from the address book, leaving ourselves out
+    book.top_up("http://127.0.0.1:3002");
+    let nodes = book.nodes();
+    assert_eq!(nodes.len(), peers::TARGET_NODES);
+    assert!(nodes.contains(&"http://127.0.0.1:3001".to_owned()));
+    assert!(!nodes.contains(&"http://127.0.0.1:3002".to_owned()));
+
+    // Each node keeps track of how it is doing
+    let node = "http://127.0.0.1:3001";
+    assert!(!book.handshaken(node));
+    book.shook_hands(node, Blockchain::new().handshake(), Some("on network test".to_owned()));
+    assert!(!book.handshaken(node));
+    book.shook_hands(node, Blockchain::new().handshake(), None);
+    assert!(book.handshaken(node));
+    book.failed(node);
+    book.failed(node);
+    book.reached(node, now + 10, Some(42), Some(7));
+    book.reached(node, now + 20, None, None);
+    book.failed(node);
+    let status = book.node_status().into_iter().find(|&(ref address, _)| address == node).unwrap().1;
+    assert_eq!((status.last_contact, status.height, status.latency, status.failures),
+               (now + 20, Some(42), Some(7), 1));
+
+    // Deleted nodes leave the address book too, and don't come back from
+    // other nodes' peers
+    assert!(book.unregister(node));
+    assert!(!book.unregister(node));
+    assert!(!book.add(node));
+    book.top_up("http://127.0.0.1:3002");
+    assert!(!book.nodes().contains(&node.to_owned()));
+    assert!(!book.register("not an address"));
+    assert!(book.register(node));
+    assert!(!book.register(node));
+    assert!(book.unregister(node));
+
+    // Our nodes survive a round trip through their file, apart from the chain
+    let path = env::temp_dir().join("address_book_test.json");
+    book.to_file(path.to_str().unwrap().to_owned());
+    let mut loaded = AddressBook::from_file(path.to_str().unwrap().to_owned());
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(loaded.nodes(), book.nodes());
+    assert!(!loaded.add(node));
+}
+
+#[test]
+fn remote_requests() {
+    // Answers are read up to a cap
+    assert_eq!(remote::read_body("[1, 2]".as_bytes(), 6), Ok("[1, 2]".to_owned()));
+    assert_eq!(remote::read_body("[1, 2, 3]".as_bytes(), 6), Err(PeerError::TooLarge(6)));
+
+    // Only failures which may go away are tried again, waiting longer each time
+    assert!(PeerError::Unreachable("timed out".to_owned()).transient());
+    assert!(PeerError::Status(503, String::new()).transient());
+    assert!(!PeerError::Status(404, String::new()).transient());
+    assert!(!PeerError::Malformed("EOF".to_owned()).transient());
+    assert_eq!(remote::backoff(0), Duration::from_millis(0));
+    assert!(remote::backoff(2) > remote::backoff(1));
+
+    // A node nobody listens on fails the request, instead of the caller
+    let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let dead = format!("http://127.0.0.1:{}", port);
+    match remote::get::<Vec<BlockHeader>>(&remote::client(), &format!("{}/headers", dead), 1024) {
+        Err(PeerError::Unreachable(_)) => (),
+        _ => panic!("Reached a node which is not there"),
+    }
+
+    // Resolving reports it, and keeps our chain
+    let mut blockchain = Blockchain::new();
+    let mut book = AddressBook::new();
+    book.register(&dead);
+    let resolution = resolve(&mut blockchain, &mut book);
+    assert!(!resolution.replaced);
+    assert_eq!(resolution.failures.keys().collect::<Vec<_>>(), vec![&dead]);
+    assert_eq!(book.node_status()[0].1.failures, 1);
+    assert_eq!(blockchain.chain.len(), 1);
+}
+
+#[test]
+fn peer_bans() {
+    let mut book = AddressBook::new();
+    book.policy = BanPolicy { threshold: 100, duration: 600 };
+    let node = "http://127.0.0.1:3001";
+    let now = 1_000_000;
+    assert!(book.register(node));
+
+    // What a node did wrong adds up, until it gets banned
+    assert_eq!(Misbehaviour::from_error(&PeerError::Unreachable("timed out".to_owned())), None);
+    assert_eq!(Misbehaviour::from_error(&PeerError::Status(404, String::new())), None);
+    assert_eq!(Misbehaviour::from_rule(&BlockRule::Consensus), Misbehaviour::BadProof);
+    assert_eq!(Misbehaviour::from_rule(&BlockRule::Overspend), Misbehaviour::InvalidBlock);
+    assert!(!peer_failed(&mut book, node, &PeerError::Malformed("EOF".to_owned()), now));
+    assert!(!punish(&mut book, node, Misbehaviour::InvalidBlock, now));
+    assert_eq!(book.score(node), 70);
+    assert!(punish(&mut book, node, Misbehaviour::InvalidBlock, now));
+    assert!(!punish(&mut book, node, Misbehaviour::Malformed, now));
+
+    // Banned nodes are gone, and don't come back until the ban is over
+    assert!(book.banned(node));
+    assert!(book.nodes().is_empty());
+    assert!(!book.add(node));
+    assert!(!book.register(node));
+    assert_eq!(book.bans()[0].1.until, now + 600);
+
+    // The ban list survives a round trip through its file
+    let path = env::temp_dir().join("peer_bans_test.json");
+    book.to_file(path.to_str().unwrap().to_owned());
+    let mut loaded = AddressBook::from_file(path.to_str().unwrap().to_owned());
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(loaded.bans(), book.bans());
+
+    loaded.expire_bans(now + 599);
+    assert!(loaded.banned(node));
+    loaded.expire_bans(now + 600);
+    assert!(!loaded.banned(node));
+    assert!(loaded.register(node));
+
+    // Unbanning forgives everything
+    assert!(book.unban(node));
+    assert!(!book.unban(node));
+    assert_eq!(book.score(node), 0);
+    assert!(book.register(node));
+
+    // Scores go down over time
+    assert!(!punish(&mut book, node, Misbehaviour::Malformed, now));
+    book.expire_bans(now + 10 * peers::SCORE_DECAY_INTERVAL + 1);
+    assert_eq!(book.score(node), 10);
+    book.expire_bans(now + 25 * peers::SCORE_DECAY_INTERVAL);
+    assert_eq!(book.score(node), 0);
+}
+
+// A node for p2p tests: a blockchain taking connections on loopback, and
+// handling their messages on a thread of its own, like the daemon does.
+#[cfg(test)]
+fn p2p_test_node(blockchain: Blockchain) -> (P2p, Arc<std::sync::Mutex<Blockchain>>, String) {
+    let (tx, rx) = mpsc::channel();
+    let p2p = P2p::new(blockchain.handshake(), blockchain.limits, tx);
+    let address = p2p.listen("127.0.0.1:0").unwrap().to_string();
+    let shared = Arc::new(std::sync::Mutex::new(blockchain));
+    let (node, handler) = (shared.clone(), p2p.clone());
+    thread::spawn(move || {
+        let mut book = AddressBook::new();
+        for command in rx {
+            let mut blockchain = node.lock().unwrap();
+            match command {
+                ReplCommand::P2pConnected { peer, height } =>
+                    p2p_connected(&blockchain, &book, &handler, peer, height),
+                ReplCommand::P2pMessage { peer, message } => {
+                    handle_p2p(&mut blockchain, &mut book, &handler, peer, message);
+                },
+                _ => {},
+            }
+            handler.set_local(blockchain.handshake());
+        }
+    });
+    (p2p, shared, address)
+}
+
+// Waits a few seconds at most for something to happen.
+#[cfg(test)]
+fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    for _ in 0..100 {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+#[test]
+fn p2p_network() {
+    // Frames survive a round trip, and anything off about them is refused
+    let mut genesis = GenesisConfig::default();
+    genesis.allocations.insert("alice".to_owned(), 5);
+    let mut blockchain = Blockchain::with_genesis(&genesis);
+    blockchain.mine_block("miner".to_owned()).unwrap();
+    let block = blockchain.chain[1].clone();
+    let decoded: Block = encoding::from_bytes(&encoding::to_bytes(&block)).unwrap();
+    assert_eq!(Blockchain::hash(&decoded), Blockchain::hash(&block));
+    assert!(encoding::from_bytes::<Block>(&encoding::to_bytes(&block)[..20]).is_err());
+
+    let magic = p2p::magic(&Blockchain::hash(&blockchain.chain[0]));
+    let mut frame = vec![];
+    p2p::write_frame(&mut frame, magic, &Message::Block(block.clone())).unwrap();
+    match p2p::read_frame(&mut &frame[..], magic, frame.len()) {
+        Ok(Message::Block(read)) => assert_eq!(Blockchain::hash(&read), Blockchain::hash(&block)),
+        _ => panic!("Block did not survive a round trip"),
+    }
+    assert!(p2p::read_frame(&mut &frame[..], [0; 4], frame.len()).is_err());
+    assert!(p2p::read_frame(&mut &frame[..], magic, 10).is_err());
+    let last = frame.len() - 1;
+    frame[last] ^= 1;
+    assert!(p2p::read_frame(&mut &frame[..], magic, frame.len()).is_err());
+
+    // Three nodes in a line: A, B, then C. A is ahead.
+    let (a, chain_a, address_a) = p2p_test_node(Blockchain::with_genesis(&genesis));
+    let (b, chain_b, address_b) = p2p_test_node(Blockchain::with_genesis(&genesis));
+    let (c, chain_c, _) = p2p_test_node(Blockchain::with_genesis(&genesis));
+    chain_a.lock().unwrap().mine_block("miner".to_owned()).unwrap();
+    chain_a.lock().unwrap().mine_block("miner".to_owned()).unwrap();
+    a.set_local(chain_a.lock().unwrap().handshake());
+    b.connect(&address_a);
+    c.connect(&address_b);
+    assert!(eventually(|| a.peers().len() == 1 && b.peers().len() == 2 && c.peers().len() == 1));
+    let handshake = b.peers().into_iter().find(|peer| peer.outbound).unwrap().handshake;
+    assert_eq!((handshake.network.as_ref(), handshake.height), ("main", 3));
+    assert_eq!(handshake.software_version, BLOCKCHAIN_RS_VERSION);
+
+    // B catches up with A on handshake
+    assert!(eventually(|| chain_b.lock().unwrap().chain.len() == 3));
+
+    // New blocks and transactions spread all the way
+    let hash = {
+        let mut blockchain = chain_a.lock().unwrap();
+        blockchain.mine_block("miner".to_owned()).unwrap();
+        Blockchain::hash(blockchain.chain.last().unwrap())
+    };
+    a.broadcast(Message::Inv { blocks: vec![hash.clone()], transactions: vec![] }, None);
+    assert!(eventually(|| chain_c.lock().unwrap().chain.last().map(Blockchain::hash) == Some(hash.clone())));
+
+    let txid = {
+        let mut blockchain = chain_a.lock().unwrap();
+        blockchain.new_transaction("alice".to_owned(), "bob".to_owned(), 2).unwrap();
+        blockchain.current_transactions[0].id()
+    };
+    a.broadcast(Message::Inv { blocks: vec![], transactions: vec![txid.clone()] }, None);
+    assert!(eventually(|| chain_c.lock().unwrap().pending_transaction(&txid).is_some()));
+
+    // Nodes from another network don't get past the handshake
+    let mut other = GenesisConfig::default();
+    other.timestamp += 1;
+    let (d, _, _) = p2p_test_node(Blockchain::with_genesis(&other));
+    d.connect(&address_a);
+    let mut renamed = Blockchain::with_genesis(&genesis);
+    renamed.network = "test".to_owned();
+    let (e, _, _) = p2p_test_node(renamed);
+    e.connect(&address_a);
+    thread::sleep(Duration::from_millis(500));
+    assert!(d.peers().is_empty());
+    assert!(e.peers().is_empty());
+    assert_eq!(a.peers().len(), 1);
+
+    // Nodes sending invalid blocks are dropped, and banned by IP address
+    let mut fork = chain_a.lock().unwrap().clone();
+    fork.mine_block("miner".to_owned()).unwrap();
+    let mut forged = fork.chain.last().unwrap().clone();
+    while fork.engine.verify_seal(&forged.header()) {
+        forged.proof += 1;
+    }
+    let (f, _, _) = p2p_test_node(fork);
+    f.connect(&address_a);
+    assert!(eventually(|| a.peers().len() == 2));
+    f.broadcast(Message::Block(forged), None);
+    assert!(eventually(|| a.peers().len() == 1 && f.peers().is_empty()));
+    f.connect(&address_a);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(a.peers().len(), 1);
+
+    // So are nodes sending headers which don't check out
+    let (g, _, address_g) = p2p_test_node(Blockchain::with_genesis(&genesis));
+    let (h, _, _) = p2p_test_node(Blockchain::with_genesis(&genesis));
+    h.connect(&address_g);
+    assert!(eventually(|| g.peers().len() == 1));
+    let (mut headers, engine) = {
+        let blockchain = chain_a.lock().unwrap();
+        (blockchain.chain[1..].iter().map(Block::header).collect::<Vec<_>>(), blockchain.engine.clone())
+    };
+    while engine.verify_seal(&headers[1]) {
+        headers[1].proof += 1;
+    }
+    h.broadcast(Message::Headers(headers), None);
+    assert!(eventually(|| g.peers().is_empty() && h.peers().is_empty()));
+    h.connect(&address_g);
+    thread::sleep(Duration::from_millis(500));
+    assert!(g.peers().is_empty());
+}
+
+#[test]
+fn node_handshake() {
+    use tiny_http::Method;
+
+    let blockchain = Blockchain::new();
+    let ours = blockchain.handshake();
+    assert_eq!(ours.protocol_version, handshake::PROTOCOL_VERSION);
+    assert_eq!(ours.height, 1);
+    assert!(ours.check(&ours).is_ok());
+
+    // Only the network, genesis and protocol version have to match
+    let mut theirs = ours.clone();
+    theirs.software_version = "0.0.1".to_owned();
+    theirs.services = vec![];
+    theirs.height = 10;
+    assert!(theirs.check(&ours).is_ok());
+    let mut other = ours.clone();
+    other.network = "test".to_owned();
+    assert!(other.check(&ours).is_err());
+    let mut other = ours.clone();
+    other.genesis = "0".repeat(64);
+    assert!(other.check(&ours).is_err());
+    let mut other = ours.clone();
+    other.protocol_version += 1;
+    assert!(other.check(&ours).unwrap_err().contains("protocol version"));
+
+    // It comes in over p2p and HTTP alike
+    let magic = p2p::magic(&ours.genesis);
+    let mut frame = vec![];
+    p2p::write_frame(&mut frame, magic, &Message::Version(ours.clone())).unwrap();
+    match p2p::read_frame(&mut &frame[..], magic, frame.len()) {
+        Ok(Message::Version(handshake)) => assert_eq!(handshake, ours),
+        _ => panic!("Handshake did not survive a round trip"),
+    }
+    let body = serde_json::to_string(&ours).unwrap();
+    match api::route(&Method::Post, "/handshake", &body) {
+        Ok((ReplCommand::HttpHandshake { handshake }, 200)) => assert_eq!(handshake, ours),
+        _ => panic!("POST /handshake was not routed"),
+    }
+    assert_eq!(api::route(&Method::Get, "/handshake", "").err().map(|e| e.status), Some(405));
+
+    // We stop syncing with nodes which turn out to be on another network,
+    // but keep them registered
+    let mut book = AddressBook::new();
+    let (good, bad) = ("http://127.0.0.1:3001", "http://127.0.0.1:3002");
+    book.register(good);
+    book.register(bad);
+    book.shook_hands(good, theirs.clone(), theirs.check(&ours).err());
+    book.shook_hands(bad, other.clone(), other.check(&ours).err());
+    assert_eq!(book.nodes(), vec![good.to_owned()]);
+    assert_eq!(book.registered().len(), 2);
+    let status = book.node_status().into_iter().find(|&(ref node, _)| node == bad).unwrap().1;
+    assert_eq!(status.handshake, Some(other.clone()));
+    assert!(status.mismatch.is_some());
+
+    // Until they are back
+    book.shook_hands(bad, theirs.clone(), None);
+    assert_eq!(book.nodes().len(), 2);
+}
+
+#[test]
+fn http_auth() {
+    use tiny_http::Method;
+
+    let config = NodeConfig::from_str(&r#"{"http_bind": "0.0.0.0", "api_token": "secret",
+                                          "api_user": "admin", "api_password": "hunter2"}"#.to_owned());
+    assert_eq!(config.http_bind, "0.0.0.0");
+    assert_eq!(NodeConfig::default().http_bind, "127.0.0.1");
+    assert_eq!(config.public_url, None);
+    let config = NodeConfig::from_str(&r#"{"http_bind": "0.0.0.0", "api_token": "secret",
+                                          "public_url": "http://203.0.113.5:3000", "p2p_bind": "10.0.0.2",
+                                          "api_user": "admin", "api_password": "hunter2"}"#.to_owned());
+    assert_eq!(config.public_url, Some("http://203.0.113.5:3000".to_owned()));
+    assert_eq!(config.p2p_bind, Some("10.0.0.2".to_owned()));
+    let auth = api::Auth::from_config(&config).unwrap();
+    assert!(!auth.is_open());
+
+    // Reading, announcing and shaking hands stay open
+    assert!(auth.check(&Method::Get, "/chain", None).is_ok());
+    assert!(auth.check(&Method::Get, "/status?pretty", None).is_ok());
+    assert!(auth.check(&Method::Post, "/inv", None).is_ok());
+    assert!(auth.check(&Method::Post, "/handshake", None).is_ok());
+
+    // Changing our state takes a token or a user and password
+    assert_eq!(auth.check(&Method::Post, "/mine", None).unwrap_err().status, 401);
+    assert_eq!(auth.check(&Method::Post, "/mine", Some("Bearer wrong")).unwrap_err().status, 401);
+    assert_eq!(auth.check(&Method::Post, "/nodes/resolve", Some("secret")).unwrap_err().status, 401);
+    assert!(auth.check(&Method::Post, "/mine", Some("Bearer secret")).is_ok());
+    assert!(auth.check(&Method::Post, "/transactions/new", Some("bearer secret")).is_ok());
+    assert_eq!(api::base64(b"admin:hunter2"), "YWRtaW46aHVudGVyMg==");
+    assert_eq!(api::base64(b"ab"), "YWI=");
+    assert!(auth.check(&Method::Post, "/nodes/register", Some("Basic YWRtaW46aHVudGVyMg==")).is_ok());
+    assert!(auth.check(&Method::Post, "/block", Some("Basic YWRtaW46aHVudGVy")).is_err());
+
+    // Half a user is refused
+    let config = NodeConfig::from_str(&r#"{"api_user": "admin"}"#.to_owned());
+    assert!(api::Auth::from_config(&config).is_err());
+
+    // With no credentials, everything is open, like it always was
+    let open = api::Auth::from_config(&NodeConfig::default()).unwrap();
+    assert!(open.is_open());
+    assert!(open.check(&Method::Post, "/mine", None).is_ok());
+}
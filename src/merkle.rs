@@ -0,0 +1,75 @@
+// Merkle trees over transaction IDs.
+// Leaves are the transaction IDs; each parent is the SHA-256 of its two
+// children's hex strings, concatenated. When a level has an odd number
+// of nodes, the last one is paired with itself, like Bitcoin does.
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+// Root of a tree with no transactions at all.
+pub const EMPTY_ROOT: &'static str =
+    "0000000000000000000000000000000000000000000000000000000000000000";
+
+// Hashes two sibling nodes into their parent.
+pub fn parent(left: &str, right: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(left);
+    hasher.input_str(right);
+    hasher.result_str()
+}
+
+// Computes the Merkle root of a list of transaction IDs.
+pub fn root(ids: &[String]) -> String {
+    if ids.is_empty() {
+        return EMPTY_ROOT.to_owned();
+    }
+    let mut level = ids.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.pop().unwrap()
+}
+
+// Hashes each pair of nodes of a level into the level above it.
+fn next_level(level: &[String]) -> Vec<String> {
+    level.chunks(2)
+        .map(|pair| match pair.len() {
+            2 => parent(&pair[0], &pair[1]),
+            _ => parent(&pair[0], &pair[0]),
+        })
+        .collect()
+}
+
+// Gets the Merkle branch of the leaf at index: the sibling of each node
+// on the path from that leaf up to the root.
+pub fn branch(ids: &[String], index: usize) -> Vec<String> {
+    let mut branch = vec![];
+    let mut level = ids.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        let sibling = match position % 2 {
+            0 if position + 1 < level.len() => level[position + 1].clone(),
+            0 => level[position].clone(),
+            _ => level[position - 1].clone(),
+        };
+        branch.push(sibling);
+        level = next_level(&level);
+        position /= 2;
+    }
+    branch
+}
+
+// Checks that the leaf id, at index, is part of the tree with the given
+// root, using its Merkle branch.
+pub fn verify(id: &str, index: usize, branch: &[String], root: &str) -> bool {
+    let mut hash = id.to_owned();
+    let mut position = index;
+    for sibling in branch {
+        hash = match position % 2 {
+            0 => parent(&hash, sibling),
+            _ => parent(sibling, &hash),
+        };
+        position /= 2;
+    }
+    position == 0 && hash == root
+}
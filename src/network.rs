@@ -0,0 +1,94 @@
+// Networks.
+// A network is defined by its genesis block and consensus rules. Nodes
+// which disagree on them can never agree on a chain, so every node must
+// know which network it is on. The main and test networks are built in;
+// any other one is defined by the genesis on the node configuration.
+
+use checkpoints::Checkpoint;
+use config::{GenesisConfig, NodeConfig};
+use consensus::pow;
+
+// Network joined when the configuration says nothing.
+pub const DEFAULT_NETWORK: &'static str = "main";
+
+pub struct Network {
+    pub name: String,
+    // Name of the consensus engine, see consensus::from_config.
+    pub consensus: String,
+    pub genesis: GenesisConfig,
+    // Checkpoints every node on the network knows about.
+    pub checkpoints: Vec<Checkpoint>,
+    // Addresses of nodes to find peers from, when we know none.
+    pub seeds: Vec<String>,
+}
+
+// Hashes of the genesis blocks of the built-in networks. Pinning them
+// makes sure a change to how genesis blocks are made never goes by
+// unnoticed.
+const MAIN_GENESIS: &'static str = "36e3a893dc8ad629a35c752bf667d51cdc39883ca999f55d30f16fdd0f6c89a5";
+const TEST_GENESIS: &'static str = "1420467b224716e2bee480c77b190b2d66757290f498d73fb510f838daec902c";
+
+fn genesis_checkpoint(hash: &str) -> Checkpoint {
+    Checkpoint { index: 1, hash: hash.to_owned() }
+}
+
+// Gets the parameters of a built-in network.
+fn builtin(name: &str) -> Option<Network> {
+    match name {
+        "main" => Some(Network {
+            name: name.to_owned(),
+            consensus: "pow".to_owned(),
+            genesis: GenesisConfig::default(),
+            checkpoints: vec![genesis_checkpoint(MAIN_GENESIS)],
+            seeds: vec![],
+        }),
+        // Easier to mine on, for trying things out
+        "test" => Some(Network {
+            name: name.to_owned(),
+            consensus: "pow".to_owned(),
+            genesis: GenesisConfig {
+                timestamp: 1514851200,
+                pow_difficulty: 3,
+                ..GenesisConfig::default()
+            },
+            checkpoints: vec![genesis_checkpoint(TEST_GENESIS)],
+            seeds: vec![],
+        }),
+        _ => None,
+    }
+}
+
+// Gets the network named on the node configuration.
+// Return: The network, or Err if its parameters are missing or contradict
+// the built-in ones.
+pub fn from_config(config: &NodeConfig) -> Result<Network, String> {
+    match (builtin(&config.network), config.genesis.clone()) {
+        (Some(network), None) => {
+            if config.consensus != network.consensus {
+                return Err(format!("The {} network uses the {} consensus engine.",
+                                   network.name, network.consensus));
+            }
+            Ok(network)
+        },
+        (Some(_), Some(_)) => Err(format!("The {} network has a built-in genesis. \
+                                           Please give your own network another name.",
+                                          config.network)),
+        (None, Some(mut genesis)) => {
+            genesis.consensus = config.consensus.clone();
+            if genesis.pow_difficulty < 1 || genesis.pow_difficulty > pow::MAX_DIFFICULTY {
+                return Err(format!("Proof of work difficulty must be between 1 and {}.",
+                                   pow::MAX_DIFFICULTY));
+            }
+            Ok(Network {
+                name: config.network.clone(),
+                consensus: config.consensus.clone(),
+                genesis: genesis,
+                checkpoints: vec![],
+                seeds: vec![],
+            })
+        },
+        (None, None) => Err(format!("Unknown network \"{}\". Networks which are not built in \
+                                     need a genesis on the configuration.",
+                                    config.network)),
+    }
+}
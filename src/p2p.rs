@@ -0,0 +1,451 @@
+// Peer-to-peer protocol.
+// Besides the HTTP API, nodes keep TCP connections open to each other, and
+// talk over them in framed binary messages. Every frame goes:
+//
+//   magic (4 bytes) | command (1 byte) | length (4 bytes, big-endian) |
+//   checksum (4 bytes) | payload (length bytes)
+//
+// The magic comes from the genesis hash of the network, so nodes on other
+// networks are dropped on their first frame. The checksum is the start of
+// the SHA-256 hash of the payload, and the payload is the canonical
+// encoding (see encoding.rs) of the message's fields.
+//
+// Connections start with a handshake: both sides send a version message,
+// carrying their handshake (see handshake.rs), and answer the other's with
+// a verack if it is on our network and speaks our protocol version. After
+// that, either side may send anything,
+// and pings keep quiet connections alive: a connection nothing comes
+// through for a while is dropped.
+//
+// Every connection has a thread reading from it and one writing to it, so
+// we only take so many connections from other nodes at once.
+// What the daemon has to handle comes to it as a ReplCommand; what it has
+// to send goes through P2p, which hands it to the writing thread.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use encoding::{self, Decode, Encode};
+use handshake::Handshake;
+use super::{Block, BlockHeader, ReplCommand, SizeLimits, Transaction};
+use sync;
+
+// How long, in seconds, we wait for a node to connect and shake hands.
+pub const HANDSHAKE_TIMEOUT: u64 = 10;
+
+// How long, in seconds, a connection may stay quiet before we ping it.
+pub const PING_INTERVAL: u64 = 30;
+
+// How long, in seconds, a connection may stay quiet before we drop it.
+pub const IDLE_TIMEOUT: u64 = 3 * PING_INTERVAL;
+
+// Most connections other nodes may have open to us at once, handshakes
+// included. Whoever connects past that is dropped right away.
+pub const MAX_INBOUND: usize = 32;
+
+// Bytes in a frame before its payload.
+const FRAME_HEADER: usize = 13;
+
+#[derive(Clone)]
+pub enum Message {
+    Version(Handshake),
+    Verack,
+    // Announces blocks (by hash) and transactions (by ID)
+    Inv { blocks: Vec<String>, transactions: Vec<String> },
+    // Asks for blocks and transactions we were announced
+    GetData { blocks: Vec<String>, transactions: Vec<String> },
+    Block(Block),
+    Tx(Transaction),
+    // Asks for the headers after the last block we have in common with
+    // a locator, see sync::locator
+    GetHeaders { locator: Vec<String> },
+    Headers(Vec<BlockHeader>),
+    Ping(u64),
+    Pong(u64),
+}
+
+impl Message {
+    // Gets the command byte and the payload of the message.
+    fn to_payload(&self) -> (u8, Vec<u8>) {
+        let mut out = vec![encoding::ENCODING_VERSION];
+        let command = match *self {
+            Message::Version(ref handshake) => {
+                handshake.encode(&mut out);
+                0
+            },
+            Message::Verack => 1,
+            Message::Inv { ref blocks, ref transactions } => {
+                blocks.encode(&mut out);
+                transactions.encode(&mut out);
+                2
+            },
+            Message::GetData { ref blocks, ref transactions } => {
+                blocks.encode(&mut out);
+                transactions.encode(&mut out);
+                3
+            },
+            Message::Block(ref block) => {
+                block.encode(&mut out);
+                4
+            },
+            Message::Tx(ref transaction) => {
+                transaction.encode(&mut out);
+                5
+            },
+            Message::GetHeaders { ref locator } => {
+                locator.encode(&mut out);
+                6
+            },
+            Message::Headers(ref headers) => {
+                headers.encode(&mut out);
+                7
+            },
+            Message::Ping(nonce) => {
+                nonce.encode(&mut out);
+                8
+            },
+            Message::Pong(nonce) => {
+                nonce.encode(&mut out);
+                9
+            },
+        };
+        (command, out)
+    }
+
+    // Parses a message from its command byte and payload.
+    fn from_payload(command: u8, payload: &[u8]) -> Result<Message, String> {
+        Ok(match command {
+            0 => Message::Version(encoding::from_bytes(payload)?),
+            1 => {
+                encoding::from_bytes::<Empty>(payload)?;
+                Message::Verack
+            },
+            2 => {
+                let (blocks, transactions) = encoding::from_bytes::<Pair<Vec<String>, Vec<String>>>(payload)?.0;
+                Message::Inv { blocks: blocks, transactions: transactions }
+            },
+            3 => {
+                let (blocks, transactions) = encoding::from_bytes::<Pair<Vec<String>, Vec<String>>>(payload)?.0;
+                Message::GetData { blocks: blocks, transactions: transactions }
+            },
+            4 => Message::Block(encoding::from_bytes(payload)?),
+            5 => Message::Tx(encoding::from_bytes(payload)?),
+            6 => Message::GetHeaders { locator: encoding::from_bytes(payload)? },
+            7 => Message::Headers(encoding::from_bytes(payload)?),
+            8 => Message::Ping(encoding::from_bytes(payload)?),
+            9 => Message::Pong(encoding::from_bytes(payload)?),
+            _ => return Err(format!("unknown command {}", command)),
+        })
+    }
+}
+
+// Payload of two fields.
+struct Pair<A, B>((A, B));
+
+impl<A: Decode, B: Decode> Decode for Pair<A, B> {
+    fn decode(input: &mut &[u8]) -> Result<Pair<A, B>, String> {
+        let first = A::decode(input)?;
+        let second = B::decode(input)?;
+        Ok(Pair((first, second)))
+    }
+}
+
+// Payload of no fields.
+struct Empty;
+
+impl Decode for Empty {
+    fn decode(_input: &mut &[u8]) -> Result<Empty, String> {
+        Ok(Empty)
+    }
+}
+
+// Gets the magic of a network, from the hash of its genesis block.
+pub fn magic(genesis_hash: &str) -> [u8; 4] {
+    let mut magic = [0; 4];
+    for (i, byte) in magic.iter_mut().enumerate() {
+        *byte = genesis_hash.get(i * 2..i * 2 + 2)
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            .unwrap_or(0);
+    }
+    magic
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let mut hasher = Sha256::new();
+    hasher.input(payload);
+    let mut hash = [0; 32];
+    hasher.result(&mut hash);
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+// Writes a message as a frame.
+pub fn write_frame<W: Write>(writer: &mut W, magic: [u8; 4], message: &Message) -> io::Result<()> {
+    let (command, payload) = message.to_payload();
+    let mut frame = Vec::with_capacity(FRAME_HEADER + payload.len());
+    frame.extend_from_slice(&magic);
+    frame.push(command);
+    let len = payload.len() as u32;
+    frame.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+    frame.extend_from_slice(&checksum(&payload));
+    frame.extend_from_slice(&payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+// Reads a frame, making sure it belongs to our network, isn't larger than
+// max_payload, and arrived whole.
+pub fn read_frame<R: Read>(reader: &mut R, magic: [u8; 4], max_payload: usize) -> Result<Message, String> {
+    let mut header = [0; FRAME_HEADER];
+    reader.read_exact(&mut header).map_err(|e| format!("{}", e))?;
+    if header[..4] != magic {
+        return Err("frame from another network".to_owned());
+    }
+    let len = header[5..9].iter().fold(0, |len, &byte| (len << 8) | byte as usize);
+    if len > max_payload {
+        return Err(format!("frame of {} bytes is too large", len));
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).map_err(|e| format!("{}", e))?;
+    if header[9..] != checksum(&payload) {
+        return Err("frame checksum does not match".to_owned());
+    }
+    Message::from_payload(header[4], &payload)
+}
+
+// A node we have a connection to.
+#[derive(Clone)]
+pub struct PeerInfo {
+    pub id: u64,
+    pub address: String,
+    // Whether we connected to it, or it to us
+    pub outbound: bool,
+    // What it told us about itself when we shook hands
+    pub handshake: Handshake,
+}
+
+struct Connection {
+    info: PeerInfo,
+    writer: Sender<Message>,
+    stream: TcpStream,
+}
+
+// Handle to our p2p connections. Clones share them.
+#[derive(Clone)]
+pub struct P2p {
+    magic: [u8; 4],
+    max_payload: usize,
+    // What we tell nodes we shake hands with
+    local: Arc<Mutex<Handshake>>,
+    connections: Arc<Mutex<BTreeMap<u64, Connection>>>,
+    next_id: Arc<AtomicU64>,
+    // How many connections from other nodes we are running
+    inbound: Arc<AtomicUsize>,
+    daemon: Sender<ReplCommand>,
+}
+
+impl P2p {
+    // local: Our handshake. Frames carry the magic of its genesis.
+    // limits: Size limits of our network, to bound the frames we read
+    // daemon: Where the messages we receive go
+    pub fn new(local: Handshake, limits: SizeLimits, daemon: Sender<ReplCommand>) -> P2p {
+        let headers = sync::MAX_HEADERS * sync::MAX_HEADER_SIZE as usize;
+        P2p {
+            magic: magic(&local.genesis),
+            max_payload: ::std::cmp::max(limits.block, headers) + 1,
+            local: Arc::new(Mutex::new(local)),
+            connections: Arc::new(Mutex::new(BTreeMap::new())),
+            next_id: Arc::new(AtomicU64::new(1)),
+            inbound: Arc::new(AtomicUsize::new(0)),
+            daemon: daemon,
+        }
+    }
+
+    // Updates what we tell nodes we shake hands with from now on, like the
+    // length of our chain.
+    pub fn set_local(&self, local: Handshake) {
+        *self.local.lock().unwrap() = local;
+    }
+
+    // Takes connections from other nodes, on a thread of its own.
+    // Return: The address we listen on
+    pub fn listen(&self, address: &str) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let local = listener.local_addr()?;
+        let p2p = self.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    if p2p.inbound.fetch_add(1, Ordering::SeqCst) >= MAX_INBOUND {
+                        p2p.inbound.fetch_sub(1, Ordering::SeqCst);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+                    let p2p = p2p.clone();
+                    thread::spawn(move || {
+                        let inbound = p2p.inbound.clone();
+                        p2p.run(stream, false);
+                        inbound.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }
+        });
+        Ok(local)
+    }
+
+    // Connects to a node, on a thread of its own.
+    // address: Where the node listens, like 127.0.0.1:4000
+    pub fn connect(&self, address: &str) {
+        let p2p = self.clone();
+        let address = address.to_owned();
+        thread::spawn(move || {
+            let target = address.to_socket_addrs().ok().and_then(|mut addresses| addresses.next());
+            let stream = match target {
+                Some(target) => TcpStream::connect_timeout(&target, Duration::from_secs(HANDSHAKE_TIMEOUT))
+                    .map_err(|e| format!("{}", e)),
+                None => Err("no such address".to_owned()),
+            };
+            match stream {
+                Ok(stream) => p2p.run(stream, true),
+                Err(reason) => println!("P2P: could not connect to {}: {}", address, reason),
+            }
+        });
+    }
+
+    // Sends a message to a node.
+    pub fn send(&self, peer: u64, message: Message) {
+        if let Some(connection) = self.connections.lock().unwrap().get(&peer) {
+            let _ = connection.writer.send(message);
+        }
+    }
+
+    // Sends a message to every node.
+    // except: The node it came from
+    pub fn broadcast(&self, message: Message, except: Option<u64>) {
+        for (id, connection) in self.connections.lock().unwrap().iter() {
+            if Some(*id) != except {
+                let _ = connection.writer.send(message.clone());
+            }
+        }
+    }
+
+    // Drops our connection to a node. Its threads find out on their own.
+    pub fn disconnect(&self, peer: u64) {
+        if let Some(connection) = self.connections.lock().unwrap().remove(&peer) {
+            let _ = connection.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // IP address of a node we are connected to, which bans go by: the
+    // port of a node connecting to us changes every time.
+    pub fn ip(&self, peer: u64) -> Option<String> {
+        self.connections.lock().unwrap().get(&peer)
+            .and_then(|connection| connection.info.address.parse::<SocketAddr>().ok())
+            .map(|address| address.ip().to_string())
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.connections.lock().unwrap().values()
+            .map(|connection| connection.info.clone())
+            .collect()
+    }
+
+    // Shakes hands with a node.
+    // Return: Its handshake
+    fn handshake(&self, stream: &mut TcpStream) -> Result<Handshake, String> {
+        let ours = self.local.lock().unwrap().clone();
+        write_frame(stream, self.magic, &Message::Version(ours.clone())).map_err(|e| format!("{}", e))?;
+        let theirs = match read_frame(stream, self.magic, self.max_payload)? {
+            Message::Version(theirs) => theirs,
+            _ => return Err("did not start with a version message".to_owned()),
+        };
+        theirs.check(&ours)?;
+        write_frame(stream, self.magic, &Message::Verack).map_err(|e| format!("{}", e))?;
+        match read_frame(stream, self.magic, self.max_payload)? {
+            Message::Verack => Ok(theirs),
+            _ => Err("did not acknowledge our version".to_owned()),
+        }
+    }
+
+    // Runs a connection, until either side drops it.
+    fn run(self, mut stream: TcpStream, outbound: bool) {
+        let address = stream.peer_addr().map(|address| address.to_string()).unwrap_or("?".to_owned());
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)));
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT)));
+        let handshake = match self.handshake(&mut stream) {
+            Ok(handshake) => handshake,
+            Err(reason) => {
+                println!("P2P: handshake with {} failed: {}", address, reason);
+                let _ = stream.shutdown(Shutdown::Both);
+                return;
+            },
+        };
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(IDLE_TIMEOUT)));
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(IDLE_TIMEOUT)));
+        let (mut writing, closing) = match (stream.try_clone(), stream.try_clone()) {
+            (Ok(writing), Ok(closing)) => (writing, closing),
+            _ => return,
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (writer, messages) = mpsc::channel();
+        let height = handshake.height;
+        let info = PeerInfo { id: id, address: address, outbound: outbound, handshake: handshake };
+        self.connections.lock().unwrap().insert(id, Connection { info: info, writer: writer.clone(), stream: closing });
+        let _ = self.daemon.send(ReplCommand::P2pConnected { peer: id, height: height });
+
+        // Write whatever we are told to, pinging while there's nothing
+        let magic = self.magic;
+        thread::spawn(move || {
+            let mut nonce = 0;
+            loop {
+                let message = match messages.recv_timeout(Duration::from_secs(PING_INTERVAL)) {
+                    Ok(message) => message,
+                    Err(RecvTimeoutError::Timeout) => {
+                        nonce += 1;
+                        Message::Ping(nonce)
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if write_frame(&mut writing, magic, &message).is_err() {
+                    break;
+                }
+            }
+            let _ = writing.shutdown(Shutdown::Both);
+        });
+
+        // Read whatever comes, until the connection fails or goes quiet
+        loop {
+            match read_frame(&mut stream, self.magic, self.max_payload) {
+                Ok(Message::Ping(nonce)) => {
+                    let _ = writer.send(Message::Pong(nonce));
+                },
+                Ok(Message::Pong(_)) => {},
+                Ok(Message::Version(_)) | Ok(Message::Verack) => {
+                    println!("P2P: peer {} shook hands twice.", id);
+                    break;
+                },
+                Ok(message) => {
+                    if self.daemon.send(ReplCommand::P2pMessage { peer: id, message: message }).is_err() {
+                        break;
+                    }
+                },
+                Err(reason) => {
+                    println!("P2P: dropping peer {}: {}", id, reason);
+                    break;
+                },
+            }
+        }
+        self.disconnect(id);
+        let _ = stream.shutdown(Shutdown::Both);
+        let _ = self.daemon.send(ReplCommand::P2pDisconnected { peer: id });
+    }
+}
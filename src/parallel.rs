@@ -0,0 +1,38 @@
+// Parallel helpers.
+// Validating a long chain is mostly hashing, and most of it doesn't
+// depend on the blocks before, so it can be spread across every CPU.
+
+use std::thread;
+
+// Below this many items, spawning threads costs more than it saves.
+const MIN_PARALLEL_ITEMS: usize = 64;
+
+// How many threads to spread work across: one per CPU.
+pub fn threads() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+}
+
+// Computes f for every index in 0..len, in chunks across threads.
+// Return: The results, in order
+pub fn map<T, F>(len: usize, threads: usize, f: F) -> Vec<T>
+    where T: Send, F: Fn(usize) -> T + Sync
+{
+    if threads <= 1 || len < MIN_PARALLEL_ITEMS {
+        return (0..len).map(f).collect();
+    }
+    let chunk = (len + threads - 1) / threads;
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..len).step_by(chunk)
+            .map(|start| {
+                let end = ::std::cmp::min(start + chunk, len);
+                scope.spawn(move || (start..end).map(f).collect::<Vec<T>>())
+            })
+            .collect();
+        workers.into_iter()
+            .flat_map(|worker| worker.join().expect("Validation thread panicked!"))
+            .collect()
+    })
+}
@@ -0,0 +1,448 @@
+// Address book.
+// Every node address we have heard of, along with when we last heard
+// from it. Nodes share the addresses they have heard from lately on
+// GET /peers, and ask the nodes they know for theirs every now and then,
+// so a node only needs one address (a seed) to find the rest of the
+// network.
+//
+// Some of those addresses are registered as our nodes: those we gossip
+// with and sync from. We keep track of how each one is doing.
+//
+// Nodes which misbehave (sending invalid blocks, garbage, or too much of
+// anything) gather a score, and once it gets too high, they are banned for
+// a while: they leave the address book, and nothing from them is taken
+// until the ban is over. Scores go down over time, so that a node slipping
+// up once in a while never gets banned for it. Nodes we can't reach are
+// not misbehaving: they are just failing.
+//
+// The address book is saved to its own file, apart from the chain, so
+// nothing that happens to the chain ever touches it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{Read, Write};
+
+use serde_json;
+use url::Url;
+
+use super::BlockRule;
+use handshake::Handshake;
+use remote::PeerError;
+
+// How many addresses we keep. When full, the one we heard from least
+// recently goes away.
+pub const MAX_ADDRESSES: usize = 1000;
+
+// How many addresses we share at once.
+pub const MAX_SHARED: usize = 100;
+
+// How recently, in seconds, we must have heard from a node to share it.
+pub const GOOD_PEER_AGE: u64 = 3 * 60 * 60;
+
+// How often, in seconds, we ask our nodes for their peers.
+pub const EXCHANGE_INTERVAL: u64 = 10 * 60;
+
+// How many nodes we keep registered, when we know of enough of them.
+pub const TARGET_NODES: usize = 8;
+
+// Misbehaviour score which gets a node banned, unless configured.
+pub const DEFAULT_BAN_THRESHOLD: u64 = 100;
+
+// How long bans last, in seconds, unless configured.
+pub const DEFAULT_BAN_DURATION: u64 = 24 * 60 * 60;
+
+// How often, in seconds, misbehaviour scores go down by a point.
+pub const SCORE_DECAY_INTERVAL: u64 = 6 * 60;
+
+// Ways a node can misbehave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Misbehaviour {
+    // Sent a block breaking a rule
+    InvalidBlock,
+    // Sent a block with a bad proof of work or signature
+    BadProof,
+    // Sent something we could not parse, or other than what we asked for
+    Malformed,
+    // Sent more than we are willing to read
+    Oversized,
+}
+
+impl Misbehaviour {
+    // How much it adds to the score of a node.
+    pub fn penalty(&self) -> u64 {
+        match *self {
+            Misbehaviour::InvalidBlock => 50,
+            Misbehaviour::BadProof     => 100,
+            Misbehaviour::Malformed    => 20,
+            Misbehaviour::Oversized    => 50,
+        }
+    }
+
+    pub fn description(&self) -> &'static str {
+        match *self {
+            Misbehaviour::InvalidBlock => "sent an invalid block",
+            Misbehaviour::BadProof     => "sent a block with a bad proof of work or signature",
+            Misbehaviour::Malformed    => "sent a malformed answer",
+            Misbehaviour::Oversized    => "sent an oversized answer",
+        }
+    }
+
+    // The misbehaviour behind a failed request, if the node is to blame.
+    pub fn from_error(error: &PeerError) -> Option<Misbehaviour> {
+        match *error {
+            PeerError::Unreachable(_) | PeerError::Status(_, _) => None,
+            PeerError::TooLarge(_) => Some(Misbehaviour::Oversized),
+            PeerError::Malformed(_) | PeerError::Misbehaved(_) => Some(Misbehaviour::Malformed),
+            PeerError::Incompatible(_) => None,
+        }
+    }
+
+    // The misbehaviour of sending a block breaking a rule.
+    pub fn from_rule(rule: &BlockRule) -> Misbehaviour {
+        match *rule {
+            BlockRule::Consensus => Misbehaviour::BadProof,
+            _ => Misbehaviour::InvalidBlock,
+        }
+    }
+}
+
+// When nodes get banned, and for how long.
+#[derive(Clone, Copy)]
+pub struct BanPolicy {
+    pub threshold: u64,
+    // In seconds
+    pub duration: u64,
+}
+
+impl Default for BanPolicy {
+    fn default() -> BanPolicy {
+        BanPolicy {
+            threshold: DEFAULT_BAN_THRESHOLD,
+            duration:  DEFAULT_BAN_DURATION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Ban {
+    // Timestamp when the ban is over
+    pub until: u64,
+    // What got the node banned, last
+    pub reason: String,
+}
+
+// How a registered node is doing.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NodeStatus {
+    // Timestamp when we last heard from it, or 0 if never
+    pub last_contact: u64,
+    // Length of its chain, last time it told us
+    pub height: Option<u64>,
+    // Round trip time of our last status request, in milliseconds
+    pub latency: Option<u64>,
+    // Times we failed to reach it since we last did
+    pub failures: u64,
+    // What it told us about itself, last time we shook hands
+    pub handshake: Option<Handshake>,
+    // Why we don't sync with it, if it is not on our network or doesn't
+    // speak our protocol version
+    pub mismatch: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AddressBook {
+    // Timestamp when we last heard from each address, or 0 if never
+    addresses: BTreeMap<String, u64>,
+    // Our nodes
+    nodes: BTreeMap<String, NodeStatus>,
+    // Misbehaviour score of every address which misbehaved, short of a ban
+    scores: BTreeMap<String, u64>,
+    // Timestamp up to which scores went down already
+    decayed: u64,
+    bans: BTreeMap<String, Ban>,
+    // Nodes deleted by hand, which we don't take from other nodes again
+    deleted: BTreeSet<String>,
+    #[serde(skip)]
+    pub policy: BanPolicy,
+}
+
+impl AddressBook {
+    pub fn new() -> AddressBook {
+        AddressBook::default()
+    }
+
+    // Load address book from file
+    pub fn from_file(filename: String) -> AddressBook {
+        let f = File::open(filename);
+        match f {
+            Err(_) => {
+                println!("Cannot read address book file. Creating a new one.");
+                AddressBook::new()
+            },
+            Ok(mut f) => {
+                let mut text = String::new();
+                let _ = f.read_to_string(&mut text);
+                match serde_json::from_str(&text) {
+                    Ok(book) => book,
+                    Err(_) => {
+                        println!("Cannot parse address book. Creating a new one.");
+                        AddressBook::new()
+                    },
+                }
+            }
+        }
+    }
+
+    // Saves address book to file
+    pub fn to_file(&self, filename: String) {
+        let serialized = serde_json::to_string_pretty(self)
+            .expect("Unable to serialize address book!");
+        let f = File::create(filename);
+        match f {
+            Err(_) => println!("Unable to create file!"),
+            Ok(mut f) => f.write_all(serialized.as_bytes())
+                .expect("Unable to write address book to file!"),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+
+    // Adds an address we were told about, unless it is malformed,
+    // banned, or was deleted.
+    // Return: Whether it is new to us
+    pub fn add(&mut self, address: &str) -> bool {
+        if self.addresses.contains_key(address) || self.bans.contains_key(address)
+            || self.deleted.contains(address) || Url::parse(address).is_err() {
+            return false;
+        }
+        if self.addresses.len() >= MAX_ADDRESSES {
+            // Our nodes stay
+            let stalest = self.addresses.iter()
+                .filter(|&(address, _)| !self.nodes.contains_key(address))
+                .min_by_key(|&(_, &last_seen)| last_seen)
+                .map(|(address, _)| address.clone());
+            if let Some(stalest) = stalest {
+                self.addresses.remove(&stalest);
+            }
+        }
+        self.addresses.insert(address.to_owned(), 0);
+        true
+    }
+
+    // Records that we just heard back from an address we reached out to.
+    // Whatever a node claims about itself, or others, when it reaches us
+    // doesn't count: anyone can claim to be anyone.
+    pub fn seen(&mut self, address: &str, now: u64) {
+        self.add(address);
+        if let Some(last_seen) = self.addresses.get_mut(address) {
+            *last_seen = now;
+        }
+        if let Some(status) = self.nodes.get_mut(address) {
+            status.last_contact = now;
+            status.failures = 0;
+        }
+    }
+
+    // Records that we just heard from one of our nodes, along with what
+    // it told us about itself.
+    pub fn reached(&mut self, address: &str, now: u64, height: Option<u64>, latency: Option<u64>) {
+        self.seen(address, now);
+        if let Some(status) = self.nodes.get_mut(address) {
+            status.height = height.or(status.height);
+            status.latency = latency.or(status.latency);
+        }
+    }
+
+    // Records that we failed to reach one of our nodes.
+    pub fn failed(&mut self, address: &str) {
+        if let Some(status) = self.nodes.get_mut(address) {
+            status.failures += 1;
+        }
+    }
+
+    // Records what one of our nodes told us about itself on handshake.
+    // mismatch: Why we don't sync with it, if we don't
+    pub fn shook_hands(&mut self, address: &str, handshake: Handshake, mismatch: Option<String>) {
+        if let Some(status) = self.nodes.get_mut(address) {
+            status.handshake = Some(handshake);
+            status.mismatch = mismatch;
+        }
+    }
+
+    // Our nodes we sync with: all of them, but those we found out are
+    // not on our network or don't speak our protocol version.
+    pub fn nodes(&self) -> Vec<String> {
+        self.nodes.iter()
+            .filter(|&(_, status)| status.mismatch.is_none())
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    // Whether an address is one of our nodes, which we shook hands with
+    // and found on our network, speaking our protocol version.
+    pub fn handshaken(&self, address: &str) -> bool {
+        self.nodes.get(address)
+            .map_or(false, |status| status.handshake.is_some() && status.mismatch.is_none())
+    }
+
+    // Every one of our nodes.
+    pub fn registered(&self) -> Vec<String> {
+        self.nodes.keys().cloned().collect()
+    }
+
+    // Our nodes, along with how each is doing.
+    pub fn node_status(&self) -> Vec<(String, NodeStatus)> {
+        self.nodes.iter()
+            .map(|(address, status)| (address.clone(), status.clone()))
+            .collect()
+    }
+
+    // Registers an address as one of our nodes, even if it was deleted.
+    // Return: Whether it is new
+    pub fn register(&mut self, address: &str) -> bool {
+        if self.nodes.contains_key(address) || self.bans.contains_key(address)
+            || Url::parse(address).is_err() {
+            return false;
+        }
+        self.deleted.remove(address);
+        self.add(address);
+        let status = NodeStatus {
+            last_contact: self.addresses.get(address).cloned().unwrap_or(0),
+            ..NodeStatus::default()
+        };
+        self.nodes.insert(address.to_owned(), status);
+        true
+    }
+
+    // Deletes a node. It goes away from the address book too, and is
+    // remembered as deleted, so that it doesn't come back from other
+    // nodes' peers; only registering it again does.
+    // Return: Whether it was one of our nodes
+    pub fn unregister(&mut self, address: &str) -> bool {
+        self.deleted.insert(address.to_owned());
+        self.forget(address)
+    }
+
+    // Drops an address from our nodes and the address book.
+    // Return: Whether it was one of our nodes
+    fn forget(&mut self, address: &str) -> bool {
+        self.addresses.remove(address);
+        self.nodes.remove(address).is_some()
+    }
+
+    // Registers nodes from the address book, until we have enough of
+    // them, those we heard from most recently first.
+    // local: Our own address
+    pub fn top_up(&mut self, local: &str) {
+        for address in self.candidates() {
+            if self.nodes.len() >= TARGET_NODES {
+                break;
+            }
+            if address != local {
+                self.register(&address);
+            }
+        }
+    }
+
+    // Addresses we heard from lately, most recent first.
+    pub fn good(&self, now: u64) -> Vec<String> {
+        let mut good: Vec<(&String, &u64)> = self.addresses.iter()
+            .filter(|&(_, &last_seen)| last_seen > 0 && last_seen + GOOD_PEER_AGE >= now)
+            .collect();
+        good.sort_by(|a, b| b.1.cmp(a.1));
+        good.into_iter()
+            .take(MAX_SHARED)
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    // Records that a node misbehaved, banning it if its score gets too
+    // high.
+    // Return: Whether it is banned now
+    pub fn misbehaved(&mut self, address: &str, misbehaviour: Misbehaviour, now: u64) -> bool {
+        if self.bans.contains_key(address) {
+            return true;
+        }
+        self.decay(now);
+        let score = {
+            let score = self.scores.entry(address.to_owned()).or_insert(0);
+            *score += misbehaviour.penalty();
+            *score
+        };
+        if score < self.policy.threshold {
+            return false;
+        }
+        self.scores.remove(address);
+        self.forget(address);
+        self.bans.insert(address.to_owned(), Ban {
+            until: now + self.policy.duration,
+            reason: misbehaviour.description().to_owned(),
+        });
+        true
+    }
+
+    // Takes a point off every score for each SCORE_DECAY_INTERVAL gone by.
+    fn decay(&mut self, now: u64) {
+        if self.decayed == 0 || now < self.decayed {
+            self.decayed = now;
+            return;
+        }
+        let points = (now - self.decayed) / SCORE_DECAY_INTERVAL;
+        if points == 0 {
+            return;
+        }
+        for score in self.scores.values_mut() {
+            *score = score.saturating_sub(points);
+        }
+        self.scores.retain(|_, score| *score > 0);
+        self.decayed += points * SCORE_DECAY_INTERVAL;
+    }
+
+    // Misbehaviour score of an address.
+    pub fn score(&self, address: &str) -> u64 {
+        self.scores.get(address).cloned().unwrap_or(0)
+    }
+
+    pub fn banned(&self, address: &str) -> bool {
+        self.bans.contains_key(address)
+    }
+
+    // Lifts bans which are over, and lowers scores.
+    pub fn expire_bans(&mut self, now: u64) {
+        self.decay(now);
+        let expired: Vec<String> = self.bans.iter()
+            .filter(|&(_, ban)| ban.until <= now)
+            .map(|(address, _)| address.clone())
+            .collect();
+        for address in expired {
+            self.bans.remove(&address);
+        }
+    }
+
+    // Banned addresses, along with until when and why.
+    pub fn bans(&self) -> Vec<(String, Ban)> {
+        self.bans.iter()
+            .map(|(address, ban)| (address.clone(), ban.clone()))
+            .collect()
+    }
+
+    // Lifts a ban, forgiving whatever the node did.
+    // Return: Whether it was banned
+    pub fn unban(&mut self, address: &str) -> bool {
+        self.scores.remove(address);
+        self.bans.remove(address).is_some()
+    }
+
+    // Every address we know, those we heard from most recently first.
+    pub fn candidates(&self) -> Vec<String> {
+        let mut candidates: Vec<(&String, &u64)> = self.addresses.iter().collect();
+        candidates.sort_by(|a, b| b.1.cmp(a.1));
+        candidates.into_iter()
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+}
@@ -0,0 +1,172 @@
+// Requests to other nodes.
+// Every request we make to another node goes through here, so no node can
+// hold us up or take us down: connecting and reading are bounded by
+// timeouts, answers are read up to a size cap, and requests which fail for
+// reasons that may go away (the node was unreachable, or had an error of
+// its own) are tried again a few times, waiting longer each time. Whatever
+// goes wrong comes back as a PeerError, never as a panic.
+
+use std::fmt;
+use std::io::Read;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use reqwest;
+use serde::de::DeserializeOwned;
+use serde_json;
+use url::Url;
+
+// How long we wait for a node to take our connection, in seconds.
+pub const CONNECT_TIMEOUT: u64 = 5;
+
+// How long we wait for a node to send or take anything, once connected,
+// in seconds.
+pub const READ_TIMEOUT: u64 = 30;
+
+// How many times we try a request, at most.
+pub const ATTEMPTS: u32 = 3;
+
+// How long we wait before trying a request again the first time, in
+// milliseconds. It doubles every time after.
+pub const RETRY_DELAY: u64 = 500;
+
+// Most bytes we read from an answer, unless told otherwise.
+pub const MAX_RESPONSE_SIZE: u64 = 1024 * 1024;
+
+// How much bigger blocks are as JSON than encoded, at most.
+pub const JSON_OVERHEAD: u64 = 4;
+
+// What went wrong with a request to a node.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PeerError {
+    // Could not connect, or it took too long to answer
+    Unreachable(String),
+    // Answered with an error status
+    Status(u16, String),
+    // Answered with more than we are willing to read
+    TooLarge(u64),
+    // Answered with something we could not parse
+    Malformed(String),
+    // Answered with something other than what we asked for
+    Misbehaved(String),
+    // Is not on our network, or doesn't speak our protocol version
+    Incompatible(String),
+}
+
+impl PeerError {
+    // Whether trying again later may work.
+    pub fn transient(&self) -> bool {
+        match *self {
+            PeerError::Unreachable(_) => true,
+            PeerError::Status(status, _) => status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PeerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PeerError::Unreachable(ref reason) => write!(f, "unreachable: {}", reason),
+            PeerError::Status(status, ref body) => write!(f, "HTTP {}: {}", status, body),
+            PeerError::TooLarge(limit) => write!(f, "answer larger than {} bytes", limit),
+            PeerError::Malformed(ref reason) => write!(f, "malformed answer: {}", reason),
+            PeerError::Misbehaved(ref reason) => write!(f, "{}", reason),
+            PeerError::Incompatible(ref reason) => write!(f, "incompatible: {}", reason),
+        }
+    }
+}
+
+// Builds a client whose reads time out.
+pub fn client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(Duration::from_secs(READ_TIMEOUT))
+        .build()
+        .expect("Unable to build HTTP client!")
+}
+
+// How long to wait before an attempt.
+// attempt: How many attempts were made already
+pub fn backoff(attempt: u32) -> Duration {
+    match attempt {
+        0 => Duration::from_millis(0),
+        n => Duration::from_millis(RETRY_DELAY << (n - 1)),
+    }
+}
+
+// Checks that a node takes connections, without waiting too long for it.
+fn connect(uri: &str) -> Result<(), PeerError> {
+    let url = Url::parse(uri).map_err(|e| PeerError::Unreachable(format!("{}", e)))?;
+    let addresses = url.to_socket_addrs().map_err(|e| PeerError::Unreachable(format!("{}", e)))?;
+    let mut last_error = PeerError::Unreachable(format!("{} has no address", uri));
+    for address in addresses {
+        match TcpStream::connect_timeout(&address, Duration::from_secs(CONNECT_TIMEOUT)) {
+            Ok(_) => return Ok(()),
+            Err(e) => last_error = PeerError::Unreachable(format!("{}", e)),
+        }
+    }
+    Err(last_error)
+}
+
+// Reads a whole answer, as long as it is no larger than limit.
+pub fn read_body<R: Read>(reader: R, limit: u64) -> Result<String, PeerError> {
+    let mut body = String::new();
+    reader.take(limit + 1).read_to_string(&mut body)
+        .map_err(|e| PeerError::Unreachable(format!("{}", e)))?;
+    if body.len() as u64 > limit {
+        return Err(PeerError::TooLarge(limit));
+    }
+    Ok(body)
+}
+
+// Makes a request once.
+// body: What to post, or None to get
+fn attempt(client: &reqwest::Client, uri: &str, body: Option<&str>, limit: u64) -> Result<String, PeerError> {
+    connect(uri)?;
+    let sent = match body {
+        Some(body) => client.post(uri).body(body.to_owned()).send(),
+        None => client.get(uri).send(),
+    };
+    let mut res = sent.map_err(|e| PeerError::Unreachable(format!("{}", e)))?;
+    let text = read_body(&mut res, limit)?;
+    if !res.status().is_success() {
+        return Err(PeerError::Status(res.status().as_u16(), text));
+    }
+    Ok(text)
+}
+
+// Makes a request, trying again while it fails for reasons that may go
+// away.
+fn request(client: &reqwest::Client, uri: &str, body: Option<&str>, limit: u64) -> Result<String, PeerError> {
+    let mut tries = 0;
+    loop {
+        thread::sleep(backoff(tries));
+        tries += 1;
+        match attempt(client, uri, body, limit) {
+            Err(ref e) if e.transient() && tries < ATTEMPTS => continue,
+            result => return result,
+        }
+    }
+}
+
+// Fetches a JSON document from a node.
+// limit: Most bytes we read
+pub fn get<T: DeserializeOwned>(client: &reqwest::Client, uri: &str, limit: u64) -> Result<T, PeerError> {
+    let body = request(client, uri, None, limit)?;
+    serde_json::from_str(&body).map_err(|e| PeerError::Malformed(format!("{}", e)))
+}
+
+// Posts a JSON document to a node.
+pub fn post(client: &reqwest::Client, uri: &str, body: &str) -> Result<(), PeerError> {
+    request(client, uri, Some(body), MAX_RESPONSE_SIZE).map(|_| ())
+}
+
+// Posts a JSON document to a node, and reads the JSON document it answers
+// with.
+// limit: Most bytes we read
+pub fn post_json<T: DeserializeOwned>(client: &reqwest::Client, uri: &str, body: &str,
+                                      limit: u64) -> Result<T, PeerError> {
+    let answer = request(client, uri, Some(body), limit)?;
+    serde_json::from_str(&answer).map_err(|e| PeerError::Malformed(format!("{}", e)))
+}
@@ -0,0 +1,212 @@
+// Chain sync.
+// Rather than downloading every node's whole chain, we sync headers
+// first. We send each node a locator (hashes of some of our blocks, dense
+// near our tip and sparse further back), and it answers with the headers
+// after the last block we have in common. Headers are small, so we can
+// tell which node has the heaviest chain, and whether it is heavier than
+// ours, before downloading any block. Then the missing blocks are fetched
+// by hash range, spread across every node which has them, in parallel.
+
+use std::collections::{BTreeMap, HashSet};
+use std::thread;
+
+use reqwest;
+
+use super::{Block, BlockHeader, Blockchain};
+use remote::{self, PeerError};
+
+// Most headers a node answers with at once. Whoever gets that many asks
+// again for the rest.
+pub const MAX_HEADERS: usize = 2000;
+
+// Most blocks a node answers with at once.
+pub const MAX_BLOCKS: usize = 500;
+
+// How many blocks we ask a node for at once, when syncing.
+pub const BLOCKS_PER_REQUEST: usize = 100;
+
+// How many headers more than the height it told us on handshake we take
+// from a node, for blocks it got since.
+pub const HEIGHT_MARGIN: usize = 100;
+
+// Most bytes a header takes as JSON.
+pub const MAX_HEADER_SIZE: u64 = 1024;
+
+// How many of the last blocks go on a locator before it starts skipping.
+const LOCATOR_DENSE: usize = 10;
+
+// Builds the locator of a chain: hashes of its last blocks, then of
+// blocks further and further apart, down to genesis.
+pub fn locator(chain: &[Block]) -> Vec<String> {
+    let mut locator = vec![];
+    if chain.is_empty() {
+        return locator;
+    }
+    let mut position = chain.len() - 1;
+    let mut step = 1;
+    loop {
+        locator.push(Blockchain::hash(&chain[position]));
+        if position == 0 {
+            return locator;
+        }
+        if locator.len() >= LOCATOR_DENSE {
+            step *= 2;
+        }
+        position = position.saturating_sub(step);
+    }
+}
+
+// A run of consecutive blocks to download, and the nodes which have them.
+pub struct Range {
+    pub hashes: Vec<String>,
+    pub sources: Vec<String>,
+}
+
+// Splits the hashes of the blocks to download into ranges, each one
+// along with the nodes which have it.
+// sources: Every node, along with the hashes of the headers it sent us
+pub fn ranges(hashes: &[String], sources: &[(String, HashSet<String>)]) -> Vec<Range> {
+    hashes.chunks(BLOCKS_PER_REQUEST)
+        .map(|chunk| {
+            // A node having the last block of a range has all of it
+            let last = chunk.last().unwrap();
+            Range {
+                hashes: chunk.to_vec(),
+                sources: sources.iter()
+                    .filter(|&&(_, ref known)| known.contains(last))
+                    .map(|&(ref node, _)| node.clone())
+                    .collect(),
+            }
+        })
+        .collect()
+}
+
+// Downloads the headers a node has after the last block it has in common
+// with our locator.
+// limit: Most headers we take; a node having more has to wait for the
+// next sync to send us the rest
+pub fn fetch_headers(client: &reqwest::Client, node: &str, locator: &[String],
+                     limit: usize) -> Result<Vec<BlockHeader>, PeerError> {
+    let mut headers: Vec<BlockHeader> = vec![];
+    let mut locator = locator.join(",");
+    loop {
+        let batch: Vec<BlockHeader> = remote::get(client, &format!("{}/headers?locator={}", node, locator),
+                                                  MAX_HEADERS as u64 * MAX_HEADER_SIZE)?;
+        if batch.len() > MAX_HEADERS {
+            return Err(PeerError::Misbehaved(format!("{} sent more than {} headers", node, MAX_HEADERS)));
+        }
+        // Every batch must pick up where the last one left off
+        if let (Some(last), Some(first)) = (headers.last(), batch.first()) {
+            if first.index <= last.index {
+                return Err(PeerError::Misbehaved(format!("{} sent headers it had sent already", node)));
+            }
+        }
+        let more = batch.len() == MAX_HEADERS;
+        if let Some(last) = batch.last() {
+            locator = Blockchain::hash_header(last);
+        }
+        headers.extend(batch);
+        if headers.len() >= limit {
+            headers.truncate(limit);
+            return Ok(headers);
+        }
+        if !more {
+            return Ok(headers);
+        }
+    }
+}
+
+// Downloads a range of blocks from a node, checking that they are the
+// ones we asked for.
+// max_block_size: Largest block the network takes, encoded
+fn fetch_range(client: &reqwest::Client, node: &str, range: &Range,
+               max_block_size: usize) -> Result<Vec<Block>, PeerError> {
+    let limit = range.hashes.len() as u64 * max_block_size as u64 * remote::JSON_OVERHEAD;
+    let blocks: Vec<Block> = remote::get(client, &format!("{}/blocks?from={}&to={}", node, range.hashes[0],
+                                                          range.hashes[range.hashes.len() - 1]), limit)?;
+    check_range(node, range, &blocks)?;
+    Ok(blocks)
+}
+
+// Checks that a node sent the blocks of a range, with the transactions
+// their headers commit to.
+// blocks: What the node sent
+pub fn check_range(node: &str, range: &Range, blocks: &[Block]) -> Result<(), PeerError> {
+    let hashes: Vec<String> = blocks.iter().map(Blockchain::hash).collect();
+    if hashes != range.hashes {
+        return Err(PeerError::Misbehaved(format!("{} sent other blocks than we asked for", node)));
+    }
+    if blocks.iter().any(|block| block.merkle_root != Blockchain::merkle_root(&block.transactions)
+                          || !Blockchain::distinct_transactions(&block.transactions)) {
+        return Err(PeerError::Misbehaved(format!("{} sent a block whose transactions don't match its header", node)));
+    }
+    Ok(())
+}
+
+// Downloads ranges of blocks. Each range is first asked from one of its
+// nodes, taking turns, with every node downloading on a thread of its
+// own; ranges which fail are then asked from the other nodes which have
+// them, unless they failed us already.
+// failures: Where to record the nodes which failed us, and why
+// Return: Every block, in order, along with the node which sent it, or
+// Err if some range could not be downloaded from any node
+pub fn fetch_blocks(client: &reqwest::Client, ranges: &[Range], max_block_size: usize,
+                    failures: &mut BTreeMap<String, PeerError>) -> Result<Vec<(String, Block)>, String> {
+    let mut assigned: BTreeMap<&str, Vec<usize>> = BTreeMap::new();
+    for (i, range) in ranges.iter().enumerate() {
+        if !range.sources.is_empty() {
+            let node = &range.sources[i % range.sources.len()];
+            assigned.entry(node).or_insert_with(Vec::new).push(i);
+        }
+    }
+    let mut downloaded: Vec<Option<(String, Vec<Block>)>> = ranges.iter().map(|_| None).collect();
+    thread::scope(|scope| {
+        let nodes: Vec<&str> = assigned.keys().cloned().collect();
+        let workers: Vec<_> = assigned.into_iter()
+            .map(|(node, indexes)| scope.spawn(move || {
+                indexes.into_iter()
+                    .map(|i| (i, fetch_range(client, node, &ranges[i], max_block_size)))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+        for (node, worker) in nodes.into_iter().zip(workers) {
+            for (i, blocks) in worker.join().expect("Sync thread panicked!") {
+                match blocks {
+                    Ok(blocks) => downloaded[i] = Some((node.to_owned(), blocks)),
+                    Err(reason) => {
+                        println!("Sync: {}: {}", node, reason);
+                        failures.insert(node.to_owned(), reason);
+                    },
+                }
+            }
+        }
+    });
+
+    let mut blocks = vec![];
+    for (range, download) in ranges.iter().zip(downloaded.into_iter()) {
+        let download = match download {
+            Some(download) => download,
+            None => {
+                let mut download = None;
+                for node in &range.sources {
+                    if failures.contains_key(node) {
+                        continue;
+                    }
+                    match fetch_range(client, node, range, max_block_size) {
+                        Ok(blocks) => {
+                            download = Some((node.clone(), blocks));
+                            break;
+                        },
+                        Err(reason) => {
+                            failures.insert(node.clone(), reason);
+                        },
+                    }
+                }
+                download.ok_or(format!("Could not download block {} from any node", range.hashes[0]))?
+            },
+        };
+        let (node, download) = download;
+        blocks.extend(download.into_iter().map(|block| (node.clone(), block)));
+    }
+    Ok(blocks)
+}
@@ -0,0 +1,113 @@
+// Block tree.
+// Our chain is a single branch of the tree of every block we know about.
+// Blocks whose parent we don't know yet are kept aside as orphans until
+// it shows up, and every branch tip is tracked along with its cumulative
+// work, so we can tell when a side chain overtakes ours.
+
+use std::collections::{HashMap, HashSet};
+
+use super::Block;
+
+// How many orphans we hold on to. When full, the oldest one goes away.
+pub const MAX_ORPHANS: usize = 100;
+
+#[derive(Clone)]
+struct TreeEntry {
+    block: Block,
+    // Work of every block from genesis up to this one
+    work: u64,
+}
+
+// A branch tip.
+pub struct Tip {
+    pub hash: String,
+    pub index: u64,
+    pub work: u64,
+}
+
+#[derive(Clone, Default)]
+pub struct BlockTree {
+    blocks: HashMap<String, TreeEntry>,
+    orphans: Vec<(String, Block)>,
+    tips: HashSet<String>,
+    // Hashes of the blocks which have children
+    parents: HashSet<String>,
+}
+
+impl BlockTree {
+    pub fn new() -> BlockTree {
+        BlockTree::default()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.blocks.contains_key(hash)
+            || self.orphans.iter().any(|&(ref orphan, _)| orphan == hash)
+    }
+
+    // Gets a block on the tree (not an orphan).
+    pub fn get(&self, hash: &str) -> Option<&Block> {
+        self.blocks.get(hash).map(|entry| &entry.block)
+    }
+
+    // Cumulative work up to a block, if we know it.
+    pub fn work(&self, hash: &str) -> Option<u64> {
+        self.blocks.get(hash).map(|entry| entry.work)
+    }
+
+    // Adds a block whose parent is already on the tree (or a genesis
+    // block, which has none).
+    // work: Cumulative work up to this block
+    pub fn insert(&mut self, hash: String, block: Block, work: u64) {
+        self.tips.remove(&block.previous_hash);
+        self.parents.insert(block.previous_hash.clone());
+        // A block we have children for is not a tip
+        if !self.parents.contains(&hash) {
+            self.tips.insert(hash.clone());
+        }
+        self.blocks.insert(hash, TreeEntry { block: block, work: work });
+    }
+
+    // Holds a block until its parent arrives.
+    pub fn add_orphan(&mut self, hash: String, block: Block) {
+        if self.orphans.len() >= MAX_ORPHANS {
+            self.orphans.remove(0);
+        }
+        self.orphans.push((hash, block));
+    }
+
+    // Takes every orphan waiting for parent.
+    pub fn take_orphans(&mut self, parent: &str) -> Vec<(String, Block)> {
+        let (children, others) = self.orphans.drain(..)
+            .partition(|&(_, ref block)| block.previous_hash == parent);
+        self.orphans = others;
+        children
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    // Gets the branch ending at a block, from genesis up to it.
+    pub fn branch(&self, hash: &str) -> Vec<Block> {
+        let mut branch = vec![];
+        let mut current = self.blocks.get(hash);
+        while let Some(entry) = current {
+            branch.push(entry.block.clone());
+            current = self.blocks.get(&entry.block.previous_hash);
+        }
+        branch.reverse();
+        branch
+    }
+
+    // Every branch tip, heaviest first.
+    pub fn tips(&self) -> Vec<Tip> {
+        let mut tips: Vec<Tip> = self.tips.iter()
+            .map(|hash| {
+                let entry = &self.blocks[hash];
+                Tip { hash: hash.clone(), index: entry.block.index, work: entry.work }
+            })
+            .collect();
+        tips.sort_by(|a, b| b.work.cmp(&a.work).then(a.hash.cmp(&b.hash)));
+        tips
+    }
+}